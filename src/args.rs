@@ -0,0 +1,329 @@
+use tun_tap::Mode;
+
+use crate::icmp::EchoPayload;
+use crate::ipv4::{Ipv4Address, Ipv4Cidr};
+use crate::ping::DEFAULT_PING_SOURCE;
+use crate::responder::DEFAULT_REPLY_TTL;
+
+pub const USAGE: &str = "usage: icmp-tuntap [--iface <name>] [--mode tun|tap] [--allow <cidr>]... [--trust <cidr>]... [--json] [--verbose] [--reply-ttl <ttl>] [--reply-source <address>] [--addr <address>] [--forward] [--subnet <cidr>] [--broadcast-echo] [--echo-payload mirror|zero|reverse|invert] [--rewrite-id <request id>:<reply id>]... [--delay-ms <ms>] [--jitter <min>-<max>] [--rate <replies/s>] [--loss <fraction>] [--corrupt <fraction>] [--mtu-probe <threshold> [--advertise-mtu <mtu>]] [--no-fragment] [--no-checksum] [--count <n>] [--pcap <file>] [--replay <file>] [--ping <address> [--source <address>]]";
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Args {
+    pub iface: String,
+    pub mode: Mode,
+    // only answer sources in one of these subnets, everyone if empty
+    pub allow: Vec<Ipv4Cidr>,
+    // don't verify the checksums of packets from these subnets
+    pub trust: Vec<Ipv4Cidr>,
+    // print the handled ICMP packets to stdout as JSON lines
+    pub json: bool,
+    // print every IPv4 packet received and sent as a tree
+    pub verbose: bool,
+    // TTL of our replies
+    pub reply_ttl: u8,
+    // reply from this address instead of the one a request was sent to
+    pub reply_source: Option<Ipv4Address>,
+    // our address, packets to other ones aren't answered
+    pub addr: Option<Ipv4Address>,
+    pub forward: bool,
+    // the subnet of the device, to recognize its broadcast address
+    pub subnet: Option<Ipv4Cidr>,
+    // answer echo requests sent to broadcast and multicast addresses
+    pub broadcast_echo: bool,
+    // what to put in the payload of echo replies
+    pub echo_payload: EchoPayload,
+    // (request id, reply id): the identifiers to swap in echo replies
+    pub rewrite_id: Vec<(u16, u16)>,
+    pub delay_ms: u64,
+    // a random extra delay for each echo reply, in milliseconds
+    pub jitter: Option<(u64, u64)>,
+    // echo replies per second, unlimited if not set
+    pub rate: Option<u32>,
+    // fractions of the echo replies to drop and to corrupt
+    pub loss: f64,
+    pub corrupt: f64,
+    // answer Don't Fragment datagrams bigger than this with
+    // "fragmentation needed"
+    pub mtu_probe: Option<u16>,
+    // the MTU advertised in those, the threshold if not set
+    pub advertise_mtu: Option<u16>,
+    // drop replies bigger than the MTU instead of fragmenting them
+    pub no_fragment: bool,
+    // leave the checksums of our replies at 0
+    pub no_checksum: bool,
+    // exit after this many frames, never if 0
+    pub count: u64,
+    // capture everything received and sent to this file
+    pub pcap: Option<String>,
+    // read frames from this file instead of a TUN/TAP device
+    pub replay: Option<String>,
+    // send echo requests to this address instead of answering
+    pub ping: Option<Ipv4Address>,
+    // source address of our echo requests
+    pub source: Ipv4Address,
+}
+
+impl Default for Args {
+    fn default() -> Self {
+        Args {
+            iface: String::from("tun0"),
+            mode: Mode::Tun,
+            allow: Vec::new(),
+            trust: Vec::new(),
+            json: false,
+            verbose: false,
+            reply_ttl: DEFAULT_REPLY_TTL,
+            reply_source: None,
+            addr: None,
+            forward: false,
+            subnet: None,
+            broadcast_echo: false,
+            echo_payload: EchoPayload::Mirror,
+            rewrite_id: Vec::new(),
+            delay_ms: 0,
+            jitter: None,
+            rate: None,
+            loss: 0.0,
+            corrupt: 0.0,
+            mtu_probe: None,
+            advertise_mtu: None,
+            no_fragment: false,
+            no_checksum: false,
+            count: 0,
+            pcap: None,
+            replay: None,
+            ping: None,
+            source: Ipv4Address::from(DEFAULT_PING_SOURCE),
+        }
+    }
+}
+
+fn parse_mode(mode: &str) -> Result<Mode, String> {
+    match mode {
+        "tun" => Ok(Mode::Tun),
+        "tap" => Ok(Mode::Tap),
+        _ => Err(format!("unknown mode '{mode}', expected 'tun' or 'tap'")),
+    }
+}
+
+fn parse_echo_payload(echo_payload: &str) -> Result<EchoPayload, String> {
+    match echo_payload {
+        "mirror" => Ok(EchoPayload::Mirror),
+        "zero" => Ok(EchoPayload::Zero),
+        "reverse" => Ok(EchoPayload::Reverse),
+        "invert" => Ok(EchoPayload::Invert),
+        _ => Err(format!("unknown echo payload '{echo_payload}', expected 'mirror', 'zero', 'reverse' or 'invert'")),
+    }
+}
+
+fn parse_rewrite_id(rewrite_id: &str) -> Result<(u16, u16), String> {
+    let invalid = || format!("invalid id rewrite '{rewrite_id}', expected <request id>:<reply id>");
+    let (from, to) = rewrite_id.split_once(':').ok_or_else(invalid)?;
+    Ok((from.parse().map_err(|_| invalid())?, to.parse().map_err(|_| invalid())?))
+}
+
+fn parse_rate(rate: &str) -> Result<u32, String> {
+    match rate.parse() {
+        Ok(rate) if rate > 0 => Ok(rate),
+        _ => Err(format!("invalid rate '{rate}', expected a positive number of replies per second")),
+    }
+}
+
+fn parse_fraction(flag: &str, fraction: &str) -> Result<f64, String> {
+    match fraction.parse() {
+        Ok(fraction) if (0.0..=1.0).contains(&fraction) => Ok(fraction),
+        _ => Err(format!("invalid {flag} '{fraction}', expected a fraction from 0 to 1")),
+    }
+}
+
+fn parse_jitter(jitter: &str) -> Result<(u64, u64), String> {
+    let invalid = || format!("invalid jitter '{jitter}', expected <min>-<max> in milliseconds");
+    let (min, max) = jitter.split_once('-').ok_or_else(invalid)?;
+    let min: u64 = min.parse().map_err(|_| invalid())?;
+    let max: u64 = max.parse().map_err(|_| invalid())?;
+    if min > max {
+        return Err(format!("invalid jitter '{jitter}', the minimum is above the maximum"));
+    }
+    Ok((min, max))
+}
+
+// RFC 791: every host must take 68 byte datagrams
+fn parse_mtu(flag: &str, mtu: &str) -> Result<u16, String> {
+    match mtu.parse() {
+        Ok(mtu) if mtu >= 68 => Ok(mtu),
+        _ => Err(format!("invalid {flag} '{mtu}', expected a number of bytes from 68 to 65535")),
+    }
+}
+
+fn parse_ttl(ttl: &str) -> Result<u8, String> {
+    match ttl.parse() {
+        Ok(ttl) if ttl > 0 => Ok(ttl),
+        _ => Err(format!("invalid TTL '{ttl}', expected a number from 1 to 255")),
+    }
+}
+
+// hand-rolled parser for the command line (without the program name),
+// anything not given keeps its default value
+pub fn parse_args<I: IntoIterator<Item = String>>(args: I) -> Result<Args, String> {
+    let mut parsed = Args::default();
+    let mut args = args.into_iter();
+
+    while let Some(arg) = args.next() {
+        let mut value = |flag: &str| {
+            args.next().ok_or_else(|| format!("missing value for {flag}"))
+        };
+
+        match arg.as_str() {
+            "--iface" => parsed.iface = value("--iface")?,
+            "--mode" => parsed.mode = parse_mode(&value("--mode")?)?,
+            "--allow" => parsed.allow.push(value("--allow")?.parse()?),
+            "--trust" => parsed.trust.push(value("--trust")?.parse()?),
+            "--json" => parsed.json = true,
+            "--verbose" => parsed.verbose = true,
+            "--reply-ttl" => parsed.reply_ttl = parse_ttl(&value("--reply-ttl")?)?,
+            "--reply-source" => parsed.reply_source = Some(value("--reply-source")?.parse()?),
+            "--addr" => parsed.addr = Some(value("--addr")?.parse()?),
+            "--forward" => parsed.forward = true,
+            "--subnet" => parsed.subnet = Some(value("--subnet")?.parse()?),
+            "--broadcast-echo" => parsed.broadcast_echo = true,
+            "--echo-payload" => parsed.echo_payload = parse_echo_payload(&value("--echo-payload")?)?,
+            "--rewrite-id" => parsed.rewrite_id.push(parse_rewrite_id(&value("--rewrite-id")?)?),
+            "--delay-ms" => parsed.delay_ms = value("--delay-ms")?.parse()
+                .map_err(|_| String::from("invalid --delay-ms, expected a number of milliseconds"))?,
+            "--jitter" => parsed.jitter = Some(parse_jitter(&value("--jitter")?)?),
+            "--rate" => parsed.rate = Some(parse_rate(&value("--rate")?)?),
+            "--loss" => parsed.loss = parse_fraction("--loss", &value("--loss")?)?,
+            "--corrupt" => parsed.corrupt = parse_fraction("--corrupt", &value("--corrupt")?)?,
+            "--mtu-probe" => parsed.mtu_probe = Some(parse_mtu("--mtu-probe", &value("--mtu-probe")?)?),
+            "--advertise-mtu" => parsed.advertise_mtu = Some(parse_mtu("--advertise-mtu", &value("--advertise-mtu")?)?),
+            "--no-fragment" => parsed.no_fragment = true,
+            "--no-checksum" => parsed.no_checksum = true,
+            "--count" => parsed.count = value("--count")?.parse()
+                .map_err(|_| String::from("invalid --count, expected a number of packets"))?,
+            "--pcap" => parsed.pcap = Some(value("--pcap")?),
+            "--replay" => parsed.replay = Some(value("--replay")?),
+            "--ping" => parsed.ping = Some(value("--ping")?.parse()?),
+            "--source" => parsed.source = value("--source")?.parse()?,
+            _ => return Err(format!("unknown argument '{arg}'")),
+        }
+    }
+
+    // our echo requests are sent without an Ethernet header
+    if parsed.ping.is_some() && parsed.mode == Mode::Tap {
+        return Err(String::from("--ping only works in TUN mode"));
+    }
+    // replies to broadcasts need a unicast source
+    if parsed.broadcast_echo && parsed.addr.is_none() && parsed.reply_source.is_none() {
+        return Err(String::from("--broadcast-echo needs --addr or --reply-source"));
+    }
+    // ARP is only answered for our own address
+    if parsed.addr.is_none() && parsed.mode == Mode::Tap {
+        return Err(String::from("--mode tap needs --addr"));
+    }
+
+    match (parsed.mtu_probe, parsed.advertise_mtu) {
+        (None, Some(_)) => return Err(String::from("--advertise-mtu only works with --mtu-probe")),
+        // a bigger MTU would tell the sender to try the same size again
+        (Some(threshold), Some(mtu)) if mtu > threshold =>
+            return Err(format!("--advertise-mtu {mtu} is above the --mtu-probe threshold of {threshold}")),
+        _ => {},
+    }
+
+    Ok(parsed)
+}
+
+#[cfg(test)]
+fn to_args(args: &[&str]) -> Vec<String> {
+    args.iter().map(|arg| arg.to_string()).collect()
+}
+
+#[test]
+fn test_parse_args() {
+    assert_eq!(parse_args(to_args(&[])).unwrap(), Args::default());
+
+    let args = parse_args(to_args(&["--iface", "tun5", "--mode", "tap", "--addr", "10.0.0.1"])).unwrap();
+    assert_eq!(args.iface, "tun5");
+    assert_eq!(args.mode, Mode::Tap);
+    assert!(parse_args(to_args(&["--json"])).unwrap().json);
+    assert!(parse_args(to_args(&["--verbose"])).unwrap().verbose);
+    assert!(parse_args(to_args(&["--no-fragment"])).unwrap().no_fragment);
+    assert!(parse_args(to_args(&["--no-checksum"])).unwrap().no_checksum);
+    assert_eq!(parse_args(to_args(&["--echo-payload", "invert"])).unwrap().echo_payload, EchoPayload::Invert);
+    assert!(parse_args(to_args(&["--echo-payload", "random"])).is_err());
+    assert_eq!(parse_args(to_args(&["--count", "3"])).unwrap().count, 3);
+    assert!(parse_args(to_args(&["--count", "x"])).is_err());
+    let args = parse_args(to_args(&["--reply-ttl", "1", "--reply-source", "10.0.0.2"])).unwrap();
+    assert_eq!(args.reply_ttl, 1);
+    assert_eq!(args.reply_source, Some(Ipv4Address::from([10, 0, 0, 2])));
+    assert!(parse_args(to_args(&["--reply-ttl", "0"])).is_err());
+    assert!(parse_args(to_args(&["--reply-ttl", "256"])).is_err());
+    assert!(parse_args(to_args(&["--subnet", "10.0.0.0/24", "--broadcast-echo"])).is_err());
+    let args = parse_args(to_args(&["--subnet", "10.0.0.0/24", "--broadcast-echo", "--addr", "10.0.0.1"])).unwrap();
+    assert_eq!(args.subnet, Some("10.0.0.0/24".parse().unwrap()));
+    assert!(args.broadcast_echo);
+    assert_eq!(args.delay_ms, 0);
+    assert_eq!(parse_args(to_args(&["--delay-ms", "1500"])).unwrap().delay_ms, 1500);
+    assert!(parse_args(to_args(&["--delay-ms", "-1"])).is_err());
+    assert_eq!(parse_args(to_args(&["--jitter", "5-40"])).unwrap().jitter, Some((5, 40)));
+    assert_eq!(parse_args(to_args(&["--jitter", "7-7"])).unwrap().jitter, Some((7, 7)));
+    assert!(parse_args(to_args(&["--jitter", "40-5"])).is_err());
+    assert!(parse_args(to_args(&["--jitter", "40"])).is_err());
+    assert!(parse_args(to_args(&["--jitter", "-5-40"])).is_err());
+    assert_eq!(parse_args(to_args(&["--rate", "100"])).unwrap().rate, Some(100));
+    assert!(parse_args(to_args(&["--rate", "0"])).is_err());
+    let args = parse_args(to_args(&["--loss", "0.1", "--corrupt", "0.05"])).unwrap();
+    assert_eq!((args.loss, args.corrupt), (0.1, 0.05));
+    assert!(parse_args(to_args(&["--loss", "1.5"])).is_err());
+    assert!(parse_args(to_args(&["--corrupt", "NaN"])).is_err());
+    let args = parse_args(to_args(&["--addr", "10.0.0.1", "--forward"])).unwrap();
+    assert_eq!(args.addr, Some(Ipv4Address::from([10, 0, 0, 1])));
+    assert!(args.forward);
+    assert!(parse_args(to_args(&["--mode", "tap", "--addr", "10.0.0.1", "--ping", "10.0.0.0"])).is_err());
+    assert!(parse_args(to_args(&["--mode", "tap"])).is_err());
+    let args = parse_args(to_args(&["--mtu-probe", "1400", "--advertise-mtu", "1280"])).unwrap();
+    assert_eq!((args.mtu_probe, args.advertise_mtu), (Some(1400), Some(1280)));
+    assert!(parse_args(to_args(&["--mtu-probe", "1400", "--advertise-mtu", "1500"])).is_err());
+    assert!(parse_args(to_args(&["--advertise-mtu", "1280"])).is_err());
+    assert!(parse_args(to_args(&["--mtu-probe", "67"])).is_err());
+
+    let args = parse_args(to_args(&["--ping", "10.0.0.0", "--source", "10.0.0.7"])).unwrap();
+    assert_eq!(args.ping, Some(Ipv4Address::from([10, 0, 0, 0])));
+    assert_eq!(args.source, Ipv4Address::from([10, 0, 0, 7]));
+    assert!(parse_args(to_args(&["--ping", "localhost"])).is_err());
+    assert_eq!(parse_args(to_args(&["--pcap", "out.pcap"])).unwrap().pcap.as_deref(), Some("out.pcap"));
+
+    assert!(parse_args(to_args(&["--iface"])).is_err());
+    assert!(parse_args(to_args(&["--mode", "ethernet"])).is_err());
+    assert!(parse_args(to_args(&["--quiet"])).is_err());
+}
+
+#[test]
+fn test_parse_args_rewrite_id() {
+    let args = parse_args(to_args(&["--rewrite-id", "12:48879", "--rewrite-id", "0:1"])).unwrap();
+    assert_eq!(args.rewrite_id, vec![(12, 48879), (0, 1)]);
+    assert!(parse_args(to_args(&[])).unwrap().rewrite_id.is_empty());
+
+    assert!(parse_args(to_args(&["--rewrite-id", "12"])).is_err());
+    assert!(parse_args(to_args(&["--rewrite-id", "12:65536"])).is_err());
+    assert!(parse_args(to_args(&["--rewrite-id", "a:1"])).is_err());
+    assert!(parse_args(to_args(&["--rewrite-id"])).is_err());
+}
+
+#[test]
+fn test_parse_args_allow() {
+    let args = parse_args(to_args(&["--allow", "10.0.0.0/24", "--allow", "192.168.0.0/16"])).unwrap();
+    assert_eq!(args.allow, vec!["10.0.0.0/24".parse().unwrap(), "192.168.0.0/16".parse().unwrap()]);
+
+    assert!(parse_args(to_args(&["--allow", "10.0.0.0"])).is_err());
+    assert!(parse_args(to_args(&["--allow"])).is_err());
+}
+
+#[test]
+fn test_parse_args_trust() {
+    let args = parse_args(to_args(&["--trust", "10.0.0.0/24", "--trust", "192.168.0.0/16"])).unwrap();
+    assert_eq!(args.trust, vec!["10.0.0.0/24".parse().unwrap(), "192.168.0.0/16".parse().unwrap()]);
+    assert!(args.allow.is_empty());
+
+    assert!(parse_args(to_args(&["--trust", "10.0.0.0"])).is_err());
+    assert!(parse_args(to_args(&["--trust"])).is_err());
+}
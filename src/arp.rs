@@ -0,0 +1,137 @@
+use nom::error::{Error, ErrorKind};
+use nom::{number, IResult};
+
+use crate::consts::ETHERTYPE_IPV4;
+use crate::eth::{parse_mac_address, MacAddress};
+use crate::ipv4::Ipv4Address;
+use crate::util::{Deserialize, Serialize};
+
+// RFC 826, only for IPv4 over Ethernet which is all a TAP device carries
+const HARDWARE_TYPE_ETHERNET: u16 = 1;
+const HARDWARE_LEN: u8 = 6;
+const PROTOCOL_LEN: u8 = 4;
+
+pub const ARP_REQUEST: u16 = 1;
+pub const ARP_REPLY: u16 = 2;
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct ArpPacket {
+    pub operation: u16,
+    pub sender_mac: MacAddress,
+    pub sender_ip: Ipv4Address,
+    // unknown (all zeroes) in requests
+    pub target_mac: MacAddress,
+    pub target_ip: Ipv4Address,
+}
+
+impl Serialize for ArpPacket {
+    fn serialize_into(&self, s: &mut Vec<u8>) {
+        s.extend(HARDWARE_TYPE_ETHERNET.to_be_bytes());
+        s.extend(ETHERTYPE_IPV4.to_be_bytes());
+        s.push(HARDWARE_LEN);
+        s.push(PROTOCOL_LEN);
+        s.extend(self.operation.to_be_bytes());
+        s.extend(self.sender_mac.0);
+        s.extend(self.sender_ip.0.to_be_bytes());
+        s.extend(self.target_mac.0);
+        s.extend(self.target_ip.0.to_be_bytes());
+    }
+
+    fn serialized_len(&self) -> usize {
+        8 + 2 * (HARDWARE_LEN + PROTOCOL_LEN) as usize
+    }
+}
+
+// fails with `ErrorKind::Tag` for anything but IPv4 over Ethernet
+pub fn parse_arp_packet(input: &[u8]) -> IResult<&[u8], ArpPacket> {
+    let (rest, hardware_type) = number::complete::be_u16(input)?;
+    let (rest, protocol_type) = number::complete::be_u16(rest)?;
+    let (rest, hardware_len) = number::complete::be_u8(rest)?;
+    let (rest, protocol_len) = number::complete::be_u8(rest)?;
+    if hardware_type != HARDWARE_TYPE_ETHERNET || protocol_type != ETHERTYPE_IPV4
+        || hardware_len != HARDWARE_LEN || protocol_len != PROTOCOL_LEN {
+        return Err(nom::Err::Error(Error::new(input, ErrorKind::Tag)));
+    }
+
+    let (rest, operation) = number::complete::be_u16(rest)?;
+    let (rest, sender_mac) = parse_mac_address(rest)?;
+    let (rest, sender_ip) = number::complete::be_u32(rest)?;
+    let (rest, target_mac) = parse_mac_address(rest)?;
+    let (rest, target_ip) = number::complete::be_u32(rest)?;
+
+    Ok((rest, ArpPacket {
+        operation,
+        sender_mac,
+        sender_ip: Ipv4Address(sender_ip),
+        target_mac,
+        target_ip: Ipv4Address(target_ip),
+    }))
+}
+
+impl Deserialize for ArpPacket {
+    fn deserialize(input: &[u8]) -> IResult<&[u8], Self> {
+        parse_arp_packet(input)
+    }
+}
+
+// Answers a request asking who has `address` with our MAC. Anything else
+// belongs to other hosts on the segment, claiming it would be ARP spoofing.
+pub fn arp_reply(request: &ArpPacket, mac: MacAddress, address: Ipv4Address) -> Option<ArpPacket> {
+    if request.operation != ARP_REQUEST || request.target_ip != address {
+        return None;
+    }
+
+    Some(ArpPacket {
+        operation: ARP_REPLY,
+        sender_mac: mac,
+        sender_ip: request.target_ip,
+        target_mac: request.sender_mac,
+        target_ip: request.sender_ip,
+    })
+}
+
+#[test]
+fn test_arp_reply() {
+    // who has 10.0.0.1? tell 10.0.0.0
+    let request = [
+        0, 1,                                   // Hardware type (Ethernet)
+        8, 0,                                   // Protocol type (IPv4)
+        6, 4,                                   // Hardware and protocol lengths
+        0, 1,                                   // Operation (request)
+        0x5a, 0x3c, 0x11, 0x02, 0x9e, 0x41,     // Sender MAC
+        10, 0, 0, 0,                            // Sender IP
+        0, 0, 0, 0, 0, 0,                       // Target MAC
+        10, 0, 0, 1,                            // Target IP
+    ];
+    let ours = MacAddress([0x02, 0, 0, 0, 0, 0x01]);
+
+    let (rest, parsed) = parse_arp_packet(&request).unwrap();
+    assert!(rest.is_empty());
+    assert_eq!(parsed.operation, ARP_REQUEST);
+    assert_eq!(parsed.sender_ip, Ipv4Address::from([10, 0, 0, 0]));
+    assert_eq!(parsed.serialize(), request);
+    assert_eq!(parsed.serialized_len(), request.len());
+
+    let reply = arp_reply(&parsed, ours, Ipv4Address::from([10, 0, 0, 1])).unwrap();
+    assert_eq!(reply.serialize(), [
+        0, 1, 8, 0, 6, 4,
+        0, 2,                                   // Operation (reply)
+        0x02, 0, 0, 0, 0, 0x01,                 // our MAC
+        10, 0, 0, 1,
+        0x5a, 0x3c, 0x11, 0x02, 0x9e, 0x41,
+        10, 0, 0, 0,
+    ]);
+
+    // not for us, or not a request
+    assert!(arp_reply(&parsed, ours, Ipv4Address::from([10, 0, 0, 2])).is_none());
+    assert!(arp_reply(&reply, ours, Ipv4Address::from([10, 0, 0, 0])).is_none());
+    let mut probe = parsed;
+    probe.target_ip = probe.sender_ip;
+    assert!(arp_reply(&probe, ours, Ipv4Address::from([10, 0, 0, 1])).is_none());
+
+    // IPv6 isn't resolved with ARP
+    let mut other = request;
+    other[2..4].copy_from_slice(&[0x86, 0xdd]);
+    assert!(parse_arp_packet(&other).is_err());
+    assert!(parse_arp_packet(&request[..27]).is_err());
+}
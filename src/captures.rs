@@ -0,0 +1,107 @@
+// Round trip tests over the raw IPv4 packets in tests/data, captured
+// from Linux on a TUN device: each one has to parse with valid checksums
+// and serialize back to the same bytes. New captures only need to be
+// dropped in there as `<name>.bin`.
+
+use std::fs;
+use std::path::PathBuf;
+
+use crate::icmp::{IcmpHeaderData, IcmpPacket, IcmpType};
+use crate::ipv4::{Ipv4Address, Ipv4HeaderProtocol, Ipv4Option, Ipv4Packet};
+use crate::util::Serialize;
+
+fn captures() -> Vec<(String, Vec<u8>)> {
+    let dir = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("tests/data");
+    let mut captures: Vec<(String, Vec<u8>)> = fs::read_dir(&dir).unwrap()
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.extension().is_some_and(|extension| extension == "bin"))
+        .map(|path| (path.file_stem().unwrap().to_string_lossy().into_owned(), fs::read(&path).unwrap()))
+        .collect();
+    captures.sort();
+    captures
+}
+
+fn parse_capture(captures: &[(String, Vec<u8>)], name: &str) -> (Ipv4Packet, IcmpPacket) {
+    let (_, bytes) = captures.iter().find(|(capture, _)| capture == name)
+        .unwrap_or_else(|| panic!("tests/data/{name}.bin is missing"));
+    let packet = Ipv4Packet::parse(bytes).unwrap();
+    let icmp_packet = IcmpPacket::from_ipv4(&packet).unwrap();
+    (packet, icmp_packet)
+}
+
+#[test]
+fn test_captures_round_trip() {
+    let captures = captures();
+    assert!(!captures.is_empty());
+
+    for (name, bytes) in &captures {
+        // the header checksum is verified, and so is the ICMP one below
+        let packet = Ipv4Packet::parse(bytes).unwrap_or_else(|e| panic!("{name}: {e}"));
+        assert_eq!(packet.header.total_length as usize, bytes.len(), "{name}: trailing bytes");
+        assert_eq!(packet.serialize(), *bytes, "{name}: IPv4 round trip");
+
+        if packet.header.protocol == Ipv4HeaderProtocol::Icmp {
+            let icmp_packet = IcmpPacket::from_ipv4(&packet).unwrap_or_else(|e| panic!("{name}: {e}"));
+            assert_eq!(icmp_packet.serialize(), packet.data, "{name}: ICMP round trip");
+        }
+    }
+}
+
+#[test]
+fn test_captures_contents() {
+    let captures = captures();
+
+    // `ping -R`: the echo carries a Record Route option with room for 9
+    // addresses, the sender already put its own in
+    let record_route = |packet: &Ipv4Packet| match &packet.header.options[0] {
+        Ipv4Option::RecordRoute { pointer, addrs } => (*pointer, addrs.clone()),
+        other => panic!("expected Record Route, got {:?}", other),
+    };
+    let (packet, icmp_packet) = parse_capture(&captures, "echo_request_record_route");
+    assert_eq!(packet.header.prelude.header_length, 15);
+    let (pointer, addrs) = record_route(&packet);
+    assert_eq!(pointer, 8);
+    assert_eq!(addrs.len(), 9);
+    assert_eq!(addrs[0], Ipv4Address::from([10, 0, 0, 2]));
+    assert_eq!(icmp_packet.header.icmp_type, IcmpType::EchoRequest);
+
+    // the host pinged adds itself twice, on the way in and on the way out
+    let (packet, icmp_packet) = parse_capture(&captures, "echo_reply_record_route");
+    let (pointer, addrs) = record_route(&packet);
+    assert_eq!(pointer, 16);
+    assert_eq!(addrs[..3], [Ipv4Address::from([10, 0, 0, 2]), Ipv4Address::from([10, 0, 0, 1]), Ipv4Address::from([10, 0, 0, 1])]);
+    assert_eq!(icmp_packet.header.icmp_type, IcmpType::EchoReply);
+
+    let (_, icmp_packet) = parse_capture(&captures, "echo_reply_1500");
+    assert_eq!(icmp_packet.header.icmp_type, IcmpType::EchoReply);
+    assert_eq!(icmp_packet.data.len(), 1472);
+
+    // the router quotes the whole traceroute-style UDP probe, whose TTL
+    // ran out
+    let (_, icmp_packet) = parse_capture(&captures, "time_exceeded_traceroute");
+    match icmp_packet.header.data {
+        Some(IcmpHeaderData::TimeExceeded { ip_header, .. }) => {
+            assert_eq!(ip_header.ttl, 1);
+            assert_eq!(ip_header.protocol, Ipv4HeaderProtocol::Udp);
+        },
+        other => panic!("expected Time Exceeded data, got {:?}", other),
+    }
+
+    let (packet, icmp_packet) = parse_capture(&captures, "dest_unreachable_port");
+    assert_eq!((icmp_packet.header.icmp_type, icmp_packet.header.code), (IcmpType::DestinationUnreachable, 3));
+    match icmp_packet.header.data {
+        Some(IcmpHeaderData::DestinationUnreachable { ip_header, .. }) => assert_eq!(ip_header.source, packet.header.destination),
+        other => panic!("expected Destination Unreachable data, got {:?}", other),
+    }
+
+    // quotes only the start of the 1480 byte datagram, in 576 bytes
+    let (_, icmp_packet) = parse_capture(&captures, "fragmentation_needed");
+    match icmp_packet.header.data {
+        Some(IcmpHeaderData::DestinationUnreachable { next_hop_mtu, ip_header, .. }) => {
+            assert_eq!(next_hop_mtu, 1400);
+            assert_eq!(ip_header.total_length, 1480);
+            assert!(ip_header.frag_info.dont_fragment());
+        },
+        other => panic!("expected Destination Unreachable data, got {:?}", other),
+    }
+}
@@ -0,0 +1,36 @@
+// Numbers from the RFCs and the Linux headers that more than one module
+// needs, so nobody has to hardcode them
+
+// https://www.iana.org/assignments/ieee-802-numbers/ieee-802-numbers.xhtml
+pub const ETHERTYPE_IPV4: u16 = 0x0800;
+pub const ETHERTYPE_ARP: u16 = 0x0806;
+pub const ETHERTYPE_IPV6: u16 = 0x86dd;
+
+// Linux puts 2 bytes of flags and the ethertype of the packet in front
+// of every frame read from (or written to) a TUN/TAP device
+pub const TUN_PREFIX_LEN: usize = 4;
+pub const TUN_FLAGS: [u8; 2] = [0x00, 0x00];
+pub const TUN_PREFIX_IPV4: [u8; TUN_PREFIX_LEN] = [
+    TUN_FLAGS[0], TUN_FLAGS[1], (ETHERTYPE_IPV4 >> 8) as u8, ETHERTYPE_IPV4 as u8,
+];
+pub const TUN_PREFIX_IPV6: [u8; TUN_PREFIX_LEN] = [
+    TUN_FLAGS[0], TUN_FLAGS[1], (ETHERTYPE_IPV6 >> 8) as u8, ETHERTYPE_IPV6 as u8,
+];
+
+// without the frame check sequence, which TAP devices don't carry
+pub const ETHERNET_HEADER_LEN: usize = 14;
+
+// without options
+pub const IPV4_MIN_HEADER_LEN: usize = 20;
+// fixed, options go into extension headers
+pub const IPV6_HEADER_LEN: usize = 40;
+// type, code, checksum and the 4 bytes of "rest of header"
+pub const ICMP_HEADER_LEN: usize = 8;
+
+const _: () = assert!(TUN_PREFIX_IPV4[2] == 0x08 && TUN_PREFIX_IPV4[3] == 0x00);
+
+#[test]
+fn test_tun_prefix() {
+    assert_eq!(TUN_PREFIX_IPV4, [0, 0, 8, 0]);
+    assert_eq!(TUN_PREFIX_IPV6, [0, 0, 0x86, 0xdd]);
+}
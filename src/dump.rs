@@ -0,0 +1,150 @@
+use std::fmt::Write;
+
+use crate::icmp::parse_icmp_packet;
+use crate::ipv4::{Ipv4HeaderFragmentationInfo, Ipv4HeaderProtocol, Ipv4Packet};
+
+// Renders a packet as an indented tree, one node per layer:
+//
+// IPv4
+//   source: 10.0.0.0
+//   ...
+//   ICMP
+//     type: Echo Request
+//     ...
+//     payload: 56 bytes
+//     data: 10 24 9e 64 ...
+
+const INDENT: &str = "  ";
+// how much of the payload is shown, enough to recognize it
+const DATA_PREVIEW_LEN: usize = 16;
+
+fn field(out: &mut String, depth: usize, name: &str, value: impl std::fmt::Display) {
+    // writing into a String never fails
+    let _ = writeln!(out, "{}{}: {}", INDENT.repeat(depth), name, value);
+}
+
+fn data_preview(data: &[u8]) -> String {
+    let bytes: Vec<String> = data.iter().take(DATA_PREVIEW_LEN).map(|byte| format!("{byte:02x}")).collect();
+    if data.len() > DATA_PREVIEW_LEN {
+        format!("{} ...", bytes.join(" "))
+    } else {
+        bytes.join(" ")
+    }
+}
+
+fn node(out: &mut String, depth: usize, name: &str) {
+    let _ = writeln!(out, "{}{}", INDENT.repeat(depth), name);
+}
+
+// the flags that are set, by their usual abbreviations
+fn flags(frag_info: &Ipv4HeaderFragmentationInfo) -> String {
+    let mut flags = Vec::new();
+    if frag_info.dont_fragment() {
+        flags.push("DF");
+    }
+    if frag_info.more_fragments() {
+        flags.push("MF");
+    }
+    if flags.is_empty() {
+        String::from("none")
+    } else {
+        flags.join(" ")
+    }
+}
+
+pub fn format_tree(packet: &Ipv4Packet) -> String {
+    let mut out = String::new();
+    let header = &packet.header;
+
+    node(&mut out, 0, "IPv4");
+    field(&mut out, 1, "version", header.prelude.version);
+    field(&mut out, 1, "header_length", header.prelude.header_length);
+    field(&mut out, 1, "dscp", header.prelude.dscp);
+    field(&mut out, 1, "ecn", header.prelude.ecn);
+    field(&mut out, 1, "total_length", header.total_length);
+    field(&mut out, 1, "identification", header.identification);
+    field(&mut out, 1, "flags", flags(&header.frag_info));
+    field(&mut out, 1, "fragment_offset", header.frag_info.offset);
+    field(&mut out, 1, "ttl", header.ttl);
+    field(&mut out, 1, "protocol", header.protocol);
+    field(&mut out, 1, "checksum", format!("{:#06x}", header.checksum));
+    field(&mut out, 1, "source", header.source);
+    field(&mut out, 1, "destination", header.destination);
+
+    if header.protocol != Ipv4HeaderProtocol::Icmp {
+        field(&mut out, 1, "payload", format!("{} bytes", packet.data.len()));
+        if !packet.data.is_empty() {
+            field(&mut out, 1, "data", data_preview(&packet.data));
+        }
+        return out;
+    }
+
+    match parse_icmp_packet(&packet.data) {
+        Ok((_, icmp)) => {
+            node(&mut out, 1, "ICMP");
+            field(&mut out, 2, "type", icmp.header.icmp_type);
+            field(&mut out, 2, "code", icmp.header.code);
+            field(&mut out, 2, "description", icmp.description());
+            field(&mut out, 2, "checksum", format!("{:#06x}", icmp.header.checksum));
+            if let (Some(id), Some(seq)) = (icmp.echo_id(), icmp.echo_seq()) {
+                field(&mut out, 2, "id", id);
+                field(&mut out, 2, "seq", seq);
+            }
+            field(&mut out, 2, "payload", format!("{} bytes", icmp.data.len()));
+            if !icmp.data.is_empty() {
+                field(&mut out, 2, "data", data_preview(&icmp.data));
+            }
+        },
+        Err(_) => {
+            field(&mut out, 1, "payload", format!("{} bytes (malformed ICMP)", packet.data.len()));
+        },
+    }
+
+    out
+}
+
+#[test]
+fn test_format_tree() {
+    use crate::ipv4::parse_ipv4_packet;
+    use crate::util::PING_REQUEST;
+
+    // the ICMP node nests under the IPv4 one
+    let (_, packet) = parse_ipv4_packet(&PING_REQUEST).unwrap();
+    assert_eq!(format_tree(&packet), "\
+IPv4
+  version: 4
+  header_length: 5
+  dscp: 0
+  ecn: 0
+  total_length: 84
+  identification: 16758
+  flags: DF
+  fragment_offset: 0
+  ttl: 64
+  protocol: ICMP
+  checksum: 0xe532
+  source: 10.0.0.0
+  destination: 10.0.0.1
+  ICMP
+    type: Echo Request
+    code: 0
+    description: Echo request
+    checksum: 0x5bb6
+    id: 9
+    seq: 2
+    payload: 56 bytes
+    data: 10 24 9e 64 00 00 00 00 2e e3 00 00 00 00 00 00 ...
+");
+
+    // short payloads are shown whole
+    let mut udp = packet;
+    udp.header.protocol = Ipv4HeaderProtocol::Udp;
+    udp.data.truncate(4);
+    assert!(format_tree(&udp).ends_with("  payload: 4 bytes\n  data: 08 00 5b b6\n"));
+    assert!(format_tree(&udp).contains("\n  protocol: UDP\n"));
+
+    udp.header.frag_info.set_dont_fragment(false);
+    assert!(format_tree(&udp).contains("\n  flags: none\n"));
+    udp.header.frag_info.set_more_fragments(true);
+    assert!(format_tree(&udp).contains("\n  flags: MF\n"));
+}
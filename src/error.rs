@@ -0,0 +1,48 @@
+use std::fmt;
+
+use nom::error::{Error, ErrorKind};
+
+// Errors surfaced by the public parsing API, so that callers don't have
+// to dig into nom's error types
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum IcmpTuntapError {
+    TooShort,
+    BadChecksum,
+    UnsupportedVersion,
+    UnsupportedProtocol,
+    // the IPv4 header claims to be longer than the whole packet
+    BadHeaderLength,
+}
+
+impl fmt::Display for IcmpTuntapError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let description = match self {
+            IcmpTuntapError::TooShort => "packet is too short",
+            IcmpTuntapError::BadChecksum => "checksum mismatch",
+            IcmpTuntapError::UnsupportedVersion => "unsupported IP version",
+            IcmpTuntapError::UnsupportedProtocol => "unsupported protocol",
+            IcmpTuntapError::BadHeaderLength => "header length exceeds the total length",
+        };
+        write!(f, "{}", description)
+    }
+}
+
+impl std::error::Error for IcmpTuntapError {}
+
+// our parsers signal a bad checksum with `ErrorKind::Verify`, a wrong IP
+// version with `ErrorKind::Tag` and IPv4 options running past the end of
+// the packet with `ErrorKind::TooLarge`; anything else means we ran out
+// of input or the lengths don't add up
+impl<I> From<nom::Err<Error<I>>> for IcmpTuntapError {
+    fn from(e: nom::Err<Error<I>>) -> Self {
+        match e {
+            nom::Err::Error(e) | nom::Err::Failure(e) => match e.code {
+                ErrorKind::Verify => IcmpTuntapError::BadChecksum,
+                ErrorKind::Tag => IcmpTuntapError::UnsupportedVersion,
+                ErrorKind::TooLarge => IcmpTuntapError::BadHeaderLength,
+                _ => IcmpTuntapError::TooShort,
+            },
+            nom::Err::Incomplete(_) => IcmpTuntapError::TooShort,
+        }
+    }
+}
@@ -0,0 +1,89 @@
+use std::fmt;
+
+use nom::{bytes, number, IResult};
+
+use crate::consts::ETHERNET_HEADER_LEN;
+use crate::util::{Deserialize, Serialize};
+
+#[derive(Clone, Copy, Eq, PartialEq, Hash)]
+pub struct MacAddress(pub [u8; 6]);
+
+impl MacAddress {
+    pub const BROADCAST: MacAddress = MacAddress([0xff; 6]);
+
+    pub fn is_broadcast(&self) -> bool {
+        *self == MacAddress::BROADCAST
+    }
+}
+
+impl fmt::Display for MacAddress {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let [a, b, c, d, e, g] = self.0;
+        write!(f, "{a:02x}:{b:02x}:{c:02x}:{d:02x}:{e:02x}:{g:02x}")
+    }
+}
+
+impl fmt::Debug for MacAddress {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self)
+    }
+}
+
+// the frame check sequence isn't part of it, TAP devices don't carry it
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct EthernetHeader {
+    pub destination: MacAddress,
+    pub source: MacAddress,
+    pub ethertype: u16,
+}
+
+impl Serialize for EthernetHeader {
+    fn serialize_into(&self, s: &mut Vec<u8>) {
+        s.extend(self.destination.0);
+        s.extend(self.source.0);
+        s.extend(self.ethertype.to_be_bytes());
+    }
+
+    fn serialized_len(&self) -> usize {
+        ETHERNET_HEADER_LEN
+    }
+}
+
+pub fn parse_mac_address(input: &[u8]) -> IResult<&[u8], MacAddress> {
+    let (input, mac) = bytes::complete::take(6usize)(input)?;
+    Ok((input, MacAddress(mac.try_into().unwrap())))
+}
+
+pub fn parse_ethernet_header(input: &[u8]) -> IResult<&[u8], EthernetHeader> {
+    let (input, destination) = parse_mac_address(input)?;
+    let (input, source) = parse_mac_address(input)?;
+    let (input, ethertype) = number::complete::be_u16(input)?;
+    Ok((input, EthernetHeader { destination, source, ethertype }))
+}
+
+impl Deserialize for EthernetHeader {
+    fn deserialize(input: &[u8]) -> IResult<&[u8], Self> {
+        parse_ethernet_header(input)
+    }
+}
+
+#[test]
+fn test_ethernet_header() {
+    use crate::consts::ETHERTYPE_ARP;
+
+    let bytes = [
+        0xff, 0xff, 0xff, 0xff, 0xff, 0xff,     // Destination
+        0x5a, 0x3c, 0x11, 0x02, 0x9e, 0x41,     // Source
+        0x08, 0x06,                             // Ethertype (ARP)
+        0, 1,                                   // start of the payload
+    ];
+
+    let (rest, header) = parse_ethernet_header(&bytes).unwrap();
+    assert_eq!(rest, [0, 1]);
+    assert!(header.destination.is_broadcast());
+    assert_eq!(header.source.to_string(), "5a:3c:11:02:9e:41");
+    assert_eq!(header.ethertype, ETHERTYPE_ARP);
+    assert_eq!(header.serialize(), bytes[..ETHERNET_HEADER_LEN]);
+
+    assert!(parse_ethernet_header(&bytes[..13]).is_err());
+}
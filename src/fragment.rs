@@ -0,0 +1,399 @@
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+use crate::ipv4::{Ipv4Address, Ipv4Header, Ipv4HeaderProtocol, Ipv4Packet};
+
+// https://en.wikipedia.org/wiki/IP_fragmentation
+
+// fragments belonging to the same original datagram share these fields
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Hash)]
+pub struct FragmentKey {
+    pub source: Ipv4Address,
+    pub destination: Ipv4Address,
+    pub identification: u16,
+    pub protocol: Ipv4HeaderProtocol,
+}
+
+impl FragmentKey {
+    pub fn of(header: &Ipv4Header) -> FragmentKey {
+        FragmentKey {
+            source: header.source,
+            destination: header.destination,
+            identification: header.identification,
+            protocol: header.protocol,
+        }
+    }
+}
+
+#[derive(Debug)]
+struct FragmentSet {
+    // header of the fragment at offset 0, reused for the reassembled packet
+    header: Option<Ipv4Header>,
+    // (byte offset, data)
+    fragments: Vec<(usize, Vec<u8>)>,
+    // known once the last fragment (MF unset) arrives
+    total_len: Option<usize>,
+    buffered: usize,
+    first_seen: Instant,
+}
+
+impl FragmentSet {
+    fn new(now: Instant) -> FragmentSet {
+        FragmentSet {
+            header: None,
+            fragments: Vec::new(),
+            total_len: None,
+            buffered: 0,
+            first_seen: now,
+        }
+    }
+
+    fn is_complete(&self) -> bool {
+        let total_len = match (self.total_len, &self.header) {
+            (Some(total_len), Some(_)) => total_len,
+            _ => return false,
+        };
+
+        let mut covered = 0;
+        let mut fragments: Vec<&(usize, Vec<u8>)> = self.fragments.iter().collect();
+        fragments.sort_by_key(|(offset, _)| *offset);
+        for (offset, data) in fragments {
+            if *offset > covered {
+                return false;
+            }
+            covered = covered.max(offset + data.len());
+        }
+
+        covered >= total_len
+    }
+
+    fn assemble(self) -> Option<Ipv4Packet> {
+        let total_len = self.total_len?;
+        let mut header = self.header?;
+
+        let mut data = vec![0u8; total_len];
+        for (offset, fragment) in self.fragments {
+            let end = (offset + fragment.len()).min(total_len);
+            if offset < end {
+                data[offset..end].copy_from_slice(&fragment[..end - offset]);
+            }
+        }
+
+        header.frag_info.set_more_fragments(false);
+        header.frag_info.offset = 0;
+        header.total_length = u16::try_from(header.prelude.header_length as usize * 4 + total_len).ok()?;
+
+        let mut packet = Ipv4Packet { header, data };
+        packet.update_checksum();
+        Some(packet)
+    }
+}
+
+impl Ipv4Packet {
+    // Splits the packet into fragments no larger than `mtu` bytes. Every
+    // fragment but the last has More Fragments set, and their offsets are
+    // in 8-byte units, so the payload is cut at multiples of 8 bytes. For
+    // simplicity every fragment carries the options of the original. An
+    // MTU without room for the header and 8 bytes of payload can't be
+    // met, the packet is then returned as is.
+    pub fn fragment(&self, mtu: usize) -> Vec<Ipv4Packet> {
+        let header_len = self.header.prelude.header_length as usize * 4;
+        if header_len + self.data.len() <= mtu || mtu < header_len + 8 {
+            return vec![self.clone()];
+        }
+
+        let chunk_len = (mtu - header_len) / 8 * 8;
+        let chunks: Vec<&[u8]> = self.data.chunks(chunk_len).collect();
+        let last = chunks.len() - 1;
+        // the packet may itself be a fragment of a larger datagram
+        let more_fragments = self.header.frag_info.more_fragments();
+
+        chunks.iter().enumerate().map(|(i, chunk)| {
+            let mut header = self.header.clone();
+            header.frag_info.offset += (i * chunk_len / 8) as u16;
+            header.frag_info.set_more_fragments(i != last || more_fragments);
+            header.total_length = (header_len + chunk.len()) as u16;
+
+            let mut fragment = Ipv4Packet { header, data: chunk.to_vec() };
+            fragment.update_checksum();
+            fragment
+        }).collect()
+    }
+}
+
+// Reassembles fragmented datagrams while keeping the memory held by
+// incomplete sets under `byte_budget`: fragments that don't fit are
+// dropped (and counted) until completed or evicted sets free up room.
+// Sets still incomplete `timeout` after their first fragment are given up.
+#[allow(dead_code)]
+#[derive(Debug)]
+pub struct FragmentReassembler {
+    sets: HashMap<FragmentKey, FragmentSet>,
+    byte_budget: usize,
+    timeout: Duration,
+    buffered_bytes: usize,
+    pub dropped: usize,
+    pub timed_out: usize,
+}
+
+#[allow(dead_code)]
+impl FragmentReassembler {
+    pub fn new(byte_budget: usize, timeout: Duration) -> FragmentReassembler {
+        FragmentReassembler {
+            sets: HashMap::new(),
+            byte_budget,
+            timeout,
+            buffered_bytes: 0,
+            dropped: 0,
+            timed_out: 0,
+        }
+    }
+
+    pub fn buffered_bytes(&self) -> usize {
+        self.buffered_bytes
+    }
+
+    // returns the reassembled datagram once `packet` completes it;
+    // unfragmented packets are handed back untouched
+    pub fn push(&mut self, packet: Ipv4Packet) -> Option<Ipv4Packet> {
+        self.push_at(packet, Instant::now())
+    }
+
+    pub fn push_at(&mut self, packet: Ipv4Packet, now: Instant) -> Option<Ipv4Packet> {
+        self.expire(now);
+
+        let frag_info = packet.header.frag_info;
+        if !frag_info.is_fragment() {
+            return Some(packet);
+        }
+
+        let key = FragmentKey::of(&packet.header);
+        let offset = frag_info.offset as usize * 8;
+
+        // a retransmitted fragment adds nothing, don't charge it twice
+        if self.sets.get(&key).is_some_and(|set| set.fragments.iter().any(|(o, data)| *o == offset && *data == packet.data)) {
+            return None;
+        }
+
+        // the reassembled datagram couldn't fit its total length field,
+        // give up on the whole set
+        let header_len = packet.header.prelude.header_length as usize * 4;
+        if header_len + offset + packet.data.len() > u16::MAX as usize {
+            self.remove(&key);
+            self.dropped += 1;
+            return None;
+        }
+
+        if self.buffered_bytes + packet.data.len() > self.byte_budget {
+            self.dropped += 1;
+            return None;
+        }
+
+        let set = self.sets.entry(key).or_insert_with(|| FragmentSet::new(now));
+
+        if !frag_info.more_fragments() {
+            set.total_len = Some(offset + packet.data.len());
+        }
+
+        set.buffered += packet.data.len();
+        self.buffered_bytes += packet.data.len();
+        set.fragments.push((offset, packet.data));
+        if offset == 0 {
+            set.header = Some(packet.header);
+        }
+
+        if !set.is_complete() {
+            return None;
+        }
+
+        let set = self.remove(&key)?;
+        set.assemble()
+    }
+
+    // gives up on the sets that have been waiting for longer than the timeout
+    pub fn expire(&mut self, now: Instant) {
+        let timeout = self.timeout;
+        let expired: Vec<FragmentKey> = self.sets.iter()
+            .filter(|(_, set)| now.saturating_duration_since(set.first_seen) >= timeout)
+            .map(|(key, _)| *key)
+            .collect();
+
+        for key in expired {
+            self.remove(&key);
+            self.timed_out += 1;
+        }
+    }
+
+    // drops an incomplete set, releasing its share of the budget
+    pub fn evict(&mut self, key: &FragmentKey) -> bool {
+        self.remove(key).is_some()
+    }
+
+    fn remove(&mut self, key: &FragmentKey) -> Option<FragmentSet> {
+        let set = self.sets.remove(key)?;
+        self.buffered_bytes -= set.buffered;
+        Some(set)
+    }
+}
+
+#[cfg(test)]
+fn test_fragment(identification: u16, offset: u16, more: bool, data: Vec<u8>) -> Ipv4Packet {
+    use crate::ipv4::MORE_FRAGMENTS;
+
+    let mut header = Ipv4Header::new(Ipv4Address(0x0a000000), Ipv4Address(0x0a000001), Ipv4HeaderProtocol::Icmp, data.len());
+    header.identification = identification;
+    header.frag_info.flags = if more { MORE_FRAGMENTS } else { 0 };
+    header.frag_info.offset = offset;
+    header.update_checksum();
+    Ipv4Packet { header, data }
+}
+
+#[test]
+fn test_fragment_reassembler_byte_budget() {
+    let mut reassembler = FragmentReassembler::new(64, Duration::from_secs(30));
+
+    assert!(reassembler.push(test_fragment(1, 0, true, vec![1; 48])).is_none());
+    assert_eq!(reassembler.buffered_bytes(), 48);
+
+    // a new set doesn't fit in the remaining budget
+    assert!(reassembler.push(test_fragment(2, 0, true, vec![2; 48])).is_none());
+    assert_eq!(reassembler.dropped, 1);
+    assert_eq!(reassembler.buffered_bytes(), 48);
+
+    // completing the first set frees its memory
+    let packet = reassembler.push(test_fragment(1, 6, false, vec![1; 8])).unwrap();
+    assert_eq!(packet.data, vec![1; 56]);
+    assert_eq!(packet.header.total_length, 20 + 56);
+    assert!(packet.checksum_is_valid());
+    assert_eq!(reassembler.buffered_bytes(), 0);
+
+    // so does evicting an incomplete one
+    assert!(reassembler.push(test_fragment(2, 0, true, vec![2; 48])).is_none());
+    assert_eq!(reassembler.buffered_bytes(), 48);
+    let key = FragmentKey::of(&test_fragment(2, 0, true, Vec::new()).header);
+    assert!(reassembler.evict(&key));
+    assert_eq!(reassembler.buffered_bytes(), 0);
+    assert!(reassembler.push(test_fragment(3, 0, true, vec![3; 48])).is_none());
+    assert_eq!(reassembler.dropped, 1);
+}
+
+#[test]
+fn test_fragment_reassembler_icmp_echo() {
+    use crate::icmp::{parse_icmp_packet, IcmpPacket};
+    use crate::ipv4::Ipv4PacketBuilder;
+    use crate::util::Serialize;
+
+    let payload: Vec<u8> = (0..100).collect();
+    let original = Ipv4PacketBuilder::new()
+        .source(Ipv4Address::from([10, 0, 0, 0]))
+        .destination(Ipv4Address::from([10, 0, 0, 1]))
+        .identification(42)
+        .payload(IcmpPacket::echo_request(1, 1, &payload).serialize())
+        .build();
+
+    // split right after 56 bytes (7 fragment offset units)
+    let mut first = Ipv4Packet {
+        header: original.header.clone(),
+        data: original.data[..56].to_vec(),
+    };
+    first.header.frag_info.set_more_fragments(true);
+    first.header.total_length = 20 + 56;
+    first.update_checksum();
+
+    let mut second = Ipv4Packet {
+        header: original.header.clone(),
+        data: original.data[56..].to_vec(),
+    };
+    second.header.frag_info.offset = 7;
+    second.header.total_length = 20 + second.data.len() as u16;
+    second.update_checksum();
+
+    let mut reassembler = FragmentReassembler::new(64 * 1024, Duration::from_secs(30));
+    let start = Instant::now();
+    assert!(reassembler.push_at(second, start).is_none());
+    let packet = reassembler.push_at(first, start + Duration::from_secs(1)).unwrap();
+
+    assert_eq!(packet.serialize(), original.serialize());
+    let (_, icmp_packet) = parse_icmp_packet(&packet.data).unwrap();
+    assert_eq!(icmp_packet.data, payload);
+}
+
+#[test]
+fn test_fragment_reassembler_timeout() {
+    let mut reassembler = FragmentReassembler::new(64 * 1024, Duration::from_secs(30));
+    let start = Instant::now();
+
+    assert!(reassembler.push_at(test_fragment(1, 0, true, vec![1; 48]), start).is_none());
+    reassembler.expire(start + Duration::from_secs(29));
+    assert_eq!(reassembler.buffered_bytes(), 48);
+
+    // the last fragment shows up too late, the set was already dropped
+    let late = test_fragment(1, 6, false, vec![1; 8]);
+    assert!(reassembler.push_at(late, start + Duration::from_secs(31)).is_none());
+    assert_eq!(reassembler.timed_out, 1);
+    assert_eq!(reassembler.buffered_bytes(), 8);
+}
+
+#[test]
+fn test_fragment_then_reassemble() {
+    use crate::ipv4::Ipv4PacketBuilder;
+    use crate::util::Serialize;
+
+    let original = Ipv4PacketBuilder::new()
+        .source(Ipv4Address::from([10, 0, 0, 1]))
+        .destination(Ipv4Address::from([10, 0, 0, 0]))
+        .identification(7)
+        .payload((0..3000).map(|i| i as u8).collect())
+        .build();
+
+    let fragments = original.fragment(1500);
+    assert_eq!(fragments.len(), 3);
+    for fragment in &fragments {
+        assert!(fragment.serialize().len() <= 1500);
+        assert!(fragment.checksum_is_valid());
+        assert_eq!(fragment.header.identification, 7);
+    }
+    assert_eq!(fragments[1].header.frag_info.offset, 1480 / 8);
+    assert!(fragments[0].header.frag_info.more_fragments());
+    assert!(!fragments[2].header.frag_info.more_fragments());
+
+    let mut reassembler = FragmentReassembler::new(64 * 1024, Duration::from_secs(30));
+    let mut reassembled = None;
+    for fragment in fragments {
+        reassembled = reassembler.push(fragment);
+    }
+    assert_eq!(reassembled.unwrap().serialize(), original.serialize());
+
+    // packets that fit are left alone
+    assert_eq!(original.fragment(4000).len(), 1);
+
+    // and so are those the MTU has no room for, payload or not
+    for mtu in [0, 20, 27] {
+        let fragments = original.fragment(mtu);
+        assert_eq!(fragments.len(), 1, "MTU {mtu}");
+        assert_eq!(fragments[0].serialize(), original.serialize());
+    }
+    let empty = Ipv4PacketBuilder::new().build();
+    assert_eq!(empty.fragment(8)[0].serialize(), empty.serialize());
+    assert_eq!(original.fragment(28).len(), 375);
+}
+
+#[test]
+fn test_fragment_reassembler_duplicates_and_overflow() {
+    let mut reassembler = FragmentReassembler::new(64 * 1024, Duration::from_secs(30));
+
+    // a duplicate is only buffered once
+    assert!(reassembler.push(test_fragment(1, 0, true, vec![1; 48])).is_none());
+    assert!(reassembler.push(test_fragment(1, 0, true, vec![1; 48])).is_none());
+    assert_eq!(reassembler.buffered_bytes(), 48);
+    assert_eq!(reassembler.dropped, 0);
+    let packet = reassembler.push(test_fragment(1, 6, false, vec![1; 8])).unwrap();
+    assert_eq!(packet.data, vec![1; 56]);
+    assert_eq!(reassembler.buffered_bytes(), 0);
+
+    // 20 + 8190 * 8 + 16 bytes don't fit in total_length, the set is dropped
+    assert!(reassembler.push(test_fragment(2, 0, true, vec![2; 48])).is_none());
+    assert!(reassembler.push(test_fragment(2, 8190, false, vec![2; 16])).is_none());
+    assert_eq!(reassembler.dropped, 1);
+    assert_eq!(reassembler.buffered_bytes(), 0);
+}
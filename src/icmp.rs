@@ -1,11 +1,17 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::io;
+
 use nom::IResult;
-use nom::bytes;
+use nom::error::{Error, ErrorKind};
 use nom::number;
 
-use crate::ipv4::{Ipv4Address, Ipv4Header};
+use crate::error::IcmpTuntapError;
+use crate::ipv4::{Ipv4Address, Ipv4Header, Ipv4HeaderProtocol, Ipv4Packet};
 use crate::ipv4::parse_ipv4_header;
-use crate::util::Serialize;
-use crate::util::checksum_16;
+use crate::util::{Deserialize, Serialize};
+use crate::util::{checksum_16, checksum_valid};
+use crate::util::checksum_update;
 
 #[allow(dead_code)]
 #[repr(u8)]
@@ -13,6 +19,8 @@ use crate::util::checksum_16;
 pub enum IcmpType {
     EchoReply = 0u8,
     DestinationUnreachable = 3u8,
+    // deprecated by RFC 6633, but still seen from old routers
+    SourceQuench = 4u8,
     RedirectMessage = 5u8,
     EchoRequest = 8u8,
     RouterAdvertisement = 9u8,
@@ -21,6 +29,11 @@ pub enum IcmpType {
     BadIpHeader = 12u8,
     Timestamp = 13u8,
     TimestampReply = 14u8,
+    AddressMaskRequest = 17u8,
+    AddressMaskReply = 18u8,
+    // RFC 8335, probing the state of an interface
+    ExtendedEchoRequest = 42u8,
+    ExtendedEchoReply = 43u8,
     Unimplemented(u8),
 }
 
@@ -29,6 +42,7 @@ impl From<u8> for IcmpType {
         match orig {
             0 => IcmpType::EchoReply,
             3 => IcmpType::DestinationUnreachable,
+            4 => IcmpType::SourceQuench,
             5 => IcmpType::RedirectMessage,
             8 => IcmpType::EchoRequest,
             9 => IcmpType::RouterAdvertisement,
@@ -37,17 +51,56 @@ impl From<u8> for IcmpType {
             12 => IcmpType::BadIpHeader,
             13 => IcmpType::Timestamp,
             14 => IcmpType::TimestampReply,
+            17 => IcmpType::AddressMaskRequest,
+            18 => IcmpType::AddressMaskReply,
+            42 => IcmpType::ExtendedEchoRequest,
+            43 => IcmpType::ExtendedEchoReply,
             _ => IcmpType::Unimplemented(orig),
         }
     }
 }
 
-impl Into<u8> for IcmpType {
-    fn into(self) -> u8 {
-        match self {
+impl IcmpType {
+    // the messages reporting a problem with another datagram, which are
+    // never answered with an error themselves
+    pub fn is_error(&self) -> bool {
+        matches!(self, IcmpType::DestinationUnreachable | IcmpType::SourceQuench | IcmpType::RedirectMessage
+            | IcmpType::TimeExceeded | IcmpType::BadIpHeader)
+    }
+}
+
+// the name of the message type, as in RFC 792 and its successors
+impl fmt::Display for IcmpType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            IcmpType::EchoReply => "Echo Reply",
+            IcmpType::DestinationUnreachable => "Destination Unreachable",
+            IcmpType::SourceQuench => "Source Quench",
+            IcmpType::RedirectMessage => "Redirect",
+            IcmpType::EchoRequest => "Echo Request",
+            IcmpType::RouterAdvertisement => "Router Advertisement",
+            IcmpType::RouterSolicitation => "Router Solicitation",
+            IcmpType::TimeExceeded => "Time Exceeded",
+            IcmpType::BadIpHeader => "Parameter Problem",
+            IcmpType::Timestamp => "Timestamp",
+            IcmpType::TimestampReply => "Timestamp Reply",
+            IcmpType::AddressMaskRequest => "Address Mask Request",
+            IcmpType::AddressMaskReply => "Address Mask Reply",
+            IcmpType::ExtendedEchoRequest => "Extended Echo Request",
+            IcmpType::ExtendedEchoReply => "Extended Echo Reply",
+            IcmpType::Unimplemented(unknown) => return write!(f, "type {unknown}"),
+        };
+        write!(f, "{}", name)
+    }
+}
+
+impl From<IcmpType> for u8 {
+    fn from(orig: IcmpType) -> Self {
+        match orig {
             IcmpType::Unimplemented(unknown) => unknown,
             IcmpType::EchoReply => 0u8,
             IcmpType::DestinationUnreachable => 3u8,
+            IcmpType::SourceQuench => 4u8,
             IcmpType::RedirectMessage => 5u8,
             IcmpType::EchoRequest => 8u8,
             IcmpType::RouterAdvertisement => 9u8,
@@ -56,23 +109,35 @@ impl Into<u8> for IcmpType {
             IcmpType::BadIpHeader => 12u8,
             IcmpType::Timestamp => 13u8,
             IcmpType::TimestampReply => 14u8,
+            IcmpType::AddressMaskRequest => 17u8,
+            IcmpType::AddressMaskReply => 18u8,
+            IcmpType::ExtendedEchoRequest => 42u8,
+            IcmpType::ExtendedEchoReply => 43u8,
         }
     }
 }
 
 #[allow(dead_code)]
-#[derive(Debug)]
+#[derive(Debug, Clone, Eq, PartialEq)]
 pub enum IcmpHeaderData {
+    Echo {
+        id: u16,
+        seq: u16,
+    },
+
     Redirect {
-        ip_addr: Ipv4Address,
-        // ip header and first 8 bytes of the original datagram
+        // where the traffic for the original destination should go
+        gateway: Ipv4Address,
+        // ip header and (at least) the first 8 bytes of the original datagram
         ip_header: Ipv4Header,
-        data: [u8; 8],
+        data: Vec<u8>,
     },
 
     TimeExceeded {
         ip_header: Ipv4Header,
-        data: [u8; 8],
+        data: Vec<u8>,
+        // RFC 4884 extension structure following the original datagram
+        extension: Option<Vec<u8>>,
     },
 
     Timestamp {
@@ -94,26 +159,118 @@ pub enum IcmpHeaderData {
     DestinationUnreachable {
         next_hop_mtu: u16,
         ip_header: Ipv4Header,
+        data: Vec<u8>,
+        extension: Option<Vec<u8>>,
+    },
+
+    AddressMask {
+        id: u16,
+        seq: u16,
+        mask: Ipv4Address,
+    },
+
+    SourceQuench {
+        ip_header: Ipv4Header,
+        data: Vec<u8>,
+    },
+
+    ParameterProblem {
+        // offset of the offending octet in the original datagram
+        pointer: u8,
+        // the RFC 4884 length of the original datagram in 32-bit words,
+        // when an extension follows it; 0 otherwise
+        length: u8,
+        ip_header: Ipv4Header,
+        // anything quoted past these 8 bytes ends up in the packet data,
+        // the extension too
         data: [u8; 8],
     },
+
+    RouterAdvertisement {
+        // seconds the addresses may be considered valid
+        lifetime: u16,
+        // the size of each entry in 32-bit words, at least 2
+        entry_words: u8,
+        // (router address, preference level, the rest of the entry when
+        // it's bigger than these two words); at most 255 of them
+        entries: Vec<(Ipv4Address, u32, Vec<u8>)>,
+    },
+
+    // the request of RFC 8335; replies carry the state of the probed
+    // interface in place of the L-bit and are kept as `RestOfHeader`
+    ExtendedEcho {
+        id: u16,
+        // only 8 bits, unlike the plain echo
+        seq: u8,
+        // the probed interface is on the node receiving the request
+        local: bool,
+    },
+
+    // the 32 bits following the checksum of the types we don't give any
+    // structure to, kept as they are so they serialize back unchanged
+    RestOfHeader {
+        bytes: [u8; 4],
+    },
 }
 
-impl Serialize for IcmpHeaderData {
-    fn serialize(&self) -> Vec<u8> {
-        let mut s = Vec::new();
+// RFC 8335: the L-bit, the lowest of the byte following the sequence
+// number; the others are reserved
+const EXTENDED_ECHO_LOCAL: u8 = 0x01;
+
+// RFC 1256: each entry is a router address and a preference level
+const ROUTER_ADVERTISEMENT_ENTRY_WORDS: u8 = 2;
+
+// RFC 4884 4.1: an original datagram followed by an extension structure
+// is zero-padded to a 32-bit boundary, and to at least 128 bytes
+const EXTENSION_MIN_DATAGRAM_LEN: usize = 128;
+
+fn padded_datagram_len(datagram_len: usize, extension: &Option<Vec<u8>>) -> usize {
+    match extension {
+        Some(_) => datagram_len.next_multiple_of(4).max(EXTENSION_MIN_DATAGRAM_LEN),
+        None => datagram_len,
+    }
+}
+
+// Writes the RFC 4884 length field, the size of the padded datagram in
+// 32-bit words (only set when an extension follows it), and then the
+// datagram, its padding and the extension. Being a single byte, the
+// length doesn't go past 1020 bytes of datagram.
+fn serialize_quoted_datagram(s: &mut Vec<u8>, rest_of_header: [u8; 2], ip_header: &Ipv4Header, data: &[u8], extension: &Option<Vec<u8>>) {
+    let datagram_len = ip_header.serialized_len() + data.len();
+    let padded_len = padded_datagram_len(datagram_len, extension);
+    let length = match extension {
+        Some(_) => u8::try_from(padded_len / 4).expect("original datagram of over 1020 bytes before an extension"),
+        None => 0,
+    };
+    s.push(0);
+    s.push(length);
+    s.extend(rest_of_header);
+    ip_header.serialize_into(s);
+    s.extend(data);
+    s.extend(std::iter::repeat_n(0u8, padded_len - datagram_len));
+    if let Some(extension) = extension {
+        s.extend(extension);
+    }
+}
 
+impl Serialize for IcmpHeaderData {
+    fn serialize_into(&self, s: &mut Vec<u8>) {
         match self {
-            IcmpHeaderData::Redirect { ip_addr, ip_header, data } => {
-                s.extend(&ip_addr.0.to_be_bytes());
-                s.extend(&ip_header.serialize());
-                s.extend(data);
+            IcmpHeaderData::Echo { id, seq } => {
+                s.extend(id.to_be_bytes());
+                s.extend(seq.to_be_bytes());
             },
 
-            IcmpHeaderData::TimeExceeded { ip_header, data } => {
-                s.extend(&ip_header.serialize());
+            IcmpHeaderData::Redirect { gateway, ip_header, data } => {
+                s.extend(&gateway.0.to_be_bytes());
+                ip_header.serialize_into(s);
                 s.extend(data);
             },
 
+            IcmpHeaderData::TimeExceeded { ip_header, data, extension } => {
+                serialize_quoted_datagram(s, [0u8; 2], ip_header, data, extension);
+            },
+
             IcmpHeaderData::Timestamp {
                 id, seq, originate, receive, transmit
             } => {
@@ -135,20 +292,77 @@ impl Serialize for IcmpHeaderData {
             },
 
             IcmpHeaderData::DestinationUnreachable {
-                next_hop_mtu, ip_header, data
+                next_hop_mtu, ip_header, data, extension
             } => {
-                s.extend(next_hop_mtu.to_be_bytes());
-                s.extend(ip_header.serialize());
+                serialize_quoted_datagram(s, next_hop_mtu.to_be_bytes(), ip_header, data, extension);
+            },
+
+            IcmpHeaderData::AddressMask { id, seq, mask } => {
+                s.extend(id.to_be_bytes());
+                s.extend(seq.to_be_bytes());
+                s.extend(mask.0.to_be_bytes());
+            },
+
+            IcmpHeaderData::SourceQuench { ip_header, data } => {
+                s.extend([0u8; 4]);
+                ip_header.serialize_into(s);
                 s.extend(data);
-            }
+            },
+
+            IcmpHeaderData::ParameterProblem { pointer, length, ip_header, data } => {
+                s.push(*pointer);
+                s.push(*length);
+                s.extend([0u8; 2]);
+                ip_header.serialize_into(s);
+                s.extend(data);
+            },
+
+            IcmpHeaderData::RouterAdvertisement { lifetime, entry_words, entries } => {
+                // the counts are single bytes
+                let num_addrs = u8::try_from(entries.len()).expect("more than 255 Router Advertisement entries");
+                assert!(*entry_words >= ROUTER_ADVERTISEMENT_ENTRY_WORDS, "Router Advertisement entries of {entry_words} words");
+                s.push(num_addrs);
+                s.push(*entry_words);
+                s.extend(lifetime.to_be_bytes());
+                // the rest is cut or zero-padded to the entry size
+                let extra_len = (*entry_words - ROUTER_ADVERTISEMENT_ENTRY_WORDS) as usize * 4;
+                for (address, preference, extra) in entries {
+                    s.extend(address.0.to_be_bytes());
+                    s.extend(preference.to_be_bytes());
+                    s.extend(extra.iter().copied().chain(std::iter::repeat(0)).take(extra_len));
+                }
+            },
+
+            IcmpHeaderData::ExtendedEcho { id, seq, local } => {
+                s.extend(id.to_be_bytes());
+                s.push(*seq);
+                s.push(if *local { EXTENDED_ECHO_LOCAL } else { 0 });
+            },
+
+            IcmpHeaderData::RestOfHeader { bytes } => s.extend(bytes),
         }
+    }
 
-        return s;
+    fn serialized_len(&self) -> usize {
+        match self {
+            IcmpHeaderData::Echo { .. } | IcmpHeaderData::ExtendedEcho { .. }
+            | IcmpHeaderData::RestOfHeader { .. } => 4,
+            IcmpHeaderData::Timestamp { .. } | IcmpHeaderData::TimestampReply { .. } => 16,
+            IcmpHeaderData::AddressMask { .. } => 8,
+            IcmpHeaderData::Redirect { ip_header, data, .. }
+            | IcmpHeaderData::SourceQuench { ip_header, data } => 4 + ip_header.serialized_len() + data.len(),
+            IcmpHeaderData::ParameterProblem { ip_header, data, .. } => 4 + ip_header.serialized_len() + data.len(),
+            IcmpHeaderData::TimeExceeded { ip_header, data, extension }
+            | IcmpHeaderData::DestinationUnreachable { ip_header, data, extension, .. } => {
+                4 + padded_datagram_len(ip_header.serialized_len() + data.len(), extension) + extension.as_ref().map_or(0, Vec::len)
+            },
+            IcmpHeaderData::RouterAdvertisement { entry_words, entries, .. } => 4 + *entry_words as usize * 4 * entries.len(),
+        }
     }
 }
 
 #[allow(dead_code)]
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct IcmpHeader {
     pub icmp_type: IcmpType,
     pub code: u8,
@@ -157,15 +371,17 @@ pub struct IcmpHeader {
 }
 
 impl Serialize for IcmpHeader {
-    fn serialize(&self) -> Vec<u8> {
-        let mut s: Vec<u8> = Vec::new();
+    fn serialize_into(&self, s: &mut Vec<u8>) {
         s.push(self.icmp_type.into());
         s.push(self.code);
         s.extend(self.checksum.to_be_bytes());
         if let Some(data) = &self.data {
-            s.extend(data.serialize());
+            data.serialize_into(s);
         }
-        return s;
+    }
+
+    fn serialized_len(&self) -> usize {
+        4 + self.data.as_ref().map_or(0, Serialize::serialized_len)
     }
 }
 
@@ -177,136 +393,439 @@ pub struct IcmpPacket {
 }
 
 impl Serialize for IcmpPacket {
-    fn serialize(&self) -> Vec<u8> {
-        let mut s = Vec::new();
-        s.extend(self.header.serialize());
+    fn serialize_into(&self, s: &mut Vec<u8>) {
+        self.header.serialize_into(s);
         s.extend(&self.data);
-        return s;
+    }
+
+    fn serialized_len(&self) -> usize {
+        self.header.serialized_len() + self.data.len()
+    }
+
+    fn serialize_to<W: io::Write>(&self, w: &mut W) -> io::Result<()> {
+        self.header.serialize_to(w)?;
+        w.write_all(&self.data)
     }
 }
 
-#[allow(dead_code)]
-impl IcmpPacket {
-    pub fn description(&self) -> &'static str {
-        let icmp_type = self.header.icmp_type;
-        let code = self.header.code;
-        match icmp_type {
-            IcmpType::EchoReply => {
-                match code {
-                    0 => "Echo reply",
-                    _ => "",
-                }
-            },
+fn description(icmp_type: IcmpType, code: u8) -> &'static str {
+    match icmp_type {
+        IcmpType::EchoReply => {
+            match code {
+                0 => "Echo reply",
+                _ => "",
+            }
+        },
 
-            IcmpType::DestinationUnreachable => {
-                match code {
-                    0 => "Destination network unreachable",
-                    1 => "Destination host unreachable",
-                    2 => "Destination protocol unreachable",
-                    3 => "Destination port unreachable",
-                    4 => "Fragmentation required",
-                    5 => "Source route failed",
-                    6 => "Destination network unknown",
-                    7 => "Destination host unknown",
-                    8 => "Source host isolated",
-                    9 => "Network administratively prohibited",
-                    10 => "Host administratively prohibited",
-                    11 => "Network unreachable for ToS",
-                    12 => "Host unreachable for ToS",
-                    13 => "Communication administratively prohibited",
-                    14 => "Host precedence violation",
-                    15 => "Precedence cutoff in effect",
-                    _ => "",
-                }
+        IcmpType::DestinationUnreachable => {
+            match code {
+                0 => "Destination network unreachable",
+                1 => "Destination host unreachable",
+                2 => "Destination protocol unreachable",
+                3 => "Destination port unreachable",
+                4 => "Fragmentation required",
+                5 => "Source route failed",
+                6 => "Destination network unknown",
+                7 => "Destination host unknown",
+                8 => "Source host isolated",
+                9 => "Network administratively prohibited",
+                10 => "Host administratively prohibited",
+                11 => "Network unreachable for ToS",
+                12 => "Host unreachable for ToS",
+                13 => "Communication administratively prohibited",
+                14 => "Host precedence violation",
+                15 => "Precedence cutoff in effect",
+                _ => "",
             }
+        }
 
-            IcmpType::RedirectMessage => {
-                match code {
-                    0 => "Redirect Datagram for the Network",
-                    1 => "Redirect Datagram for the Host",
-                    2 => "Redirect Datagram for the ToS and Network",
-                    3 => "Redirect Datagram for the ToS and Host",
-                    _ => "",
-                }
-            },
+        IcmpType::SourceQuench => {
+            match code {
+                0 => "Source quench",
+                _ => "",
+            }
+        },
 
-            IcmpType::EchoRequest => {
-                match code {
-                    0 => "Echo request",
-                    _ => "",
-                }
-            },
-            
-            IcmpType::RouterAdvertisement => {
-                match code {
-                    0 => "Router Advertisement",
-                    _ => "",
-                }
-            },
-            
-            IcmpType::RouterSolicitation => {
-                match code {
-                    0 => "Router Solicitation",
-                    _ => "",
-                }
-            },
+        IcmpType::RedirectMessage => {
+            match code {
+                0 => "Redirect Datagram for the Network",
+                1 => "Redirect Datagram for the Host",
+                2 => "Redirect Datagram for the ToS and Network",
+                3 => "Redirect Datagram for the ToS and Host",
+                _ => "",
+            }
+        },
 
-            IcmpType::TimeExceeded => {
-                match code {
-                    0 => "TTL expired in transit",
-                    1 => "Fragment reassembly time exceeded",
-                    _ => "",
-                }
-            },
+        IcmpType::EchoRequest => {
+            match code {
+                0 => "Echo request",
+                _ => "",
+            }
+        },
+        
+        IcmpType::RouterAdvertisement => {
+            match code {
+                0 => "Router Advertisement",
+                _ => "",
+            }
+        },
+        
+        IcmpType::RouterSolicitation => {
+            match code {
+                0 => "Router Solicitation",
+                _ => "",
+            }
+        },
 
-            IcmpType::BadIpHeader => {
-                match code {
-                    0 => "Pointer indicates the error",
-                    1 => "Missing a required option",
-                    2 => "Bad length",
-                    _ => "",
-                }
-            },
+        IcmpType::TimeExceeded => {
+            match code {
+                0 => "TTL expired in transit",
+                1 => "Fragment reassembly time exceeded",
+                _ => "",
+            }
+        },
 
-            IcmpType::Timestamp => {
-                match code {
-                    0 => "Timestamp",
-                    _ => "",
-                }
-            },
+        IcmpType::BadIpHeader => {
+            match code {
+                0 => "Pointer indicates the error",
+                1 => "Missing a required option",
+                2 => "Bad length",
+                _ => "",
+            }
+        },
 
-            IcmpType::TimestampReply => {
-                match code {
-                    0 => "Timestamp reply",
-                    _ => "",
-                }
-            },
+        IcmpType::Timestamp => {
+            match code {
+                0 => "Timestamp",
+                _ => "",
+            }
+        },
 
-            IcmpType::Unimplemented(_) => "Unimplemented",
-        }
+        IcmpType::TimestampReply => {
+            match code {
+                0 => "Timestamp reply",
+                _ => "",
+            }
+        },
+
+        IcmpType::AddressMaskRequest => {
+            match code {
+                0 => "Address mask request",
+                _ => "",
+            }
+        },
+
+        IcmpType::AddressMaskReply => {
+            match code {
+                0 => "Address mask reply",
+                _ => "",
+            }
+        },
+
+        IcmpType::ExtendedEchoRequest => {
+            match code {
+                0 => "Extended echo request",
+                _ => "",
+            }
+        },
+
+        IcmpType::ExtendedEchoReply => {
+            match code {
+                0 => "Extended echo reply",
+                1 => "Malformed query",
+                2 => "No such interface",
+                3 => "No such table entry",
+                4 => "Multiple interfaces satisfy query",
+                _ => "",
+            }
+        },
+
+        IcmpType::Unimplemented(_) => "Unimplemented",
+    }
+}
+
+// One line for logging every packet, e.g.
+// `EchoRequest/0 "Echo request" id=3 seq=4 payload=48B`
+fn summary(header: &IcmpHeader, payload_len: usize) -> String {
+    let mut summary = format!("{:?}/{} \"{}\"", header.icmp_type, header.code, description(header.icmp_type, header.code));
+    if let Some(IcmpHeaderData::Echo { id, seq }) = header.data {
+        summary.push_str(&format!(" id={id} seq={seq}"));
+    }
+    summary.push_str(&format!(" payload={payload_len}B"));
+    summary
+}
+
+impl fmt::Display for IcmpPacket {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "ICMP {:?} ({}) code={} len={}",
+            self.header.icmp_type,
+            self.description(),
+            self.header.code,
+            self.data.len())
+    }
+}
+
+#[allow(dead_code)]
+impl IcmpPacket {
+    pub fn description(&self) -> &'static str {
+        description(self.header.icmp_type, self.header.code)
+    }
+
+    pub fn summary(&self) -> String {
+        summary(&self.header, self.data.len())
     }
 
+    // the checksum covers the whole message, including any structured
+    // header data (e.g. the quoted datagram in error messages)
     pub fn update_checksum(&mut self) {
         self.header.checksum = 0;
         let bytes = self.serialize();
         self.header.checksum = checksum_16(&bytes);
     }
+
+    // parses a packet, verifying its checksum
+    pub fn parse(input: &[u8]) -> Result<IcmpPacket, IcmpTuntapError> {
+        let (_, packet) = parse_icmp_packet(input)?;
+        Ok(packet)
+    }
+
+    // parses the ICMP message carried by an IPv4 packet
+    pub fn from_ipv4(packet: &Ipv4Packet) -> Result<IcmpPacket, IcmpTuntapError> {
+        if packet.header.protocol != Ipv4HeaderProtocol::Icmp {
+            return Err(IcmpTuntapError::UnsupportedProtocol);
+        }
+        IcmpPacket::parse(&packet.data)
+    }
+
+    pub fn echo_request(id: u16, seq: u16, payload: &[u8]) -> IcmpPacket {
+        let mut request = IcmpPacket {
+            header: IcmpHeader {
+                checksum: 0,
+                icmp_type: IcmpType::EchoRequest,
+                code: 0,
+                data: Some(IcmpHeaderData::Echo { id, seq }),
+            },
+            data: Vec::from(payload),
+        };
+        request.update_checksum();
+        request
+    }
+
+    pub fn echo_id(&self) -> Option<u16> {
+        match self.header.data {
+            Some(IcmpHeaderData::Echo { id, .. }) => Some(id),
+            _ => None,
+        }
+    }
+
+    pub fn echo_seq(&self) -> Option<u16> {
+        match self.header.data {
+            Some(IcmpHeaderData::Echo { seq, .. }) => Some(seq),
+            _ => None,
+        }
+    }
+
+    // one line JSON object, built by hand since we don't pull in serde;
+    // none of the values need escaping
+    pub fn to_json(&self) -> String {
+        let number = |value: Option<u16>| value.map_or(String::from("null"), |value| value.to_string());
+        format!("{{\"type\":\"{:?}\",\"code\":{},\"id\":{},\"seq\":{},\"checksum_valid\":{}}}",
+            self.header.icmp_type,
+            self.header.code,
+            number(self.echo_id()),
+            number(self.echo_seq()),
+            checksum_valid(&self.serialize()))
+    }
+
+    // rewrites the echo identifier, patching the checksum incrementally
+    pub fn set_echo_id(&mut self, new_id: u16) {
+        if let Some(IcmpHeaderData::Echo { id, .. }) = &mut self.header.data {
+            self.header.checksum = checksum_update(self.header.checksum, *id, new_id);
+            *id = new_id;
+        }
+    }
+
+    pub fn echo_reply(&self) -> IcmpPacket {
+        self.echo_reply_with(|_| {})
+    }
+
+    // builds the Echo Reply for this Echo Request, letting `transform`
+    // rewrite the echoed payload before the checksum is computed
+    pub fn echo_reply_with<F: FnOnce(&mut [u8])>(&self, transform: F) -> IcmpPacket {
+        build_echo_reply(&self.header, &self.data, transform)
+    }
+}
+
+fn build_echo_reply<F: FnOnce(&mut [u8])>(request: &IcmpHeader, payload: &[u8], transform: F) -> IcmpPacket {
+    let mut data = payload.to_vec();
+    transform(&mut data);
+
+    let header_data = match request.data {
+        Some(IcmpHeaderData::Echo { id, seq }) => Some(IcmpHeaderData::Echo { id, seq }),
+        _ => None,
+    };
+
+    let mut reply = IcmpPacket {
+        header: IcmpHeader {
+            checksum: 0,
+            icmp_type: IcmpType::EchoReply,
+            code: 0,
+            data: header_data,
+        },
+        data,
+    };
+    reply.update_checksum();
+    reply
+}
+
+// What goes into the payload of our echo replies, to see how a peer
+// copes with one that doesn't match its request
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq)]
+pub enum EchoPayload {
+    // the payload of the request, as RFC 792 asks
+    #[default]
+    Mirror,
+    Zero,
+    Reverse,
+    // every byte XORed with 0xFF
+    Invert,
+}
+
+impl EchoPayload {
+    // a transform for `echo_reply_with`
+    pub fn apply(&self, payload: &mut [u8]) {
+        match self {
+            EchoPayload::Mirror => (),
+            EchoPayload::Zero => payload.fill(0),
+            EchoPayload::Reverse => payload.reverse(),
+            EchoPayload::Invert => payload.iter_mut().for_each(|b| *b ^= 0xFF),
+        }
+    }
+}
+
+// Borrowing counterpart of `IcmpPacket`, see `Ipv4PacketRef`
+#[derive(Debug)]
+pub struct IcmpPacketRef<'a> {
+    pub header: IcmpHeader,
+    pub data: &'a [u8],
+}
+
+#[allow(dead_code)]
+impl IcmpPacketRef<'_> {
+    pub fn to_owned(&self) -> IcmpPacket {
+        IcmpPacket {
+            header: self.header.clone(),
+            data: self.data.to_vec(),
+        }
+    }
+
+    pub fn echo_reply(&self) -> IcmpPacket {
+        self.echo_reply_with(|_| {})
+    }
+
+    pub fn echo_reply_with<F: FnOnce(&mut [u8])>(&self, transform: F) -> IcmpPacket {
+        build_echo_reply(&self.header, self.data, transform)
+    }
+
+    pub fn summary(&self) -> String {
+        summary(&self.header, self.data.len())
+    }
+}
+
+impl fmt::Display for IcmpPacketRef<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "ICMP {:?} ({}) code={} len={}",
+            self.header.icmp_type,
+            description(self.header.icmp_type, self.header.code),
+            self.header.code,
+            self.data.len())
+    }
+}
+
+impl Serialize for IcmpPacketRef<'_> {
+    fn serialize_into(&self, s: &mut Vec<u8>) {
+        self.header.serialize_into(s);
+        s.extend(self.data);
+    }
+
+    fn serialized_len(&self) -> usize {
+        self.header.serialized_len() + self.data.len()
+    }
+
+    fn serialize_to<W: io::Write>(&self, w: &mut W) -> io::Result<()> {
+        self.header.serialize_to(w)?;
+        w.write_all(self.data)
+    }
 }
 
-fn parse_ip_header_and_data(input: &[u8]) -> IResult<&[u8], (Ipv4Header, [u8; 8])> {
-    let (input, header) = parse_ipv4_header(input)?;
-    let (input, data) = bytes::complete::take(8u8)(input)?;
-    // SAFETY: unwrapping here is safe because if we
-    // can't take 8 bytes from the input above, we'll error
-    // before reaching the return
-    Ok((input, (header, data.try_into().unwrap())))
+// The echo identifier plays the role of a port number for ICMP, so a
+// NAT-like box has to map it when translating queries; this rewrites
+// the identifier of echo messages according to a fixed table.
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+pub struct EchoIdRewriter {
+    map: HashMap<u16, u16>,
 }
 
+impl EchoIdRewriter {
+    pub fn new() -> EchoIdRewriter {
+        EchoIdRewriter::default()
+    }
+
+    pub fn insert(&mut self, from: u16, to: u16) {
+        self.map.insert(from, to);
+    }
+
+    // returns whether the packet was rewritten
+    pub fn rewrite(&self, packet: &mut IcmpPacket) -> bool {
+        let new_id = match packet.echo_id().and_then(|id| self.map.get(&id)) {
+            Some(&new_id) => new_id,
+            None => return false,
+        };
+        packet.set_echo_id(new_id);
+        true
+    }
+}
+
+// the payload bytes of the original datagram every ICMP error quotes
+const QUOTED_DATA_LEN: usize = 8;
+
+// RFC 792 only asks for the first 8 bytes of the original datagram, but
+// RFC 1812/4884 hosts quote as much as fits, so take whatever is left.
+// Less than 8 bytes is only fine when the original was that short (as
+// its header says), anything else is a truncated quote.
+fn parse_ip_header_and_data(input: &[u8]) -> IResult<&[u8], (Ipv4Header, Vec<u8>)> {
+    let (rest, header) = parse_ipv4_header(input)?;
+    let header_len = input.len() - rest.len();
+    let original_data_len = (header.total_length as usize).saturating_sub(header_len);
+    if rest.len() < QUOTED_DATA_LEN.min(original_data_len) {
+        return Err(nom::Err::Error(Error::new(rest, ErrorKind::Eof)));
+    }
+    let (rest, data) = nom::combinator::rest(rest)?;
+    Ok((rest, (header, Vec::from(data))))
+}
+
+fn parse_echo_data(input: &[u8]) -> IResult<&[u8], IcmpHeaderData> {
+    let (input, id) = number::complete::be_u16(input)?;
+    let (input, seq) = number::complete::be_u16(input)?;
+    Ok((input, IcmpHeaderData::Echo { id, seq }))
+}
+
+fn parse_extended_echo_data(input: &[u8]) -> IResult<&[u8], IcmpHeaderData> {
+    let (input, id) = number::complete::be_u16(input)?;
+    let (input, seq) = number::complete::be_u8(input)?;
+    let (input, flags) = number::complete::be_u8(input)?;
+    let local = flags & EXTENDED_ECHO_LOCAL != 0;
+    Ok((input, IcmpHeaderData::ExtendedEcho { id, seq, local }))
+}
+
+// a redirect changes where the host sends its traffic, so the quoted
+// datagram has to be an IPv4 header we can make sense of, with the
+// 8 bytes following it
 fn parse_redirect_data(input: &[u8]) -> IResult<&[u8], IcmpHeaderData> {
-    let (input, ip_addr) = number::complete::be_u32(input)?;
+    let (input, gateway) = number::complete::be_u32(input)?;
     let (input, (ip_header, data)) = parse_ip_header_and_data(input)?;
     let data = IcmpHeaderData::Redirect {
-        ip_addr: Ipv4Address(ip_addr),
+        gateway: Ipv4Address(gateway),
         ip_header,
         data,
     };
@@ -314,9 +833,35 @@ fn parse_redirect_data(input: &[u8]) -> IResult<&[u8], IcmpHeaderData> {
     Ok((input, data))
 }
 
-fn parse_time_exceeded_data(input: &[u8]) -> IResult<&[u8], IcmpHeaderData> {
+// (ip header, data, extension)
+type QuotedDatagram = (Ipv4Header, Vec<u8>, Option<Vec<u8>>);
+
+// Parses the original datagram of an error message that may carry an
+// RFC 4884 extension. `length` is the datagram length in 32-bit words
+// taken from the header. There is only an extension when bytes follow
+// that many words and they make the 128 bytes RFC 4884 requires;
+// otherwise the datagram takes up the rest of the message.
+fn parse_ip_header_data_and_extension(input: &[u8], length: u8)
+    -> IResult<&[u8], QuotedDatagram> {
+    let datagram_len = length as usize * 4;
+    if datagram_len >= EXTENSION_MIN_DATAGRAM_LEN && datagram_len < input.len() {
+        let (datagram, extension) = input.split_at(datagram_len);
+        if let Ok((_, (ip_header, data))) = parse_ip_header_and_data(datagram) {
+            return Ok((&[], (ip_header, data, Some(Vec::from(extension)))));
+        }
+    }
+
     let (input, (ip_header, data)) = parse_ip_header_and_data(input)?;
-    let data = IcmpHeaderData::TimeExceeded { ip_header, data };
+    Ok((input, (ip_header, data, None)))
+}
+
+fn parse_time_exceeded_data(input: &[u8]) -> IResult<&[u8], IcmpHeaderData> {
+    // unused, length, unused
+    let (input, _) = number::complete::be_u8(input)?;
+    let (input, length) = number::complete::be_u8(input)?;
+    let (input, _) = number::complete::be_u16(input)?;
+    let (input, (ip_header, data, extension)) = parse_ip_header_data_and_extension(input, length)?;
+    let data = IcmpHeaderData::TimeExceeded { ip_header, data, extension };
     Ok((input, data))
 }
 
@@ -341,14 +886,72 @@ fn parse_timestamp_reply(input: &[u8]) -> IResult<&[u8], IcmpHeaderData> {
     Ok((input, data))
 }
 
-fn parse_destination_unreachable(input: &[u8]) -> IResult<&[u8], IcmpHeaderData> {
-    let (input, _) = number::complete::be_u16(input)?;
+pub const DEST_UNREACHABLE_FRAGMENTATION_NEEDED: u8 = 4;
+
+fn parse_destination_unreachable(input: &[u8], code: u8) -> IResult<&[u8], IcmpHeaderData> {
+    // unused, length, next-hop MTU
+    let (input, _) = number::complete::be_u8(input)?;
+    let (input, length) = number::complete::be_u8(input)?;
     let (input, next_hop_mtu) = number::complete::be_u16(input)?;
+    // RFC 1191: only "fragmentation needed" carries an MTU, for the other
+    // codes these bytes are unused and whatever is in them means nothing
+    let next_hop_mtu = if code == DEST_UNREACHABLE_FRAGMENTATION_NEEDED { next_hop_mtu } else { 0 };
+    let (input, (ip_header, data, extension)) = parse_ip_header_data_and_extension(input, length)?;
+    let data = IcmpHeaderData::DestinationUnreachable { next_hop_mtu, ip_header, data, extension };
+    Ok((input, data))
+}
+
+fn parse_address_mask(input: &[u8]) -> IResult<&[u8], IcmpHeaderData> {
+    let (input, id) = number::complete::be_u16(input)?;
+    let (input, seq) = number::complete::be_u16(input)?;
+    let (input, mask) = number::complete::be_u32(input)?;
+    let data = IcmpHeaderData::AddressMask { id, seq, mask: Ipv4Address(mask) };
+    Ok((input, data))
+}
+
+fn parse_source_quench(input: &[u8]) -> IResult<&[u8], IcmpHeaderData> {
+    let (input, _unused) = number::complete::be_u32(input)?;
     let (input, (ip_header, data)) = parse_ip_header_and_data(input)?;
-    let data = IcmpHeaderData::DestinationUnreachable { next_hop_mtu, ip_header, data };
+    Ok((input, IcmpHeaderData::SourceQuench { ip_header, data }))
+}
+
+fn parse_parameter_problem(input: &[u8]) -> IResult<&[u8], IcmpHeaderData> {
+    // pointer, length, unused
+    let (input, pointer) = number::complete::be_u8(input)?;
+    let (input, length) = number::complete::be_u8(input)?;
+    let (input, _) = number::complete::be_u16(input)?;
+    let (input, ip_header) = parse_ipv4_header(input)?;
+    let (input, data) = nom::bytes::complete::take(8usize)(input)?;
+    let data = IcmpHeaderData::ParameterProblem {
+        pointer,
+        length,
+        ip_header,
+        data: data.try_into().unwrap(),
+    };
     Ok((input, data))
 }
 
+fn parse_router_advertisement(input: &[u8]) -> IResult<&[u8], IcmpHeaderData> {
+    let (input, num_addrs) = number::complete::be_u8(input)?;
+    let (input, entry_words) = number::complete::be_u8(input)?;
+    let (mut input, lifetime) = number::complete::be_u16(input)?;
+    if entry_words < ROUTER_ADVERTISEMENT_ENTRY_WORDS {
+        return Err(nom::Err::Error(Error::new(input, ErrorKind::LengthValue)));
+    }
+
+    let mut entries = Vec::with_capacity(num_addrs as usize);
+    for _ in 0..num_addrs {
+        let (rest, entry) = nom::bytes::complete::take(entry_words as usize * 4)(input)?;
+        let (entry, address) = number::complete::be_u32(entry)?;
+        // bigger entries may carry more fields after these two
+        let (extra, preference) = number::complete::be_u32(entry)?;
+        entries.push((Ipv4Address(address), preference, extra.to_vec()));
+        input = rest;
+    }
+
+    Ok((input, IcmpHeaderData::RouterAdvertisement { lifetime, entry_words, entries }))
+}
+
 fn parse_icmp_header_type_code_and_checksum(input: &[u8])
     -> IResult<&[u8], (IcmpType, u8, u16)> {
     let (input, icmp_type) = number::complete::be_u8(input)?;
@@ -358,8 +961,13 @@ fn parse_icmp_header_type_code_and_checksum(input: &[u8])
     Ok((input, (IcmpType::from(icmp_type), code, checksum)))
 } 
 
-fn parse_icmp_header_data<'a>(input: &[u8], icmp_type: IcmpType) -> IResult<&[u8], Option<IcmpHeaderData>> {
+fn parse_icmp_header_data(input: &[u8], icmp_type: IcmpType, code: u8) -> IResult<&[u8], Option<IcmpHeaderData>> {
     let (input, data) = match icmp_type {
+        IcmpType::EchoRequest | IcmpType::EchoReply => {
+            let (input, data) = parse_echo_data(input)?;
+            (input, Some(data))
+        },
+
         IcmpType::RedirectMessage => {
             let (input, data) = parse_redirect_data(input)?;
             (input, Some(data))
@@ -381,26 +989,89 @@ fn parse_icmp_header_data<'a>(input: &[u8], icmp_type: IcmpType) -> IResult<&[u8
         }
 
         IcmpType::DestinationUnreachable => {
-            let (input, data) = parse_destination_unreachable(input)?;
+            let (input, data) = parse_destination_unreachable(input, code)?;
             (input, Some(data))
         }
 
-        _ => (input, None), 
+        IcmpType::AddressMaskRequest | IcmpType::AddressMaskReply => {
+            let (input, data) = parse_address_mask(input)?;
+            (input, Some(data))
+        }
+
+        IcmpType::SourceQuench => {
+            let (input, data) = parse_source_quench(input)?;
+            (input, Some(data))
+        }
+
+        IcmpType::BadIpHeader => {
+            let (input, data) = parse_parameter_problem(input)?;
+            (input, Some(data))
+        }
+
+        IcmpType::RouterAdvertisement => {
+            let (input, data) = parse_router_advertisement(input)?;
+            (input, Some(data))
+        }
+
+        IcmpType::ExtendedEchoRequest => {
+            let (input, data) = parse_extended_echo_data(input)?;
+            (input, Some(data))
+        }
+
+        IcmpType::RouterSolicitation | IcmpType::ExtendedEchoReply | IcmpType::Unimplemented(_) => {
+            let (input, bytes) = nom::bytes::complete::take(4usize)(input)?;
+            let data = IcmpHeaderData::RestOfHeader { bytes: bytes.try_into().unwrap() };
+            (input, Some(data))
+        }
     };
 
     Ok((input, data))
 }
 
-pub fn parse_icmp_packet(input: &[u8]) -> IResult<&[u8], IcmpPacket> {
+pub fn parse_icmp_header(input: &[u8]) -> IResult<&[u8], IcmpHeader> {
     let (input, (icmp_type, code, checksum)) = parse_icmp_header_type_code_and_checksum(input)?;
-    let (input, header_data) = parse_icmp_header_data(input, icmp_type)?;
-    let header = IcmpHeader { icmp_type, code, checksum, data: header_data };
+    let (input, data) = parse_icmp_header_data(input, icmp_type, code)?;
+    Ok((input, IcmpHeader { icmp_type, code, checksum, data }))
+}
+
+impl Deserialize for IcmpHeader {
+    fn deserialize(input: &[u8]) -> IResult<&[u8], Self> {
+        parse_icmp_header(input)
+    }
+}
+
+impl Deserialize for IcmpPacket {
+    fn deserialize(input: &[u8]) -> IResult<&[u8], Self> {
+        parse_icmp_packet(input)
+    }
+}
+
+// Parses an ICMP packet, failing with `ErrorKind::Verify` if the
+// checksum doesn't match the contents
+pub fn parse_icmp_packet(input: &[u8]) -> IResult<&[u8], IcmpPacket> {
+    let (rest, packet) = parse_icmp_packet_ref(input)?;
+    Ok((rest, packet.to_owned()))
+}
+
+// Same as `parse_icmp_packet`, but trusts the checksum that came off the wire
+pub fn parse_icmp_packet_unchecked(input: &[u8]) -> IResult<&[u8], IcmpPacket> {
+    let (rest, packet) = parse_icmp_packet_ref_unchecked(input)?;
+    Ok((rest, packet.to_owned()))
+}
+
+pub fn parse_icmp_packet_ref(input: &[u8]) -> IResult<&[u8], IcmpPacketRef<'_>> {
+    let (rest, packet) = parse_icmp_packet_ref_unchecked(input)?;
+    // the checksum field included, a valid message sums to zero
+    if !checksum_valid(input) {
+        return Err(nom::Err::Failure(Error::new(input, ErrorKind::Verify)));
+    }
+    Ok((rest, packet))
+}
+
+pub fn parse_icmp_packet_ref_unchecked(input: &[u8]) -> IResult<&[u8], IcmpPacketRef<'_>> {
+    let (input, header) = parse_icmp_header(input)?;
     let (input, data) = nom::combinator::rest(input)?;
-    let packet = IcmpPacket {
-        header,
-        data: Vec::from(data),
-    };
-    Ok((input, packet))
+    Ok((input, IcmpPacketRef { header, data }))
 }
 
 #[test]
@@ -412,8 +1083,17 @@ fn test_icmp_packet_serialization() {
         // data (from the `ping` command on linux)
         0, 3, 0, 4, 86, 1, 157, 100, 0, 0, 0, 0, 227, 243, 9, 0, 0, 0, 0, 0, 16, 17, 18, 19, 20, 21, 22, 23, 24, 25, 26, 27, 28, 29, 30, 31, 32, 33, 34, 35, 36, 37, 38, 39, 40, 41, 42, 43, 44, 45, 46, 47, 48, 49, 50
     ];
-    let (_, packet) = parse_icmp_packet(&bytes).unwrap();
+    // the sample is truncated, so its checksum doesn't match anymore
+    let (_, packet) = parse_icmp_packet_unchecked(&bytes).unwrap();
+    assert_eq!(packet.header.data, Some(IcmpHeaderData::Echo { id: 3, seq: 4 }));
     assert_eq!(bytes, packet.serialize().as_slice());
+
+    let mut buf = vec![1, 2, 3];
+    packet.serialize_into(&mut buf);
+    packet.header.serialize_into(&mut buf);
+    assert_eq!(&buf[..3], &[1, 2, 3]);
+    assert_eq!(&buf[3..3 + bytes.len()], &bytes);
+    assert_eq!(&buf[3 + bytes.len()..], &bytes[..8]);
 }
 
 
@@ -431,3 +1111,643 @@ fn test_icmp_packet_checksum() {
     packet.update_checksum();
     assert_eq!(checksum, packet.header.checksum);
 }
+
+#[test]
+fn test_icmp_echo_reply_transform() {
+    let bytes = [
+        8, 0, 80, 124, 0, 12, 0, 3, 237, 89, 158, 100, 0, 0, 0, 0, 91, 227, 1, 0, 0, 0, 0, 0, 16, 17, 18, 19, 20, 21, 22, 23, 24, 25, 26, 27, 28, 29, 30, 31, 32, 33, 34, 35, 36, 37, 38, 39, 40, 41, 42, 43, 44, 45, 46, 47, 48, 49, 50, 51, 52, 53, 54, 55
+    ];
+
+    let (_, request) = parse_icmp_packet(&bytes).unwrap();
+    let reply = request.echo_reply_with(|payload| {
+        payload.iter_mut().for_each(|b| *b = !*b);
+    });
+
+    assert_eq!(reply.header.icmp_type, IcmpType::EchoReply);
+    assert_eq!(reply.header.data, Some(IcmpHeaderData::Echo { id: 12, seq: 3 }));
+    let inverted: Vec<u8> = request.data.iter().map(|b| !b).collect();
+    assert_eq!(reply.data, inverted);
+    assert_eq!(checksum_16(&reply.serialize()), 0);
+}
+
+#[test]
+fn test_echo_payload() {
+    let request = IcmpPacket::echo_request(1, 2, &[1, 2, 3, 0xF0]);
+    let reply = |echo_payload: EchoPayload| {
+        let reply = request.echo_reply_with(|payload| echo_payload.apply(payload));
+        assert_eq!(checksum_16(&reply.serialize()), 0, "{echo_payload:?}");
+        assert_eq!(reply.header.data, Some(IcmpHeaderData::Echo { id: 1, seq: 2 }));
+        reply.data
+    };
+
+    assert_eq!(reply(EchoPayload::Mirror), [1, 2, 3, 0xF0]);
+    assert_eq!(reply(EchoPayload::Zero), [0, 0, 0, 0]);
+    assert_eq!(reply(EchoPayload::Reverse), [0xF0, 3, 2, 1]);
+    assert_eq!(reply(EchoPayload::Invert), [0xFE, 0xFD, 0xFC, 0x0F]);
+    assert_eq!(EchoPayload::default(), EchoPayload::Mirror);
+}
+
+#[test]
+fn test_echo_id_rewriter() {
+    let bytes = [
+        8, 0, 80, 124, 0, 12, 0, 3, 237, 89, 158, 100, 0, 0, 0, 0, 91, 227, 1, 0, 0, 0, 0, 0, 16, 17, 18, 19, 20, 21, 22, 23, 24, 25, 26, 27, 28, 29, 30, 31, 32, 33, 34, 35, 36, 37, 38, 39, 40, 41, 42, 43, 44, 45, 46, 47, 48, 49, 50, 51, 52, 53, 54, 55
+    ];
+
+    let (_, request) = parse_icmp_packet(&bytes).unwrap();
+    let mut reply = request.echo_reply();
+
+    let mut rewriter = EchoIdRewriter::new();
+    rewriter.insert(12, 0xbeef);
+    assert!(rewriter.rewrite(&mut reply));
+    assert_eq!(reply.echo_id(), Some(0xbeef));
+    assert_eq!(checksum_16(&reply.serialize()), 0);
+
+    // unmapped identifiers are left untouched
+    let mut other = IcmpPacket::echo_request(13, 1, &[1, 2, 3]);
+    assert!(!rewriter.rewrite(&mut other));
+    assert_eq!(other.echo_id(), Some(13));
+}
+
+#[test]
+fn test_icmp_packet_checksum_verification() {
+    let mut bytes = [
+        8, 0, 80, 124, 0, 12, 0, 3, 237, 89, 158, 100, 0, 0, 0, 0, 91, 227, 1, 0, 0, 0, 0, 0, 16, 17, 18, 19, 20, 21, 22, 23, 24, 25, 26, 27, 28, 29, 30, 31, 32, 33, 34, 35, 36, 37, 38, 39, 40, 41, 42, 43, 44, 45, 46, 47, 48, 49, 50, 51, 52, 53, 54, 55
+    ];
+    assert!(parse_icmp_packet(&bytes).is_ok());
+
+    bytes[30] ^= 0xff;
+    match parse_icmp_packet(&bytes) {
+        Err(nom::Err::Failure(e)) => assert_eq!(e.code, ErrorKind::Verify),
+        other => panic!("expected a checksum failure, got {:?}", other),
+    }
+    assert!(parse_icmp_packet_unchecked(&bytes).is_ok());
+}
+
+#[test]
+fn test_icmp_checksum_covers_header_data() {
+    let raw_ip_header = [
+        69, 0, 0, 102, 133, 153, 0, 0, 255, 17, 74, 242, 10, 0, 0, 0, 224, 0, 0, 251
+    ];
+    let (_, ip_header) = parse_ipv4_header(&raw_ip_header).unwrap();
+
+    let mut packet = IcmpPacket {
+        header: IcmpHeader {
+            icmp_type: IcmpType::TimeExceeded,
+            code: 0,
+            checksum: 0,
+            data: Some(IcmpHeaderData::TimeExceeded {
+                ip_header,
+                data: vec![0, 53, 0, 53, 0, 82, 0, 0],
+                extension: None,
+            }),
+        },
+        data: Vec::new(),
+    };
+    packet.update_checksum();
+
+    assert_ne!(packet.header.checksum, 0);
+    assert_eq!(checksum_16(&packet.serialize()), 0);
+}
+
+#[test]
+fn test_icmp_address_mask_serialization() {
+    let bytes = [
+        18,                 // Type (Address Mask Reply)
+        0,                  // Code
+        238, 251,           // Checksum
+        0, 1,               // Identifier
+        0, 2,               // Sequence number
+        255, 255, 255, 0,   // Address mask
+    ];
+
+    let (_, packet) = parse_icmp_packet(&bytes).unwrap();
+    assert_eq!(packet.header.icmp_type, IcmpType::AddressMaskReply);
+    assert_eq!(packet.header.data, Some(IcmpHeaderData::AddressMask {
+        id: 1,
+        seq: 2,
+        mask: Ipv4Address(0xffffff00),
+    }));
+    assert_eq!(bytes, packet.serialize().as_slice());
+}
+
+#[test]
+fn test_icmp_redirect() {
+    use crate::util::roundtrip;
+
+    // what Linux, routing between 10.0.0.2 and 10.0.0.3 on the same link,
+    // sent back to 10.0.0.2 for a `ping 10.0.0.3`, captured on a TUN device
+    let bytes = [
+        5,                  // Type (Redirect)
+        1,                  // Code (for the host)
+        240, 251,           // Checksum
+        10, 0, 0, 3,        // Gateway
+        // original IP header, after the router decremented the TTL
+        69, 0, 0, 84, 84, 222, 64, 0, 63, 1, 210, 198, 10, 0, 0, 2, 10, 0, 0, 3,
+        // the rest of the original datagram, quoted whole
+        8, 0, 78, 194, 123, 45, 0, 1, 249, 128, 210, 106, 0, 0, 0, 0, 153, 80, 10, 0, 0, 0, 0, 0,
+        16, 17, 18, 19, 20, 21, 22, 23, 24, 25, 26, 27, 28, 29, 30, 31, 32, 33, 34, 35, 36, 37, 38, 39,
+        40, 41, 42, 43, 44, 45, 46, 47, 48, 49, 50, 51, 52, 53, 54, 55,
+    ];
+
+    let packet: IcmpPacket = roundtrip(&bytes);
+    assert!(IcmpPacket::parse(&bytes).is_ok());
+    let (gateway, ip_header) = match &packet.header.data {
+        Some(IcmpHeaderData::Redirect { gateway, ip_header, data }) => {
+            assert_eq!(data, &bytes[28..]);
+            (*gateway, ip_header)
+        },
+        other => panic!("expected a redirect, got {:?}", other),
+    };
+    assert_eq!(gateway, Ipv4Address::from([10, 0, 0, 3]));
+    assert_eq!(ip_header.destination, Ipv4Address::from([10, 0, 0, 3]));
+    assert_eq!(ip_header.ttl, 63);
+    assert!(format!("{:?}", packet.header.data).contains("gateway: 10.0.0.3"));
+
+    // the quote has to be an IPv4 header
+    let mut ipv6 = bytes;
+    ipv6[8] = 0x60;
+    assert!(parse_icmp_packet_unchecked(&ipv6).is_err());
+    let mut short_header = bytes;
+    short_header[8] = 0x44;
+    assert!(parse_icmp_packet_unchecked(&short_header).is_err());
+    assert!(parse_icmp_packet_unchecked(&bytes[..30]).is_err());
+}
+
+#[test]
+fn test_icmp_extended_echo_serialization() {
+    use crate::util::roundtrip;
+
+    // RFC 8335 probe of the interface with address 10.0.0.1
+    let bytes = [
+        42,                 // Type (Extended Echo Request)
+        0,                  // Code
+        190, 202,           // Checksum
+        0x12, 0x34,         // Identifier
+        5,                  // Sequence number
+        1,                  // Reserved, L-bit
+        32, 0, 206, 238,    // Extension header (version 2)
+        0, 12, 3, 3,        // Interface Identification Object, by address
+        0, 1, 4, 0,         // AFI (IPv4), address length
+        10, 0, 0, 1,
+    ];
+
+    let packet: IcmpPacket = roundtrip(&bytes);
+    assert_eq!(packet.header.icmp_type, IcmpType::ExtendedEchoRequest);
+    assert_eq!(packet.header.data, Some(IcmpHeaderData::ExtendedEcho {
+        id: 0x1234,
+        seq: 5,
+        local: true,
+    }));
+    assert_eq!(packet.description(), "Extended echo request");
+    assert_eq!(packet.data, bytes[8..]);
+    assert!(IcmpPacket::parse(&bytes).is_ok());
+
+    let mut remote = bytes;
+    remote[7] = 0;
+    let (_, packet) = parse_icmp_packet_unchecked(&remote).unwrap();
+    assert_eq!(packet.header.data, Some(IcmpHeaderData::ExtendedEcho { id: 0x1234, seq: 5, local: false }));
+
+    // the state bits of replies are kept as they are
+    let mut reply = bytes;
+    reply[0] = 43;
+    reply[7] = 0x24;
+    let (_, packet) = parse_icmp_packet_unchecked(&reply).unwrap();
+    assert_eq!(packet.header.icmp_type, IcmpType::ExtendedEchoReply);
+    assert_eq!(packet.header.data, Some(IcmpHeaderData::RestOfHeader { bytes: [0x12, 0x34, 5, 0x24] }));
+    assert_eq!(packet.serialize(), reply);
+}
+
+#[test]
+fn test_icmp_packet_parse_errors() {
+    use crate::ipv4::parse_ipv4_packet;
+    use crate::util::PING_REQUEST;
+
+    let bytes = PING_REQUEST;
+    let (_, mut ip_packet) = parse_ipv4_packet(&bytes).unwrap();
+    assert!(IcmpPacket::from_ipv4(&ip_packet).is_ok());
+
+    assert_eq!(IcmpPacket::parse(&ip_packet.data[..3]).unwrap_err(), IcmpTuntapError::TooShort);
+
+    ip_packet.data[20] ^= 0xff;
+    assert_eq!(IcmpPacket::from_ipv4(&ip_packet).unwrap_err(), IcmpTuntapError::BadChecksum);
+
+    ip_packet.header.protocol = Ipv4HeaderProtocol::Udp;
+    assert_eq!(IcmpPacket::from_ipv4(&ip_packet).unwrap_err(), IcmpTuntapError::UnsupportedProtocol);
+}
+
+#[test]
+fn test_icmp_time_exceeded_long_quote() {
+    let mut bytes = vec![
+        11, 0, 0, 0,        // Type, Code, Checksum
+        0, 0, 0, 0,         // Unused
+        // quoted IP header
+        69, 0, 0, 48, 133, 153, 0, 0, 1, 17, 74, 242, 10, 0, 0, 0, 224, 0, 0, 251,
+    ];
+    // 28 bytes of the original datagram
+    bytes.extend((0..28).collect::<Vec<u8>>());
+    let checksum = checksum_16(&bytes);
+    bytes[2..4].copy_from_slice(&checksum.to_be_bytes());
+
+    let (_, packet) = parse_icmp_packet(&bytes).unwrap();
+    match &packet.header.data {
+        Some(IcmpHeaderData::TimeExceeded { data, .. }) => assert_eq!(data.len(), 28),
+        other => panic!("expected Time Exceeded data, got {:?}", other),
+    }
+    assert!(packet.data.is_empty());
+    assert_eq!(bytes, packet.serialize());
+}
+
+#[test]
+fn test_icmp_quoted_datagram() {
+    let mut bytes = vec![
+        11, 0, 0, 0,        // Type, Code, Checksum
+        0, 0, 0, 0,         // Unused
+        // quoted IP header, with a Record Route option
+        70, 0, 0, 60, 133, 153, 0, 0, 1, 17, 74, 242, 10, 0, 0, 0, 224, 0, 0, 251,
+        7, 3, 4, 0,
+        // first 8 bytes of the original datagram
+        0x14, 0xe9, 0x14, 0xe9, 0, 36, 0, 0,
+    ];
+
+    let (_, header) = parse_icmp_header(&bytes).unwrap();
+    match header.data {
+        Some(IcmpHeaderData::TimeExceeded { ip_header, data, .. }) => {
+            assert_eq!(ip_header.options.len(), 2);
+            assert_eq!(data, [0x14, 0xe9, 0x14, 0xe9, 0, 36, 0, 0]);
+        },
+        other => panic!("expected Time Exceeded data, got {:?}", other),
+    }
+
+    // the quote stops in the middle of the 8 bytes
+    assert!(parse_icmp_header(&bytes[..bytes.len() - 3]).is_err());
+    assert!(parse_icmp_header(&bytes[..30]).is_err());
+
+    // unless the original datagram wasn't any longer
+    bytes[11] = 29;
+    bytes.truncate(bytes.len() - 3);
+    let (_, header) = parse_icmp_header(&bytes).unwrap();
+    match header.data {
+        Some(IcmpHeaderData::TimeExceeded { data, .. }) => assert_eq!(data.len(), 5),
+        other => panic!("expected Time Exceeded data, got {:?}", other),
+    }
+}
+
+#[test]
+fn test_icmp_time_exceeded_mpls_extension() {
+    let mut bytes = vec![
+        11, 0, 0, 0,        // Type, Code, Checksum
+        0,                  // Unused
+        32,                 // Length of the original datagram (32 words)
+        0, 0,               // Unused
+        // quoted IP header
+        69, 0, 0, 128, 133, 153, 0, 0, 1, 17, 74, 242, 10, 0, 0, 0, 224, 0, 0, 251,
+    ];
+    // original datagram, padded to 128 bytes as RFC 4884 requires
+    bytes.extend([0u8; 108]);
+    let extension = [
+        32, 0, 0, 0,        // Version 2, checksum (unset)
+        0, 8,               // Object length
+        1,                  // Class-Num (MPLS label stack)
+        1,                  // C-Type (incoming MPLS label stack)
+        0, 1, 65, 1,        // Label 20, TC 0, bottom of stack, TTL 1
+    ];
+    bytes.extend(extension);
+    let checksum = checksum_16(&bytes);
+    bytes[2..4].copy_from_slice(&checksum.to_be_bytes());
+
+    let (_, packet) = parse_icmp_packet(&bytes).unwrap();
+    match &packet.header.data {
+        Some(IcmpHeaderData::TimeExceeded { data, extension: Some(parsed), .. }) => {
+            assert_eq!(data.len(), 108);
+            assert_eq!(parsed.as_slice(), extension);
+        },
+        other => panic!("expected Time Exceeded data with an extension, got {:?}", other),
+    }
+    assert_eq!(bytes, packet.serialize());
+    assert_eq!(packet.serialized_len(), bytes.len());
+
+    // shorter or unaligned quotes get padded before the extension
+    let (ip_header, short) = match packet.header.data.clone() {
+        Some(IcmpHeaderData::TimeExceeded { ip_header, data, .. }) => (ip_header, data),
+        other => panic!("expected Time Exceeded data, got {:?}", other),
+    };
+    for (data_len, length) in [(8, 32), (131, 38)] {
+        let data = IcmpHeaderData::TimeExceeded {
+            ip_header: ip_header.clone(),
+            data: short.iter().copied().cycle().take(data_len).collect(),
+            extension: Some(extension.to_vec()),
+        };
+        let serialized = data.serialize();
+        assert_eq!(serialized[1], length);
+        assert_eq!(serialized.len(), 4 + length as usize * 4 + extension.len());
+        assert_eq!(data.serialized_len(), serialized.len());
+        assert!(serialized.ends_with(&extension));
+    }
+
+    // without the length field everything is part of the datagram
+    bytes[5] = 0;
+    let (_, packet) = parse_icmp_packet_unchecked(&bytes).unwrap();
+    match &packet.header.data {
+        Some(IcmpHeaderData::TimeExceeded { data, extension: None, .. }) => assert_eq!(data.len(), 120),
+        other => panic!("expected Time Exceeded data without an extension, got {:?}", other),
+    }
+}
+
+#[test]
+fn test_icmp_time_exceeded_length_without_extension() {
+    let mut bytes = vec![
+        11, 0, 0, 0,        // Type, Code, Checksum
+        0,                  // Unused
+        7,                  // Length of the original datagram (7 words)
+        0, 0,               // Unused
+        // quoted IP header
+        69, 0, 0, 28, 133, 153, 0, 0, 1, 17, 74, 242, 10, 0, 0, 0, 224, 0, 0, 251,
+        // first 8 bytes of the original datagram
+        0x14, 0xe9, 0x14, 0xe9, 0, 8, 0, 0,
+    ];
+    let checksum = checksum_16(&bytes);
+    bytes[2..4].copy_from_slice(&checksum.to_be_bytes());
+
+    // the length covers the whole message and is under 128 bytes, so
+    // nothing follows the datagram
+    let (_, mut packet) = parse_icmp_packet(&bytes).unwrap();
+    match &packet.header.data {
+        Some(IcmpHeaderData::TimeExceeded { data, extension: None, .. }) => assert_eq!(data.len(), 8),
+        other => panic!("expected Time Exceeded data without an extension, got {:?}", other),
+    }
+
+    // serialized back without padding, only the length field is cleared
+    packet.update_checksum();
+    let serialized = packet.serialize();
+    assert_eq!(serialized.len(), bytes.len());
+    assert_eq!(serialized[5], 0);
+    assert_eq!(serialized[4..], [&bytes[4..5], &[0], &bytes[6..]].concat());
+    let (_, reparsed) = parse_icmp_packet(&serialized).unwrap();
+    assert_eq!(reparsed.header.data, packet.header.data);
+
+    // a length of 128 bytes or more with nothing after it isn't an
+    // extension either
+    bytes.extend([0u8; 100]);
+    bytes[5] = 34;
+    let (_, packet) = parse_icmp_packet_unchecked(&bytes).unwrap();
+    match &packet.header.data {
+        Some(IcmpHeaderData::TimeExceeded { data, extension: None, .. }) => assert_eq!(data.len(), 108),
+        other => panic!("expected Time Exceeded data without an extension, got {:?}", other),
+    }
+}
+
+#[test]
+fn test_icmp_packet_display() {
+    let bytes = [
+        8, 0, 80, 124, 0, 12, 0, 3, 237, 89, 158, 100, 0, 0, 0, 0, 91, 227, 1, 0, 0, 0, 0, 0, 16, 17, 18, 19, 20, 21, 22, 23, 24, 25, 26, 27, 28, 29, 30, 31, 32, 33, 34, 35, 36, 37, 38, 39, 40, 41, 42, 43, 44, 45, 46, 47, 48, 49, 50, 51, 52, 53, 54, 55
+    ];
+
+    let (_, packet) = parse_icmp_packet(&bytes).unwrap();
+    assert_eq!(packet.description(), "Echo request");
+    assert_eq!(packet.to_string(), "ICMP EchoRequest (Echo request) code=0 len=56");
+}
+
+#[test]
+fn test_icmp_packet_summary() {
+    let bytes = [
+        8, 0, 88, 204, 0, 3, 0, 4, 86, 1, 157, 100, 0, 0, 0, 0, 227, 243, 9, 0, 0, 0, 0, 0, 16, 17, 18, 19, 20, 21, 22, 23, 24, 25, 26, 27, 28, 29, 30, 31, 32, 33, 34, 35, 36, 37, 38, 39, 40, 41, 42, 43, 44, 45, 46, 47, 48, 49, 50, 51, 52, 53, 54, 55
+    ];
+    let (_, packet) = parse_icmp_packet_unchecked(&bytes).unwrap();
+    assert_eq!(packet.summary(), "EchoRequest/0 \"Echo request\" id=3 seq=4 payload=56B");
+    let (_, packet) = parse_icmp_packet_ref_unchecked(&bytes).unwrap();
+    assert_eq!(packet.summary(), "EchoRequest/0 \"Echo request\" id=3 seq=4 payload=56B");
+
+    // no id and seq outside of echo messages
+    let bytes = [10, 0, 245, 255, 0, 0, 0, 0];
+    let (_, packet) = parse_icmp_packet(&bytes).unwrap();
+    assert_eq!(packet.summary(), "RouterSolicitation/0 \"Router Solicitation\" payload=0B");
+}
+
+#[test]
+fn test_icmp_packet_to_json() {
+    let mut bytes = [
+        8, 0, 80, 124, 0, 12, 0, 3, 237, 89, 158, 100, 0, 0, 0, 0, 91, 227, 1, 0, 0, 0, 0, 0, 16, 17, 18, 19, 20, 21, 22, 23, 24, 25, 26, 27, 28, 29, 30, 31, 32, 33, 34, 35, 36, 37, 38, 39, 40, 41, 42, 43, 44, 45, 46, 47, 48, 49, 50, 51, 52, 53, 54, 55
+    ];
+
+    let (_, packet) = parse_icmp_packet(&bytes).unwrap();
+    let json = packet.to_json();
+    assert_eq!(json, r#"{"type":"EchoRequest","code":0,"id":12,"seq":3,"checksum_valid":true}"#);
+    assert!(!json.contains('\\'));
+
+    bytes[2] ^= 0xff;
+    let (_, packet) = parse_icmp_packet_unchecked(&bytes).unwrap();
+    assert!(packet.to_json().ends_with(r#""checksum_valid":false}"#));
+}
+
+#[test]
+fn test_icmp_source_quench_serialization() {
+    let mut bytes = vec![
+        4, 0, 0, 0,         // Type, Code, Checksum
+        0, 0, 0, 0,         // Unused
+        // quoted IP header
+        69, 0, 0, 48, 133, 153, 0, 0, 1, 17, 74, 242, 10, 0, 0, 0, 224, 0, 0, 251,
+        // first 8 bytes of the original datagram
+        0, 1, 2, 3, 4, 5, 6, 7,
+    ];
+    let checksum = checksum_16(&bytes);
+    bytes[2..4].copy_from_slice(&checksum.to_be_bytes());
+
+    let (_, packet) = parse_icmp_packet(&bytes).unwrap();
+    assert_eq!(packet.header.icmp_type, IcmpType::SourceQuench);
+    assert_eq!(u8::from(packet.header.icmp_type), 4);
+    assert_eq!(packet.description(), "Source quench");
+    match &packet.header.data {
+        Some(IcmpHeaderData::SourceQuench { ip_header, data }) => {
+            assert_eq!(ip_header.destination, Ipv4Address::from([224, 0, 0, 251]));
+            assert_eq!(data, &[0, 1, 2, 3, 4, 5, 6, 7]);
+        },
+        other => panic!("expected Source Quench data, got {:?}", other),
+    }
+    assert_eq!(bytes, packet.serialize());
+}
+
+#[test]
+fn test_icmp_parameter_problem_pointer() {
+    let mut bytes = vec![
+        12, 0, 0, 0,        // Type, Code, Checksum
+        20,                 // Pointer (first option octet)
+        0,                  // Length (no extension)
+        0xff, 0xff,         // Unused, zeroed on serialization
+        // quoted IP header
+        70, 0, 0, 52, 133, 153, 0, 0, 1, 17, 74, 242, 10, 0, 0, 0, 224, 0, 0, 251,
+        // an option the receiver didn't like
+        0x99, 4, 0, 0,
+        // first 8 bytes of the original datagram
+        0, 1, 2, 3, 4, 5, 6, 7,
+    ];
+    let checksum = checksum_16(&bytes);
+    bytes[2..4].copy_from_slice(&checksum.to_be_bytes());
+
+    let (_, mut packet) = parse_icmp_packet(&bytes).unwrap();
+    match &packet.header.data {
+        Some(IcmpHeaderData::ParameterProblem { pointer, data, .. }) => {
+            assert_eq!(*pointer, 20);
+            assert_eq!(data, &[0, 1, 2, 3, 4, 5, 6, 7]);
+        },
+        other => panic!("expected Parameter Problem data, got {:?}", other),
+    }
+    assert!(packet.data.is_empty());
+
+    packet.update_checksum();
+    let serialized = packet.serialize();
+    assert_eq!(serialized[4..8], [20, 0, 0, 0]);
+    assert_eq!(serialized[8..], bytes[8..]);
+
+    let (_, reparsed) = parse_icmp_packet(&serialized).unwrap();
+    assert_eq!(reparsed.header.data, packet.header.data);
+
+    // the RFC 4884 length of a message with an extension survives too
+    let mut extended = serialized;
+    extended[5] = 32;
+    extended.resize(8 + 128, 0);
+    extended.extend([32, 0, 0, 0, 0, 4, 1, 1]);
+    let (_, packet) = parse_icmp_packet_unchecked(&extended).unwrap();
+    assert_eq!(packet.serialize(), extended);
+}
+
+#[test]
+fn test_icmp_destination_unreachable_next_hop_mtu() {
+    let mut bytes = vec![
+        3, 4, 0, 0,         // Type, Code (fragmentation needed), Checksum
+        0, 0,               // Unused, Length
+        5, 220,             // Next-hop MTU (1500)
+        // quoted IP header
+        69, 0, 5, 240, 133, 153, 64, 0, 64, 17, 74, 242, 10, 0, 0, 0, 10, 0, 0, 1,
+        0, 0, 0, 0, 0, 0, 0, 0,
+    ];
+
+    let (_, header) = parse_icmp_header(&bytes).unwrap();
+    match header.data {
+        Some(IcmpHeaderData::DestinationUnreachable { next_hop_mtu, .. }) => assert_eq!(next_hop_mtu, 1500),
+        other => panic!("expected Destination Unreachable data, got {:?}", other),
+    }
+
+    // host unreachable leaves those bytes unused, whatever the sender put there
+    bytes[1] = 1;
+    let (_, header) = parse_icmp_header(&bytes).unwrap();
+    match header.data {
+        Some(IcmpHeaderData::DestinationUnreachable { next_hop_mtu, .. }) => assert_eq!(next_hop_mtu, 0),
+        other => panic!("expected Destination Unreachable data, got {:?}", other),
+    }
+}
+
+#[test]
+fn test_icmp_router_advertisement() {
+    let mut bytes = vec![
+        9, 0, 0, 0,         // Type, Code, Checksum
+        2,                  // Number of addresses
+        2,                  // Address entry size (words)
+        7, 8,               // Lifetime (1800 seconds)
+        10, 0, 0, 1,        // Router address
+        0, 0, 0, 10,        // Preference level
+        10, 0, 0, 2,        // Router address
+        0xff, 0xff, 0xff, 0xff, // Preference level (-1)
+    ];
+    let checksum = checksum_16(&bytes);
+    bytes[2..4].copy_from_slice(&checksum.to_be_bytes());
+
+    let (_, packet) = parse_icmp_packet(&bytes).unwrap();
+    assert_eq!(packet.header.data, Some(IcmpHeaderData::RouterAdvertisement {
+        lifetime: 1800,
+        entry_words: 2,
+        entries: vec![
+            (Ipv4Address::from([10, 0, 0, 1]), 10, Vec::new()),
+            (Ipv4Address::from([10, 0, 0, 2]), 0xffffffff, Vec::new()),
+        ],
+    }));
+    assert!(packet.data.is_empty());
+    assert_eq!(bytes, packet.serialize());
+    assert_eq!(packet.serialized_len(), bytes.len());
+
+    // bigger entries are written back as they came
+    let mut wide = vec![
+        9, 0, 0, 0,
+        1, 3, 7, 8,
+        10, 0, 0, 1,
+        0, 0, 0, 10,
+        1, 2, 3, 4,         // a field past the preference level
+    ];
+    let checksum = checksum_16(&wide);
+    wide[2..4].copy_from_slice(&checksum.to_be_bytes());
+    let (_, packet) = parse_icmp_packet(&wide).unwrap();
+    match &packet.header.data {
+        Some(IcmpHeaderData::RouterAdvertisement { entry_words, entries, .. }) => {
+            assert_eq!(*entry_words, 3);
+            assert_eq!(entries[0].2, [1, 2, 3, 4]);
+        },
+        other => panic!("expected a Router Advertisement, got {:?}", other),
+    }
+    assert_eq!(packet.serialize(), wide);
+    assert_eq!(packet.serialized_len(), wide.len());
+
+    // the announced addresses are missing
+    bytes.truncate(8);
+    assert!(parse_icmp_packet_unchecked(&bytes).is_err());
+}
+
+#[test]
+#[should_panic(expected = "more than 255")]
+fn test_icmp_router_advertisement_too_many_entries() {
+    let data = IcmpHeaderData::RouterAdvertisement {
+        lifetime: 1800,
+        entry_words: 2,
+        entries: vec![(Ipv4Address::from([10, 0, 0, 1]), 0, Vec::new()); 256],
+    };
+    data.serialize();
+}
+
+#[test]
+fn test_icmp_rest_of_header() {
+    use crate::consts::ICMP_HEADER_LEN;
+
+    let mut bytes = vec![
+        40, 1, 0, 0,        // Type (Photuris), Code, Checksum
+        0, 0, 0x12, 0x34,   // Reserved, Pointer
+        1, 2, 3, 4,
+    ];
+    let checksum = checksum_16(&bytes);
+    bytes[2..4].copy_from_slice(&checksum.to_be_bytes());
+
+    let (_, packet) = parse_icmp_packet(&bytes).unwrap();
+    assert_eq!(packet.header.icmp_type, IcmpType::Unimplemented(40));
+    assert_eq!(packet.header.data, Some(IcmpHeaderData::RestOfHeader { bytes: [0, 0, 0x12, 0x34] }));
+    assert_eq!(packet.data, [1, 2, 3, 4]);
+    assert_eq!(bytes, packet.serialize());
+    assert_eq!(bytes[..ICMP_HEADER_LEN], packet.header.serialize());
+
+    // the header is 8 bytes, whatever the type
+    assert!(parse_icmp_packet_unchecked(&bytes[..6]).is_err());
+}
+
+#[test]
+fn test_icmp_packet_ref() {
+    use crate::util::count_allocations;
+
+    let bytes = [
+        8, 0, 80, 124, 0, 12, 0, 3, 237, 89, 158, 100, 0, 0, 0, 0, 91, 227, 1, 0, 0, 0, 0, 0, 16, 17, 18, 19, 20, 21, 22, 23, 24, 25, 26, 27, 28, 29, 30, 31, 32, 33, 34, 35, 36, 37, 38, 39, 40, 41, 42, 43, 44, 45, 46, 47, 48, 49, 50, 51, 52, 53, 54, 55
+    ];
+
+    let (result, allocations) = count_allocations(|| parse_icmp_packet_ref(&bytes));
+    let (_, packet) = result.unwrap();
+    assert_eq!(allocations, 0);
+    assert_eq!(packet.data, &bytes[8..]);
+
+    let (result, allocations) = count_allocations(|| parse_icmp_packet(&bytes));
+    let (_, owned) = result.unwrap();
+    assert!(allocations > 0);
+
+    assert_eq!(packet.serialize(), owned.serialize());
+    assert_eq!(packet.to_owned().serialize(), bytes);
+    assert_eq!(packet.to_string(), owned.to_string());
+    assert_eq!(packet.echo_reply().serialize(), owned.echo_reply().serialize());
+
+    let mut corrupted = bytes;
+    corrupted[2] ^= 0xff;
+    assert!(parse_icmp_packet_ref(&corrupted).is_err());
+    assert!(parse_icmp_packet_ref_unchecked(&corrupted).is_ok());
+}
@@ -0,0 +1,239 @@
+use std::fmt;
+
+use nom::error::{Error, ErrorKind};
+use nom::{bytes, number, IResult};
+
+use crate::error::IcmpTuntapError;
+use crate::ipv6::{Ipv6Address, Ipv6Header, Ipv6Packet, NEXT_HEADER_ICMPV6};
+use crate::util::{checksum_16, Serialize};
+
+// RFC 4443, only as much of it as answering pings takes
+
+#[allow(dead_code)]
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum Icmpv6Type {
+    EchoRequest,
+    EchoReply,
+    Unimplemented(u8),
+}
+
+impl From<u8> for Icmpv6Type {
+    fn from(orig: u8) -> Self {
+        match orig {
+            128 => Icmpv6Type::EchoRequest,
+            129 => Icmpv6Type::EchoReply,
+            _ => Icmpv6Type::Unimplemented(orig),
+        }
+    }
+}
+
+impl From<Icmpv6Type> for u8 {
+    fn from(orig: Icmpv6Type) -> Self {
+        match orig {
+            Icmpv6Type::EchoRequest => 128,
+            Icmpv6Type::EchoReply => 129,
+            Icmpv6Type::Unimplemented(unknown) => unknown,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Icmpv6Packet {
+    pub icmp_type: Icmpv6Type,
+    pub code: u8,
+    pub checksum: u16,
+    // the 32 bits following the checksum, the identifier and sequence
+    // number of echo messages
+    pub rest_of_header: [u8; 4],
+    pub data: Vec<u8>,
+}
+
+impl Serialize for Icmpv6Packet {
+    fn serialize_into(&self, s: &mut Vec<u8>) {
+        s.push(self.icmp_type.into());
+        s.push(self.code);
+        s.extend(self.checksum.to_be_bytes());
+        s.extend(self.rest_of_header);
+        s.extend(&self.data);
+    }
+
+    fn serialized_len(&self) -> usize {
+        8 + self.data.len()
+    }
+}
+
+impl fmt::Display for Icmpv6Packet {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "ICMPv6 {:?} code={} len={}", self.icmp_type, self.code, self.data.len())
+    }
+}
+
+// Unlike ICMP, the ICMPv6 checksum also covers a pseudo-header with the
+// addresses of the IPv6 packet carrying the message (RFC 8200, section
+// 8.1). `message` has its checksum field zeroed to compute a checksum,
+// or left as is to verify one.
+pub fn icmpv6_checksum(source: Ipv6Address, destination: Ipv6Address, message: &[u8]) -> u16 {
+    let mut data = Vec::with_capacity(40 + message.len());
+    data.extend(source.0.to_be_bytes());
+    data.extend(destination.0.to_be_bytes());
+    data.extend((message.len() as u32).to_be_bytes());
+    data.extend([0, 0, 0, NEXT_HEADER_ICMPV6]);
+    data.extend(message);
+    checksum_16(&data)
+}
+
+pub fn parse_icmpv6_packet_unchecked(input: &[u8]) -> IResult<&[u8], Icmpv6Packet> {
+    let (rest, icmp_type) = number::complete::be_u8(input)?;
+    let (rest, code) = number::complete::be_u8(rest)?;
+    let (rest, checksum) = number::complete::be_u16(rest)?;
+    let (rest, rest_of_header) = bytes::complete::take(4usize)(rest)?;
+    let (rest, data) = nom::combinator::rest(rest)?;
+    Ok((rest, Icmpv6Packet {
+        icmp_type: Icmpv6Type::from(icmp_type),
+        code,
+        checksum,
+        rest_of_header: rest_of_header.try_into().unwrap(),
+        data: data.to_vec(),
+    }))
+}
+
+// Parses the ICMPv6 message carried by `packet`, failing with
+// `ErrorKind::Verify` if the checksum doesn't match
+pub fn parse_icmpv6_packet<'a>(header: &Ipv6Header, input: &'a [u8]) -> IResult<&'a [u8], Icmpv6Packet> {
+    let (rest, packet) = parse_icmpv6_packet_unchecked(input)?;
+    if icmpv6_checksum(header.source, header.destination, input) != 0 {
+        return Err(nom::Err::Failure(Error::new(input, ErrorKind::Verify)));
+    }
+    Ok((rest, packet))
+}
+
+#[allow(dead_code)]
+impl Icmpv6Packet {
+    pub fn from_ipv6(packet: &Ipv6Packet) -> Result<Icmpv6Packet, IcmpTuntapError> {
+        if packet.header.next_header != NEXT_HEADER_ICMPV6 {
+            return Err(IcmpTuntapError::UnsupportedProtocol);
+        }
+        let (_, icmp_packet) = parse_icmpv6_packet(&packet.header, &packet.data)?;
+        Ok(icmp_packet)
+    }
+
+    pub fn echo_id(&self) -> Option<u16> {
+        self.is_echo().then(|| u16::from_be_bytes([self.rest_of_header[0], self.rest_of_header[1]]))
+    }
+
+    pub fn echo_seq(&self) -> Option<u16> {
+        self.is_echo().then(|| u16::from_be_bytes([self.rest_of_header[2], self.rest_of_header[3]]))
+    }
+
+    fn is_echo(&self) -> bool {
+        matches!(self.icmp_type, Icmpv6Type::EchoRequest | Icmpv6Type::EchoReply)
+    }
+
+    pub fn update_checksum(&mut self, source: Ipv6Address, destination: Ipv6Address) {
+        self.checksum = 0;
+        self.checksum = icmpv6_checksum(source, destination, &self.serialize());
+    }
+}
+
+// The reply to the ICMPv6 echo request `icmp_request` that came in the
+// packet with `request` for a header, going back to whoever sent it.
+// `transform` may rewrite the echoed payload before the checksum is
+// computed, as with `IcmpPacket::echo_reply_with`. None for anything
+// but echo requests, and for requests to a multicast address: the reply
+// would have to come from one of our unicast ones (RFC 4443 2.2).
+pub fn build_echo_reply<F: FnOnce(&mut [u8])>(request: &Ipv6Header, icmp_request: &Icmpv6Packet, hop_limit: u8, transform: F)
+    -> Option<Ipv6Packet> {
+    if icmp_request.icmp_type != Icmpv6Type::EchoRequest || request.destination.is_multicast() {
+        return None;
+    }
+
+    let mut data = icmp_request.data.clone();
+    transform(&mut data);
+    let mut icmp_reply = Icmpv6Packet {
+        icmp_type: Icmpv6Type::EchoReply,
+        code: 0,
+        checksum: 0,
+        rest_of_header: icmp_request.rest_of_header,
+        data,
+    };
+    let header = Ipv6Header {
+        payload_length: icmp_reply.serialized_len() as u16,
+        hop_limit,
+        source: request.destination,
+        destination: request.source,
+        ..request.clone()
+    };
+    icmp_reply.update_checksum(header.source, header.destination);
+    Some(Ipv6Packet { header, data: icmp_reply.serialize() })
+}
+
+// `ping -6 -c 1 fd42::1` from fd42::2, and the reply Linux sent back,
+// captured on a TUN device
+#[cfg(test)]
+pub const ECHO_REQUEST: [u8; 64] = [
+    0x60, 0x03, 0xf3, 0x33, 0, 24, 58, 64,
+    0xfd, 0x42, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 2,
+    0xfd, 0x42, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1,
+    128,                        // Type (Echo Request)
+    0,                          // Code
+    0xd6, 0x7c,                 // Checksum
+    0x7b, 0x2d,                 // Identifier
+    0, 1,                       // Sequence number
+    0xfd, 0x80, 0xd2, 0x6a, 0, 0, 0, 0, 0x5f, 0x8d, 0x04, 0, 0, 0, 0, 0,
+];
+
+#[cfg(test)]
+const ECHO_REPLY: [u8; 64] = [
+    0x60, 0x05, 0x3d, 0x79, 0, 24, 58, 64,
+    0xfd, 0x42, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1,
+    0xfd, 0x42, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 2,
+    129, 0, 0xd5, 0x7c, 0x7b, 0x2d, 0, 1,
+    0xfd, 0x80, 0xd2, 0x6a, 0, 0, 0, 0, 0x5f, 0x8d, 0x04, 0, 0, 0, 0, 0,
+];
+
+#[test]
+fn test_icmpv6_echo_request() {
+    let packet = Ipv6Packet::parse(&ECHO_REQUEST).unwrap();
+    let icmp_packet = Icmpv6Packet::from_ipv6(&packet).unwrap();
+    assert_eq!(icmp_packet.icmp_type, Icmpv6Type::EchoRequest);
+    assert_eq!(icmp_packet.echo_id(), Some(0x7b2d));
+    assert_eq!(icmp_packet.echo_seq(), Some(1));
+    assert_eq!(icmp_packet.data, ECHO_REQUEST[48..]);
+    assert_eq!(icmp_packet.serialize(), ECHO_REQUEST[40..]);
+
+    // the checksum covers the addresses too
+    let mut other_source = packet.clone();
+    other_source.header.source.0 += 1;
+    assert_eq!(Icmpv6Packet::from_ipv6(&other_source).unwrap_err(), IcmpTuntapError::BadChecksum);
+    let mut corrupted = packet.clone();
+    corrupted.data[10] ^= 0x01;
+    assert_eq!(Icmpv6Packet::from_ipv6(&corrupted).unwrap_err(), IcmpTuntapError::BadChecksum);
+    let mut udp = packet;
+    udp.header.next_header = 17;
+    assert_eq!(Icmpv6Packet::from_ipv6(&udp).unwrap_err(), IcmpTuntapError::UnsupportedProtocol);
+}
+
+#[test]
+fn test_icmpv6_echo_reply() {
+    let request = Ipv6Packet::parse(&ECHO_REQUEST).unwrap();
+    let icmp_request = Icmpv6Packet::from_ipv6(&request).unwrap();
+    let reply = build_echo_reply(&request.header, &icmp_request, 64, |_| {}).unwrap();
+
+    // what Linux answers, but for the flow label it picks for itself
+    let mut expected = Ipv6Packet::parse(&ECHO_REPLY).unwrap();
+    assert_ne!(expected.header.flow_label, request.header.flow_label);
+    expected.header.flow_label = request.header.flow_label;
+    assert_eq!(reply.serialize(), expected.serialize());
+    assert_eq!(Icmpv6Packet::from_ipv6(&reply).unwrap().icmp_type, Icmpv6Type::EchoReply);
+
+    // replies aren't answered
+    let icmp_reply = Icmpv6Packet::from_ipv6(&reply).unwrap();
+    assert!(build_echo_reply(&reply.header, &icmp_reply, 64, |_| {}).is_none());
+    let mut multicast = request.header.clone();
+    multicast.destination = Ipv6Address(0xff02 << 112 | 1);
+    assert!(build_echo_reply(&multicast, &icmp_request, 64, |_| {}).is_none());
+
+    let zeroed = build_echo_reply(&request.header, &icmp_request, 1, |payload| payload.fill(0)).unwrap();
+    assert_eq!(zeroed.header.hop_limit, 1);
+    assert_eq!(Icmpv6Packet::from_ipv6(&zeroed).unwrap().data, [0; 16]);
+}
@@ -0,0 +1,155 @@
+// Makes replies go missing or arrive corrupted on purpose, to see how
+// ping-based monitors cope without having to set up `tc netem`
+
+use std::time::Duration;
+
+// SplitMix64, plenty for picking which replies to mess with and small
+// enough not to need a dependency. The same seed always gives the same
+// numbers.
+#[derive(Debug, Clone)]
+pub struct Rng(u64);
+
+impl Rng {
+    pub fn new(seed: u64) -> Rng {
+        Rng(seed)
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9e3779b97f4a7c15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+        z ^ (z >> 31)
+    }
+
+    // uniform in [0, 1)
+    pub fn next_f64(&mut self) -> f64 {
+        // the 53 bits an f64 mantissa can hold
+        (self.next_u64() >> 11) as f64 / (1u64 << 53) as f64
+    }
+
+    // uniform in [0, n), n > 0; the modulo bias is negligible for the
+    // sizes of a packet
+    pub fn below(&mut self, n: usize) -> usize {
+        (self.next_u64() % n as u64) as usize
+    }
+}
+
+// the fractions of replies to drop and to corrupt, 0 for none
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct ImpairConfig {
+    pub loss: f64,
+    pub corrupt: f64,
+}
+
+impl ImpairConfig {
+    pub fn is_off(&self) -> bool {
+        self.loss == 0.0 && self.corrupt == 0.0
+    }
+}
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum Impairment {
+    Deliver,
+    Drop,
+    // flip this bit of the message, counting from the most significant
+    // bit of the first byte
+    Corrupt(usize),
+}
+
+// What to do with a reply `len` bytes long. Only depends on the state of
+// `rng`, so a seeded one always gives the same pattern.
+pub fn decide(rng: &mut Rng, config: &ImpairConfig, len: usize) -> Impairment {
+    if config.loss > 0.0 && rng.next_f64() < config.loss {
+        return Impairment::Drop;
+    }
+    if len > 0 && config.corrupt > 0.0 && rng.next_f64() < config.corrupt {
+        return Impairment::Corrupt(rng.below(len * 8));
+    }
+    Impairment::Deliver
+}
+
+// A random delay in [min, max], for replies that don't all take the
+// same time; min <= max
+pub fn jitter(rng: &mut Rng, min: Duration, max: Duration) -> Duration {
+    min + (max - min).mul_f64(rng.next_f64())
+}
+
+// flips the bit picked by `Impairment::Corrupt`, leaving the checksum
+// alone so that the receiver notices
+pub fn flip_bit(data: &mut [u8], bit: usize) {
+    data[bit / 8] ^= 0x80 >> (bit % 8);
+}
+
+#[test]
+fn test_rng_is_seedable() {
+    let mut a = Rng::new(42);
+    let mut b = Rng::new(42);
+    let mut c = Rng::new(43);
+    let a: Vec<u64> = (0..8).map(|_| a.next_u64()).collect();
+    let b: Vec<u64> = (0..8).map(|_| b.next_u64()).collect();
+    let c: Vec<u64> = (0..8).map(|_| c.next_u64()).collect();
+    assert_eq!(a, b);
+    assert_ne!(a, c);
+
+    let mut rng = Rng::new(7);
+    for _ in 0..1000 {
+        let x = rng.next_f64();
+        assert!((0.0..1.0).contains(&x));
+        assert!(rng.below(3) < 3);
+    }
+}
+
+#[test]
+fn test_decide() {
+    let decisions = |config: ImpairConfig| {
+        let mut rng = Rng::new(1);
+        (0..12).map(|_| decide(&mut rng, &config, 64)).collect::<Vec<_>>()
+    };
+
+    assert!(decisions(ImpairConfig::default()).iter().all(|d| *d == Impairment::Deliver));
+    assert!(decisions(ImpairConfig { loss: 1.0, corrupt: 0.0 }).iter().all(|d| *d == Impairment::Drop));
+
+    use Impairment::*;
+    assert_eq!(decisions(ImpairConfig { loss: 0.25, corrupt: 0.5 }), [
+        Deliver, Corrupt(441), Deliver, Corrupt(406), Deliver, Deliver,
+        Corrupt(355), Deliver, Corrupt(156), Corrupt(415), Drop, Deliver,
+    ]);
+
+    // about the configured fractions over many replies
+    let mut rng = Rng::new(1);
+    let config = ImpairConfig { loss: 0.1, corrupt: 0.05 };
+    let all: Vec<Impairment> = (0..10_000).map(|_| decide(&mut rng, &config, 64)).collect();
+    let dropped = all.iter().filter(|d| **d == Drop).count();
+    let corrupted = all.iter().filter(|d| matches!(d, Corrupt(_))).count();
+    assert!((900..1100).contains(&dropped), "{dropped} dropped");
+    assert!((350..550).contains(&corrupted), "{corrupted} corrupted");
+    assert!(all.iter().all(|d| !matches!(d, Corrupt(bit) if *bit >= 64 * 8)));
+}
+
+#[test]
+fn test_jitter() {
+    let min = Duration::from_millis(5);
+    let max = Duration::from_millis(40);
+    let mut rng = Rng::new(3);
+    let delays: Vec<Duration> = (0..10_000).map(|_| jitter(&mut rng, min, max)).collect();
+    assert!(delays.iter().all(|d| (min..=max).contains(d)));
+    // spread over the whole range
+    assert!(delays.iter().any(|d| *d < Duration::from_millis(8)));
+    assert!(delays.iter().any(|d| *d > Duration::from_millis(37)));
+
+    assert_eq!(jitter(&mut rng, min, min), min);
+    let mut again = Rng::new(3);
+    assert_eq!(jitter(&mut again, min, max), delays[0]);
+}
+
+#[test]
+fn test_flip_bit() {
+    let mut data = [0u8; 2];
+    flip_bit(&mut data, 0);
+    assert_eq!(data, [0x80, 0]);
+    flip_bit(&mut data, 15);
+    assert_eq!(data, [0x80, 0x01]);
+    flip_bit(&mut data, 0);
+    assert_eq!(data, [0, 0x01]);
+}
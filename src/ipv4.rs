@@ -1,15 +1,21 @@
 use std::fmt;
-use std::cmp::max;
+use std::io;
+use std::net::Ipv4Addr;
+use std::str::FromStr;
 
+use log::warn;
 use nom::IResult;
 use nom::bytes;
-use nom::error::Error;
+use nom::error::{Error, ErrorKind};
 use nom::bits;
 use nom::number;
 use nom::sequence;
 
-use crate::util::Serialize;
-use crate::util::checksum_16;
+use crate::consts::IPV4_MIN_HEADER_LEN;
+use crate::error::IcmpTuntapError;
+use crate::util::{Deserialize, Serialize};
+use crate::util::{checksum_16, checksum_valid};
+use crate::util::checksum_update;
 
 // https://en.wikipedia.org/wiki/Internet_Protocol_version_4
 
@@ -23,11 +29,109 @@ pub struct Ipv4HeaderPrelude {
 }
 
 impl Serialize for Ipv4HeaderPrelude {
-    fn serialize(&self) -> Vec<u8> {
+    fn serialize_into(&self, s: &mut Vec<u8>) {
         let version_ihl = (self.version << 4) | self.header_length;
         let dscp_ecn = (self.dscp << 2) | self.ecn;
-        vec![version_ihl, dscp_ecn]
+        s.extend([version_ihl, dscp_ecn]);
     }
+
+    fn serialized_len(&self) -> usize {
+        2
+    }
+}
+
+// https://www.rfc-editor.org/rfc/rfc3168#section-5
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum Ecn {
+    NotEct = 0b00,
+    Ect1 = 0b01,
+    Ect0 = 0b10,
+    CongestionEncountered = 0b11,
+}
+
+impl From<u8> for Ecn {
+    // only the two low bits are meaningful
+    fn from(bits: u8) -> Self {
+        match bits & 0b11 {
+            0b00 => Ecn::NotEct,
+            0b01 => Ecn::Ect1,
+            0b10 => Ecn::Ect0,
+            _ => Ecn::CongestionEncountered,
+        }
+    }
+}
+
+impl From<Ecn> for u8 {
+    fn from(ecn: Ecn) -> Self {
+        ecn as u8
+    }
+}
+
+// The 6 bit DSCP codepoint, see RFC 2474 and RFC 4594
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct Dscp(pub u8);
+
+#[allow(dead_code)]
+impl Dscp {
+    pub const CS0: Dscp = Dscp(0);
+    pub const CS1: Dscp = Dscp(8);
+    pub const CS2: Dscp = Dscp(16);
+    pub const CS3: Dscp = Dscp(24);
+    pub const CS4: Dscp = Dscp(32);
+    pub const CS5: Dscp = Dscp(40);
+    pub const CS6: Dscp = Dscp(48);
+    pub const CS7: Dscp = Dscp(56);
+    pub const AF11: Dscp = Dscp(10);
+    pub const AF12: Dscp = Dscp(12);
+    pub const AF13: Dscp = Dscp(14);
+    pub const AF21: Dscp = Dscp(18);
+    pub const AF22: Dscp = Dscp(20);
+    pub const AF23: Dscp = Dscp(22);
+    pub const AF31: Dscp = Dscp(26);
+    pub const AF32: Dscp = Dscp(28);
+    pub const AF33: Dscp = Dscp(30);
+    pub const AF41: Dscp = Dscp(34);
+    pub const AF42: Dscp = Dscp(36);
+    pub const AF43: Dscp = Dscp(38);
+    pub const EF: Dscp = Dscp(46);
+}
+
+#[allow(dead_code)]
+impl Ipv4HeaderPrelude {
+    pub fn dscp_class(&self) -> Dscp {
+        Dscp(self.dscp & 0b11_1111)
+    }
+
+    pub fn ecn(&self) -> Ecn {
+        Ecn::from(self.ecn)
+    }
+
+    pub fn set_dscp_class(&mut self, dscp: Dscp) {
+        self.dscp = dscp.0 & 0b11_1111;
+    }
+
+    pub fn set_ecn(&mut self, ecn: Ecn) {
+        self.ecn = ecn.into();
+    }
+}
+
+#[test]
+fn test_dscp_and_ecn() {
+    // EF with CE, as seen on the wire
+    let (_, prelude) = parse_header_prelude(&[0x45, 0xbb]).unwrap();
+    assert_eq!(prelude.dscp_class(), Dscp::EF);
+    assert_eq!(prelude.dscp_class().0, 0x2e);
+    assert_eq!(prelude.ecn(), Ecn::CongestionEncountered);
+
+    let mut prelude = Ipv4HeaderPrelude { version: 4, header_length: 5, dscp: 0, ecn: 0 };
+    assert_eq!(prelude.dscp_class(), Dscp::CS0);
+    assert_eq!(prelude.ecn(), Ecn::NotEct);
+    prelude.set_dscp_class(Dscp::EF);
+    prelude.set_ecn(Ecn::CongestionEncountered);
+    assert_eq!(prelude.serialize(), vec![0x45, 0xbb]);
+
+    prelude.set_ecn(Ecn::Ect0);
+    assert_eq!(prelude.serialize(), vec![0x45, 0xba]);
 }
 
 #[derive(Debug, Clone, Copy, Eq, PartialEq)]
@@ -38,28 +142,106 @@ pub struct Ipv4HeaderFragmentationInfo {
 }
 
 impl Serialize for Ipv4HeaderFragmentationInfo {
-    fn serialize(&self) -> Vec<u8> {
+    fn serialize_into(&self, s: &mut Vec<u8>) {
         let flags_offset = ((self.flags as u16) << 13) | self.offset;
-        return Vec::from(flags_offset.to_be_bytes())
+        s.extend(flags_offset.to_be_bytes());
+    }
+
+    fn serialized_len(&self) -> usize {
+        2
     }
 }
 
-// There are several others, but these are the most common
-#[repr(u8)]
-#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+// bits of the 3-bit flags field, the highest one is reserved
+pub const DONT_FRAGMENT: u8 = 0x2;
+pub const MORE_FRAGMENTS: u8 = 0x1;
+
+#[allow(dead_code)]
+impl Ipv4HeaderFragmentationInfo {
+    pub fn dont_fragment(&self) -> bool {
+        self.flags & DONT_FRAGMENT != 0
+    }
+
+    pub fn more_fragments(&self) -> bool {
+        self.flags & MORE_FRAGMENTS != 0
+    }
+
+    pub fn set_dont_fragment(&mut self, value: bool) {
+        self.set_flag(DONT_FRAGMENT, value);
+    }
+
+    pub fn set_more_fragments(&mut self, value: bool) {
+        self.set_flag(MORE_FRAGMENTS, value);
+    }
+
+    // either not the first fragment, or followed by others
+    pub fn is_fragment(&self) -> bool {
+        self.more_fragments() || self.offset != 0
+    }
+
+    fn set_flag(&mut self, flag: u8, value: bool) {
+        if value {
+            self.flags |= flag;
+        } else {
+            self.flags &= !flag;
+        }
+    }
+}
+
+#[test]
+fn test_fragmentation_flags() {
+    let mut frag_info = Ipv4HeaderFragmentationInfo { flags: 0, offset: 185 };
+    assert!(!frag_info.dont_fragment());
+    assert!(!frag_info.more_fragments());
+
+    frag_info.set_more_fragments(true);
+    assert!(frag_info.more_fragments());
+    assert_eq!(frag_info.serialize(), vec![0x20, 0xb9]);
+
+    frag_info.set_dont_fragment(true);
+    assert!(frag_info.dont_fragment());
+    assert_eq!(frag_info.serialize(), vec![0x60, 0xb9]);
+
+    frag_info.set_more_fragments(false);
+    assert!(!frag_info.more_fragments());
+    assert_eq!(frag_info.serialize(), vec![0x40, 0xb9]);
+
+    frag_info.set_dont_fragment(false);
+    assert_eq!(frag_info.serialize(), vec![0x00, 0xb9]);
+}
+
+#[test]
+fn test_dont_fragment() {
+    // flags and offset of a `ping -M do` request: 0x4000
+    let raw = [69, 0, 0, 84, 65, 118, 64, 0, 64, 1, 229, 50, 10, 0, 0, 0, 10, 0, 0, 1];
+    let (_, header) = parse_ipv4_header(&raw).unwrap();
+    assert_eq!(header.frag_info.flags, DONT_FRAGMENT);
+    assert!(header.frag_info.dont_fragment());
+
+    let raw = [69, 0, 0, 84, 65, 118, 32, 0, 64, 1, 229, 50, 10, 0, 0, 0, 10, 0, 0, 1];
+    let (_, header) = parse_ipv4_header(&raw).unwrap();
+    assert_eq!(header.frag_info.flags, MORE_FRAGMENTS);
+    assert!(!header.frag_info.dont_fragment());
+}
+
+// There are several others, but these are the most common; the rest
+// keep their protocol number so they're written back unchanged
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Hash)]
 pub enum Ipv4HeaderProtocol {
-    Icmp = 1u8,
-    Igmp = 2u8,
-    Tcp = 6u8,
-    Udp = 17u8,
-    Encap = 41u8,
-    Ospf = 89u8,
-    Sctp = 132u8,
-    Unknown,
+    Icmp,
+    Igmp,
+    Tcp,
+    Udp,
+    Encap,
+    Ospf,
+    Sctp,
+    Unknown(u8),
 }
 
 impl Ipv4HeaderProtocol {
-    fn from_u8(protocol: u8) -> Ipv4HeaderProtocol {
+    // lenient, protocols we don't model end up as `Unknown`; `try_from`
+    // rejects them instead
+    pub fn from_u8(protocol: u8) -> Ipv4HeaderProtocol {
         match protocol {
             1u8 => Ipv4HeaderProtocol::Icmp,
             2u8 => Ipv4HeaderProtocol::Igmp,
@@ -68,12 +250,79 @@ impl Ipv4HeaderProtocol {
             41u8 => Ipv4HeaderProtocol::Encap,
             89u8 => Ipv4HeaderProtocol::Ospf,
             132u8 => Ipv4HeaderProtocol::Sctp,
-            _ => Ipv4HeaderProtocol::Unknown,
+            unknown => Ipv4HeaderProtocol::Unknown(unknown),
         }
     }
 }
 
-#[derive(Eq, PartialEq, Clone, Copy)]
+impl TryFrom<u8> for Ipv4HeaderProtocol {
+    type Error = IcmpTuntapError;
+
+    fn try_from(protocol: u8) -> Result<Self, Self::Error> {
+        match Ipv4HeaderProtocol::from_u8(protocol) {
+            Ipv4HeaderProtocol::Unknown(_) => Err(IcmpTuntapError::UnsupportedProtocol),
+            known => Ok(known),
+        }
+    }
+}
+
+impl From<Ipv4HeaderProtocol> for u8 {
+    fn from(protocol: Ipv4HeaderProtocol) -> Self {
+        match protocol {
+            Ipv4HeaderProtocol::Icmp => 1u8,
+            Ipv4HeaderProtocol::Igmp => 2u8,
+            Ipv4HeaderProtocol::Tcp => 6u8,
+            Ipv4HeaderProtocol::Udp => 17u8,
+            Ipv4HeaderProtocol::Encap => 41u8,
+            Ipv4HeaderProtocol::Ospf => 89u8,
+            Ipv4HeaderProtocol::Sctp => 132u8,
+            Ipv4HeaderProtocol::Unknown(unknown) => unknown,
+        }
+    }
+}
+
+// the IANA keyword of the protocol, or its number when we don't know it
+impl fmt::Display for Ipv4HeaderProtocol {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Ipv4HeaderProtocol::Icmp => write!(f, "ICMP"),
+            Ipv4HeaderProtocol::Igmp => write!(f, "IGMP"),
+            Ipv4HeaderProtocol::Tcp => write!(f, "TCP"),
+            Ipv4HeaderProtocol::Udp => write!(f, "UDP"),
+            Ipv4HeaderProtocol::Encap => write!(f, "IPv6"),
+            Ipv4HeaderProtocol::Ospf => write!(f, "OSPF"),
+            Ipv4HeaderProtocol::Sctp => write!(f, "SCTP"),
+            Ipv4HeaderProtocol::Unknown(unknown) => write!(f, "protocol {unknown}"),
+        }
+    }
+}
+
+#[test]
+fn test_unknown_protocol_roundtrip() {
+    // a GRE header, GRE isn't one of the protocols we know about
+    let mut bytes = [
+        69, 0, 0, 102, 133, 153, 0, 0, 255, 47, 0, 0, 10, 0, 0, 0, 10, 0, 0, 1
+    ];
+    let checksum = checksum_16(&bytes);
+    bytes[10..12].copy_from_slice(&checksum.to_be_bytes());
+
+    let (_, header) = parse_ipv4_header_strict(&bytes).unwrap();
+    assert_eq!(header.protocol, Ipv4HeaderProtocol::Unknown(47));
+    assert_eq!(u8::from(header.protocol), 47);
+    assert_eq!(header.protocol.to_string(), "protocol 47");
+    assert_eq!(header.serialize(), bytes);
+    assert!(header.verify_checksum());
+}
+
+#[test]
+fn test_protocol_try_from() {
+    assert_eq!(Ipv4HeaderProtocol::try_from(17), Ok(Ipv4HeaderProtocol::Udp));
+    assert_eq!(Ipv4HeaderProtocol::try_from(1), Ok(Ipv4HeaderProtocol::Icmp));
+    assert_eq!(Ipv4HeaderProtocol::try_from(200), Err(IcmpTuntapError::UnsupportedProtocol));
+    assert_eq!(Ipv4HeaderProtocol::from_u8(200), Ipv4HeaderProtocol::Unknown(200));
+}
+
+#[derive(Eq, PartialEq, Clone, Copy, Hash)]
 pub struct Ipv4Address(pub u32);
 
 impl fmt::Display for Ipv4Address {
@@ -90,7 +339,234 @@ impl fmt::Debug for Ipv4Address {
     }
 }
 
-#[derive(Debug, Eq, PartialEq)]
+impl From<Ipv4Addr> for Ipv4Address {
+    fn from(addr: Ipv4Addr) -> Self {
+        Ipv4Address(u32::from(addr))
+    }
+}
+
+impl From<Ipv4Address> for Ipv4Addr {
+    fn from(addr: Ipv4Address) -> Self {
+        Ipv4Addr::from(addr.0)
+    }
+}
+
+impl From<[u8; 4]> for Ipv4Address {
+    fn from(octets: [u8; 4]) -> Self {
+        Ipv4Address(u32::from_be_bytes(octets))
+    }
+}
+
+impl From<(u8, u8, u8, u8)> for Ipv4Address {
+    fn from((a, b, c, d): (u8, u8, u8, u8)) -> Self {
+        Ipv4Address::from([a, b, c, d])
+    }
+}
+
+// same classification as `std::net::Ipv4Addr`, straight on the u32
+#[allow(dead_code)]
+impl Ipv4Address {
+    // RFC 1918: 10.0.0.0/8, 172.16.0.0/12 and 192.168.0.0/16
+    pub fn is_private(&self) -> bool {
+        self.0 & 0xff00_0000 == 0x0a00_0000
+            || self.0 & 0xfff0_0000 == 0xac10_0000
+            || self.0 & 0xffff_0000 == 0xc0a8_0000
+    }
+
+    // 127.0.0.0/8
+    pub fn is_loopback(&self) -> bool {
+        self.0 & 0xff00_0000 == 0x7f00_0000
+    }
+
+    // 224.0.0.0/4
+    pub fn is_multicast(&self) -> bool {
+        self.0 & 0xf000_0000 == 0xe000_0000
+    }
+
+    // 169.254.0.0/16
+    pub fn is_link_local(&self) -> bool {
+        self.0 & 0xffff_0000 == 0xa9fe_0000
+    }
+
+    // the limited broadcast address, 255.255.255.255; directed broadcasts
+    // depend on the subnet
+    pub fn is_broadcast(&self) -> bool {
+        self.0 == u32::MAX
+    }
+}
+
+// accepts dotted quads only ("10.0.0.1"), the error is meant to be shown
+// to the user as is
+impl FromStr for Ipv4Address {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut octets = [0u8; 4];
+        let mut parts = s.split('.');
+        for octet in octets.iter_mut() {
+            let part = parts.next()
+                .ok_or_else(|| format!("invalid IPv4 address '{s}', expected 4 octets"))?;
+            if part.is_empty() || part.len() > 3 || !part.bytes().all(|b| b.is_ascii_digit()) {
+                return Err(format!("invalid IPv4 address '{s}', '{part}' is not a number"));
+            }
+            *octet = part.parse()
+                .map_err(|_| format!("invalid IPv4 address '{s}', octet {part} is out of range"))?;
+        }
+        if parts.next().is_some() {
+            return Err(format!("invalid IPv4 address '{s}', expected 4 octets"));
+        }
+        Ok(Ipv4Address::from(octets))
+    }
+}
+
+// An address block such as 10.0.0.0/8. The base may have host bits set,
+// they are ignored when matching.
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Hash)]
+pub struct Ipv4Cidr {
+    pub base: Ipv4Address,
+    pub prefix_len: u8,
+}
+
+#[allow(dead_code)]
+impl Ipv4Cidr {
+    pub fn new(base: Ipv4Address, prefix_len: u8) -> Ipv4Cidr {
+        Ipv4Cidr { base, prefix_len: prefix_len.min(32) }
+    }
+
+    pub fn mask(&self) -> u32 {
+        // shifting a u32 by 32 overflows, that's the /0 case
+        u32::MAX.checked_shl(32 - self.prefix_len.min(32) as u32).unwrap_or(0)
+    }
+
+    pub fn network(&self) -> Ipv4Address {
+        Ipv4Address(self.base.0 & self.mask())
+    }
+
+    pub fn broadcast(&self) -> Ipv4Address {
+        Ipv4Address(self.base.0 | !self.mask())
+    }
+
+    pub fn contains(&self, addr: Ipv4Address) -> bool {
+        addr.0 & self.mask() == self.network().0
+    }
+}
+
+impl fmt::Display for Ipv4Cidr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.base, self.prefix_len)
+    }
+}
+
+impl FromStr for Ipv4Cidr {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (base, prefix_len) = s.split_once('/')
+            .ok_or_else(|| format!("invalid CIDR '{s}', expected <address>/<prefix length>"))?;
+        let prefix_len = match prefix_len.parse::<u8>() {
+            Ok(len) if len <= 32 && prefix_len.bytes().all(|b| b.is_ascii_digit()) => len,
+            _ => return Err(format!("invalid CIDR '{s}', prefix length must be between 0 and 32")),
+        };
+        Ok(Ipv4Cidr::new(base.parse()?, prefix_len))
+    }
+}
+
+#[test]
+fn test_ipv4_cidr() {
+    let cidr: Ipv4Cidr = "10.0.0.0/8".parse().unwrap();
+    assert!(cidr.contains("10.5.6.7".parse().unwrap()));
+    assert!(!cidr.contains("11.0.0.1".parse().unwrap()));
+    assert_eq!(cidr.network().to_string(), "10.0.0.0");
+    assert_eq!(cidr.broadcast().to_string(), "10.255.255.255");
+    assert_eq!(cidr.to_string(), "10.0.0.0/8");
+
+    let cidr: Ipv4Cidr = "10.0.0.0/16".parse().unwrap();
+    assert!(!cidr.contains("10.5.6.7".parse().unwrap()));
+
+    // host bits in the base don't matter
+    let cidr: Ipv4Cidr = "192.168.1.77/24".parse().unwrap();
+    assert_eq!(cidr.network().to_string(), "192.168.1.0");
+    assert!(cidr.contains("192.168.1.1".parse().unwrap()));
+
+    let everything: Ipv4Cidr = "0.0.0.0/0".parse().unwrap();
+    assert!(everything.contains(Ipv4Address(0)));
+    assert!(everything.contains(Ipv4Address(u32::MAX)));
+    assert_eq!(everything.broadcast(), Ipv4Address(u32::MAX));
+
+    let host: Ipv4Cidr = "10.0.0.1/32".parse().unwrap();
+    assert!(host.contains("10.0.0.1".parse().unwrap()));
+    assert!(!host.contains("10.0.0.2".parse().unwrap()));
+    assert_eq!(host.network(), host.broadcast());
+
+    assert!("10.0.0.0/33".parse::<Ipv4Cidr>().is_err());
+    assert!("10.0.0.0".parse::<Ipv4Cidr>().is_err());
+    assert!("10.0.0/8".parse::<Ipv4Cidr>().is_err());
+    assert!("10.0.0.0/+8".parse::<Ipv4Cidr>().is_err());
+}
+
+#[test]
+fn test_ipv4_address_conversions() {
+    let addr = Ipv4Address::from(Ipv4Addr::new(10, 0, 0, 1));
+    assert_eq!(addr.0, 0x0a000001);
+    assert_eq!(Ipv4Addr::from(addr), Ipv4Addr::new(10, 0, 0, 1));
+    assert_eq!(Ipv4Address::from([10, 0, 0, 1]), addr);
+    assert_eq!(Ipv4Address::from((10, 0, 0, 1)), addr);
+    assert_eq!(addr.to_string(), "10.0.0.1");
+}
+
+#[test]
+fn test_ipv4_address_classification() {
+    let addr = |s: &str| s.parse::<Ipv4Address>().unwrap();
+
+    for private in ["10.1.2.3", "172.16.0.1", "172.31.255.255", "192.168.1.1"] {
+        assert!(addr(private).is_private(), "{private}");
+    }
+    assert!(!addr("172.32.0.1").is_private());
+    assert!(!addr("192.169.0.1").is_private());
+    assert!(addr("127.0.0.1").is_loopback());
+    assert!(addr("224.0.0.251").is_multicast());
+    assert!(addr("239.255.255.250").is_multicast());
+    assert!(!addr("240.0.0.1").is_multicast());
+    assert!(addr("169.254.10.20").is_link_local());
+    assert!(addr("255.255.255.255").is_broadcast());
+    assert!(!addr("10.0.0.255").is_broadcast());
+
+    // the answers std gives
+    for s in ["8.8.8.8", "10.1.2.3", "127.0.0.1", "224.0.0.251", "169.254.10.20", "255.255.255.255"] {
+        let std = Ipv4Addr::from(addr(s));
+        assert_eq!(addr(s).is_private(), std.is_private(), "{s}");
+        assert_eq!(addr(s).is_loopback(), std.is_loopback(), "{s}");
+        assert_eq!(addr(s).is_multicast(), std.is_multicast(), "{s}");
+        assert_eq!(addr(s).is_link_local(), std.is_link_local(), "{s}");
+        assert_eq!(addr(s).is_broadcast(), std.is_broadcast(), "{s}");
+    }
+
+    let public = addr("8.8.8.8");
+    assert!(!public.is_private());
+    assert!(!public.is_loopback());
+    assert!(!public.is_multicast());
+    assert!(!public.is_link_local());
+    assert!(!public.is_broadcast());
+}
+
+#[test]
+fn test_ipv4_address_from_str() {
+    let addr: Ipv4Address = "10.0.0.1".parse().unwrap();
+    assert_eq!(addr, Ipv4Address::from([10, 0, 0, 1]));
+    assert_eq!(addr.to_string().parse::<Ipv4Address>(), Ok(addr));
+    assert_eq!("255.255.255.255".parse::<Ipv4Address>(), Ok(Ipv4Address(u32::MAX)));
+
+    let err = "10.0.0.256".parse::<Ipv4Address>().unwrap_err();
+    assert!(err.contains("out of range"), "{err}");
+    let err = "10.0.1".parse::<Ipv4Address>().unwrap_err();
+    assert!(err.contains("expected 4 octets"), "{err}");
+    assert!("10.0.0.1.5".parse::<Ipv4Address>().is_err());
+    assert!("10..0.1".parse::<Ipv4Address>().is_err());
+    assert!("10.0.0.+1".parse::<Ipv4Address>().is_err());
+    assert!("".parse::<Ipv4Address>().is_err());
+}
+
+#[derive(Debug, Clone, Eq, PartialEq)]
 pub struct Ipv4Header {
     pub prelude: Ipv4HeaderPrelude,    
     pub total_length: u16,
@@ -101,7 +577,159 @@ pub struct Ipv4Header {
     pub checksum: u16,
     pub source: Ipv4Address,
     pub destination: Ipv4Address,
-    pub options: Vec<u8>,
+    pub options: Vec<Ipv4Option>,
+}
+
+// https://www.iana.org/assignments/ip-parameters/ip-parameters.xhtml
+const OPTION_END_OF_LIST: u8 = 0;
+const OPTION_NO_OP: u8 = 1;
+const OPTION_RECORD_ROUTE: u8 = 7;
+const OPTION_TIMESTAMP: u8 = 68;
+
+// what an IHL of 15 leaves for options
+const MAX_OPTIONS_LEN: usize = 40;
+
+#[allow(dead_code)]
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum Ipv4Option {
+    EndOfList,
+    NoOp,
+    RecordRoute {
+        // 1-based offset of the next free slot, counted from the option type
+        pointer: u8,
+        // every slot, including the ones not filled in yet
+        addrs: Vec<Ipv4Address>,
+    },
+    Timestamp {
+        pointer: u8,
+        overflow: u8,
+        flags: u8,
+        // timestamps, or (address, timestamp) pairs depending on the flags
+        entries: Vec<u32>,
+    },
+    Unknown {
+        option_type: u8,
+        data: Vec<u8>,
+    },
+}
+
+impl Serialize for Ipv4Option {
+    fn serialize_into(&self, s: &mut Vec<u8>) {
+        // the length byte of those that have one, which counts the whole
+        // option; no option can be bigger than a header allows anyway
+        let length = self.serialized_len();
+        assert!(length <= MAX_OPTIONS_LEN, "IPv4 option of {length} bytes");
+        let length = length as u8;
+        match self {
+            Ipv4Option::EndOfList => s.push(OPTION_END_OF_LIST),
+            Ipv4Option::NoOp => s.push(OPTION_NO_OP),
+            Ipv4Option::RecordRoute { pointer, addrs } => {
+                s.push(OPTION_RECORD_ROUTE);
+                s.push(length);
+                s.push(*pointer);
+                for addr in addrs {
+                    s.extend(addr.0.to_be_bytes());
+                }
+            },
+            Ipv4Option::Timestamp { pointer, overflow, flags, entries } => {
+                s.push(OPTION_TIMESTAMP);
+                s.push(length);
+                s.push(*pointer);
+                s.push((overflow << 4) | flags);
+                for entry in entries {
+                    s.extend(entry.to_be_bytes());
+                }
+            },
+            Ipv4Option::Unknown { option_type, data } => {
+                s.push(*option_type);
+                s.push(length);
+                s.extend(data);
+            },
+        }
+    }
+
+    fn serialized_len(&self) -> usize {
+        match self {
+            Ipv4Option::EndOfList | Ipv4Option::NoOp => 1,
+            Ipv4Option::RecordRoute { addrs, .. } => 3 + 4 * addrs.len(),
+            Ipv4Option::Timestamp { entries, .. } => 4 + 4 * entries.len(),
+            Ipv4Option::Unknown { data, .. } => 2 + data.len(),
+        }
+    }
+}
+
+// the bytes taken by `options` once padded with zeroes up to a 32-bit
+// boundary; more than a header can hold is a bug in whoever built them
+fn serialized_options_len(options: &[Ipv4Option]) -> usize {
+    let len: usize = options.iter().map(Ipv4Option::serialized_len).sum();
+    assert!(len <= MAX_OPTIONS_LEN, "{len} bytes of IPv4 options");
+    len.next_multiple_of(4)
+}
+
+fn serialize_options_into(options: &[Ipv4Option], s: &mut Vec<u8>) {
+    let start = s.len();
+    for option in options {
+        option.serialize_into(s);
+    }
+    let padded_len = start + serialized_options_len(options);
+    s.resize(padded_len, 0);
+}
+
+fn parse_ipv4_option(input: &[u8]) -> IResult<&[u8], Ipv4Option> {
+    let (input, option_type) = number::complete::be_u8(input)?;
+    match option_type {
+        OPTION_END_OF_LIST => return Ok((input, Ipv4Option::EndOfList)),
+        OPTION_NO_OP => return Ok((input, Ipv4Option::NoOp)),
+        _ => (),
+    }
+
+    // the length byte counts the type and length bytes themselves
+    let (input, length) = number::complete::be_u8(input)?;
+    if length < 2 {
+        return Err(nom::Err::Failure(Error::new(input, ErrorKind::LengthValue)));
+    }
+    let (input, body) = bytes::complete::take(length - 2)(input)?;
+
+    let option = match option_type {
+        OPTION_RECORD_ROUTE if body.len() % 4 == 1 => {
+            let addrs = body[1..].chunks(4)
+                .map(|addr| Ipv4Address(u32::from_be_bytes([addr[0], addr[1], addr[2], addr[3]])))
+                .collect();
+            Ipv4Option::RecordRoute { pointer: body[0], addrs }
+        },
+
+        OPTION_TIMESTAMP if body.len() % 4 == 2 => {
+            let entries = body[2..].chunks(4)
+                .map(|entry| u32::from_be_bytes([entry[0], entry[1], entry[2], entry[3]]))
+                .collect();
+            Ipv4Option::Timestamp {
+                pointer: body[0],
+                overflow: body[1] >> 4,
+                flags: body[1] & 0x0f,
+                entries,
+            }
+        },
+
+        _ => Ipv4Option::Unknown { option_type, data: Vec::from(body) },
+    };
+
+    Ok((input, option))
+}
+
+// parses the options region of the header, stopping at End of Option List
+// (whatever comes after it is just padding)
+pub fn parse_ipv4_options(mut input: &[u8]) -> IResult<&[u8], Vec<Ipv4Option>> {
+    let mut options = Vec::new();
+    while !input.is_empty() {
+        let (rest, option) = parse_ipv4_option(input)?;
+        input = rest;
+        let end_of_list = option == Ipv4Option::EndOfList;
+        options.push(option);
+        if end_of_list {
+            break;
+        }
+    }
+    Ok((&[], options))
 }
 
 fn parse_version_and_header_length(input: &[u8]) -> IResult<&[u8], (u8, u8)> {
@@ -152,7 +780,16 @@ fn parse_header_prelude(input: &[u8]) -> IResult<&[u8], Ipv4HeaderPrelude> {
 }
 
 pub fn parse_ipv4_header(input: &[u8]) -> IResult<&[u8], Ipv4Header> {
+    let original_input = input;
     let (input, prelude) = parse_header_prelude(input)?;
+    // most likely an IPv6 packet that ended up here
+    if prelude.version != 4 {
+        return Err(nom::Err::Failure(Error::new(original_input, ErrorKind::Tag)));
+    }
+    // the header is at least 5 words long, anything shorter is malformed
+    if prelude.header_length < 5 {
+        return Err(nom::Err::Failure(Error::new(original_input, ErrorKind::LengthValue)));
+    }
     // big endian fields
     let (input, total_length) = number::streaming::be_u16(input)?;
     let (input, identification) = number::streaming::be_u16(input)?;
@@ -163,11 +800,18 @@ pub fn parse_ipv4_header(input: &[u8]) -> IResult<&[u8], Ipv4Header> {
     let (input, source) = number::streaming::be_u32(input)?;
     let (input, destination) = number::streaming::be_u32(input)?;
 
-    // options field is not empty
-    let options_bytecount = max(0, (prelude.header_length - 5) * 4);
-    let (input, options) = bytes::streaming::take(options_bytecount)(input)?;
+    // up to 40 bytes of options, which have to fit both in the packet
+    // and in what we were given
+    let options_bytecount = (prelude.header_length - 5) * 4;
+    if prelude.header_length as u16 * 4 > total_length {
+        return Err(nom::Err::Failure(Error::new(original_input, ErrorKind::TooLarge)));
+    }
+    if input.len() < options_bytecount as usize {
+        return Err(nom::Err::Failure(Error::new(original_input, ErrorKind::Eof)));
+    }
+    let (input, options) = bytes::complete::take(options_bytecount)(input)?;
+    let (_, options) = parse_ipv4_options(options)?;
 
-    // TODO: we purposefully ignore the options field for now
     Ok((input, Ipv4Header {
         prelude,
         total_length,
@@ -178,29 +822,172 @@ pub fn parse_ipv4_header(input: &[u8]) -> IResult<&[u8], Ipv4Header> {
         checksum,
         source: Ipv4Address(source),
         destination: Ipv4Address(destination),
-        options: Vec::from(options),
+        options,
     }))
 }
 
-impl Serialize for Ipv4Header {
-    fn serialize(&self) -> Vec<u8> {
-        let mut s: Vec<u8> = Vec::new();
+impl Deserialize for Ipv4Header {
+    fn deserialize(input: &[u8]) -> IResult<&[u8], Self> {
+        parse_ipv4_header(input)
+    }
+}
 
-        let version_ihl = self.prelude.serialize();
-        let frag_info = self.frag_info.serialize();
+// Same as `parse_ipv4_header`, but fails with `ErrorKind::Verify` when
+// the header checksum doesn't match
+pub fn parse_ipv4_header_strict(input: &[u8]) -> IResult<&[u8], Ipv4Header> {
+    let (rest, header) = parse_ipv4_header(input)?;
+    // summing the raw bytes saves serializing the header again
+    if !checksum_valid(&input[..input.len() - rest.len()]) {
+        return Err(nom::Err::Failure(Error::new(input, ErrorKind::Verify)));
+    }
+    Ok((rest, header))
+}
 
-        s.extend(version_ihl);
+impl Serialize for Ipv4Header {
+    fn serialize_into(&self, s: &mut Vec<u8>) {
+        self.prelude.serialize_into(s);
         s.extend(self.total_length.to_be_bytes());
         s.extend(self.identification.to_be_bytes());
-        s.extend(frag_info);
+        self.frag_info.serialize_into(s);
         s.push(self.ttl);
-        s.push(self.protocol as u8);
+        s.push(self.protocol.into());
         s.extend(self.checksum.to_be_bytes());
         s.extend(self.source.0.to_be_bytes());
         s.extend(self.destination.0.to_be_bytes());
-        s.extend(&self.options);
+        serialize_options_into(&self.options, s);
+    }
+
+    fn serialized_len(&self) -> usize {
+        IPV4_MIN_HEADER_LEN + serialized_options_len(&self.options)
+    }
+}
+
+#[allow(dead_code)]
+impl Ipv4Header {
+    // a plain header without options for `payload_len` bytes of payload:
+    // TTL 64, not fragmented, identification 0. The checksum is left at
+    // 0 for `update_checksum`, after whatever else gets changed.
+    pub fn new(source: Ipv4Address, destination: Ipv4Address, protocol: Ipv4HeaderProtocol, payload_len: usize) -> Ipv4Header {
+        Ipv4Header {
+            prelude: Ipv4HeaderPrelude {
+                version: 4,
+                header_length: 5,
+                dscp: 0,
+                ecn: 0,
+            },
+            total_length: (IPV4_MIN_HEADER_LEN + payload_len) as u16,
+            identification: 0,
+            frag_info: Ipv4HeaderFragmentationInfo { flags: 0, offset: 0 },
+            ttl: 64,
+            protocol,
+            checksum: 0,
+            source,
+            destination,
+            options: Vec::new(),
+        }
+    }
+
+    pub fn is_for_us(&self, local: Ipv4Address) -> bool {
+        self.destination == local
+    }
+
+    // decrements the TTL as a router would before forwarding, patching the
+    // checksum incrementally; returns false once the TTL hits zero, in
+    // which case the packet must be dropped (and a Time Exceeded sent)
+    pub fn decrement_ttl(&mut self) -> bool {
+        if self.ttl == 0 {
+            return false;
+        }
 
-        s
+        // TTL shares its 16-bit word with the protocol
+        let old_word = u16::from_be_bytes([self.ttl, self.protocol.into()]);
+        self.ttl -= 1;
+        let new_word = u16::from_be_bytes([self.ttl, self.protocol.into()]);
+        self.checksum = checksum_update(self.checksum, old_word, new_word);
+
+        self.ttl != 0
+    }
+
+    // derives the IHL from the options actually present, so hand-built
+    // headers can't claim a length their options disagree with; options
+    // needing padding get an End of Option List, which is what a parser
+    // reads back from the padded header. The total length follows, for
+    // the same payload as before.
+    pub fn update_header_length(&mut self) {
+        let payload_len = (self.total_length as usize).saturating_sub(self.prelude.header_length as usize * 4);
+        let options_len: usize = self.options.iter().map(Ipv4Option::serialized_len).sum();
+        if !options_len.is_multiple_of(4) && self.options.last() != Some(&Ipv4Option::EndOfList) {
+            self.options.push(Ipv4Option::EndOfList);
+        }
+        self.prelude.header_length = 5 + (serialized_options_len(&self.options) / 4) as u8;
+        self.total_length = (self.prelude.header_length as usize * 4 + payload_len) as u16;
+    }
+
+    pub fn update_checksum(&mut self) {
+        self.update_header_length();
+        self.checksum = 0;
+        self.checksum = checksum_16(&self.serialize());
+    }
+
+    // a header with a correct checksum sums up to zero
+    pub fn verify_checksum(&self) -> bool {
+        checksum_valid(&self.serialize())
+    }
+
+    // counterpart of IcmpPacket::to_json
+    pub fn to_json(&self) -> String {
+        format!("{{\"source\":\"{}\",\"destination\":\"{}\",\"protocol\":\"{:?}\",\"ttl\":{},\"checksum_valid\":{}}}",
+            self.source,
+            self.destination,
+            self.protocol,
+            self.ttl,
+            self.verify_checksum())
+    }
+
+    // names of the fields that differ between the two headers; the
+    // checksum is left out since it changes along with any other field
+    pub fn diff(&self, other: &Ipv4Header) -> Vec<&'static str> {
+        let mut fields = Vec::new();
+        if self.prelude.version != other.prelude.version {
+            fields.push("version");
+        }
+        if self.prelude.header_length != other.prelude.header_length {
+            fields.push("header_length");
+        }
+        if self.prelude.dscp != other.prelude.dscp {
+            fields.push("dscp");
+        }
+        if self.prelude.ecn != other.prelude.ecn {
+            fields.push("ecn");
+        }
+        if self.total_length != other.total_length {
+            fields.push("total_length");
+        }
+        if self.identification != other.identification {
+            fields.push("identification");
+        }
+        if self.frag_info.flags != other.frag_info.flags {
+            fields.push("flags");
+        }
+        if self.frag_info.offset != other.frag_info.offset {
+            fields.push("fragment_offset");
+        }
+        if self.ttl != other.ttl {
+            fields.push("ttl");
+        }
+        if self.protocol != other.protocol {
+            fields.push("protocol");
+        }
+        if self.source != other.source {
+            fields.push("source");
+        }
+        if self.destination != other.destination {
+            fields.push("destination");
+        }
+        if self.options != other.options {
+            fields.push("options");
+        }
+        fields
     }
 }
 
@@ -223,39 +1010,334 @@ fn test_ip_header_serialization() {
     assert_eq!(raw, header.serialize().as_slice());
 }
 
+#[test]
+fn test_ip_header_to_json() {
+    let raw = [
+        69, 0, 0, 84, 65, 118, 64, 0, 64, 1, 229, 50, 10, 0, 0, 0, 10, 0, 0, 1
+    ];
+
+    let (_, header) = parse_ipv4_header(&raw).unwrap();
+    assert_eq!(header.to_json(),
+        r#"{"source":"10.0.0.0","destination":"10.0.0.1","protocol":"Icmp","ttl":64,"checksum_valid":true}"#);
+}
+
+#[test]
+fn test_ip_header_record_route_option() {
+    let raw = [
+        72,                 // Version number and IHL (8 words)
+        0, 0, 32, 133, 153, 0, 0, 64, 1, 0, 0, 10, 0, 0, 0, 10, 0, 0, 1,
+        7,                  // Record Route
+        11,                 // Option length
+        8,                  // Pointer (second slot is next)
+        10, 0, 0, 254,      // First recorded hop
+        0, 0, 0, 0,         // Empty slot
+        0,                  // End of Option List
+    ];
+
+    let (rest, header) = parse_ipv4_header(&raw).unwrap();
+    assert!(rest.is_empty());
+    assert_eq!(header.options, vec![
+        Ipv4Option::RecordRoute {
+            pointer: 8,
+            addrs: vec![Ipv4Address(0x0a0000fe), Ipv4Address(0)],
+        },
+        Ipv4Option::EndOfList,
+    ]);
+    assert_eq!(raw, header.serialize().as_slice());
+    assert_eq!(header.serialized_len(), raw.len());
+
+    // options get padded to a 4 byte boundary on their own
+    let mut padded = Vec::new();
+    serialize_options_into(&[Ipv4Option::NoOp], &mut padded);
+    assert_eq!(padded, vec![1, 0, 0, 0]);
+    assert_eq!(serialized_options_len(&[Ipv4Option::NoOp]), 4);
+
+    // a length running past the options region is an error
+    assert!(parse_ipv4_options(&[7, 12, 4, 0]).is_err());
+}
+
+#[test]
+fn test_ip_header_wrong_version() {
+    let raw = [
+        101,                // Version number (6) and IHL
+        0, 0, 102, 133, 153, 0, 0, 255, 17, 74, 242, 10, 0, 0, 0, 224, 0, 0, 251
+    ];
+
+    match parse_ipv4_header(&raw) {
+        Err(nom::Err::Failure(e)) => assert_eq!(e.code, ErrorKind::Tag),
+        other => panic!("expected a version error, got {:?}", other),
+    }
+}
+
+#[test]
+fn test_ip_header_length_too_short() {
+    let raw = [
+        68,                 // Version number and IHL (4 words, too short)
+        0, 0, 102, 133, 153, 0, 0, 255, 17, 74, 242, 10, 0, 0, 0, 224, 0, 0, 251
+    ];
+
+    match parse_ipv4_header(&raw) {
+        Err(nom::Err::Failure(e)) => assert_eq!(e.code, ErrorKind::LengthValue),
+        other => panic!("expected a header length error, got {:?}", other),
+    }
+}
+
+#[test]
+fn test_ip_header_options_bounds() {
+    // IHL of 6: exactly 4 bytes of options
+    let raw = [
+        70, 0, 0, 24, 0, 1, 0, 0, 64, 1, 0, 0, 10, 0, 0, 0, 10, 0, 0, 1,
+        1, 1, 1, 0,         // NoOp, NoOp, NoOp, EndOfList
+    ];
+    let (rest, header) = parse_ipv4_header(&raw).unwrap();
+    assert!(rest.is_empty());
+    assert_eq!(header.options, [Ipv4Option::NoOp, Ipv4Option::NoOp, Ipv4Option::NoOp, Ipv4Option::EndOfList]);
+
+    // IHL of 15 (40 bytes of options), with only 4 of them there
+    let mut truncated = raw;
+    truncated[0] = 79;
+    truncated[3] = 60;
+    match parse_ipv4_header(&truncated) {
+        Err(nom::Err::Failure(e)) => assert_eq!(e.code, ErrorKind::Eof),
+        other => panic!("expected a clean error, got {:?}", other),
+    }
+    assert_eq!(Ipv4Packet::parse(&truncated).unwrap_err(), IcmpTuntapError::TooShort);
+
+    // all 40 bytes there, but the packet is said to be shorter than that
+    let mut too_long = truncated.to_vec();
+    too_long.resize(60, 1);
+    too_long[3] = 40;
+    assert_eq!(Ipv4Packet::parse(&too_long).unwrap_err(), IcmpTuntapError::BadHeaderLength);
+    too_long[3] = 60;
+    assert!(parse_ipv4_packet(&too_long).is_ok());
+
+    // the biggest options there are say so in their length byte
+    let record_route = Ipv4Option::RecordRoute { pointer: 4, addrs: vec![Ipv4Address(0); 9] };
+    let unknown = Ipv4Option::Unknown { option_type: 0x99, data: vec![0; 38] };
+    for option in [record_route, unknown] {
+        let bytes = option.serialize();
+        assert_eq!(bytes[1] as usize, bytes.len());
+        assert_eq!(option.serialized_len(), bytes.len());
+    }
+}
+
+#[test]
+#[should_panic(expected = "IPv4 option of 1023 bytes")]
+fn test_ip_header_option_too_long() {
+    // far more than the length byte can count
+    Ipv4Option::RecordRoute { pointer: 4, addrs: vec![Ipv4Address(0); 255] }.serialize();
+}
+
+#[test]
+fn test_ip_header_verify_checksum() {
+    let mut raw = [
+        69, 0, 0, 102, 133, 153, 0, 0, 255, 17, 74, 242, 10, 0, 0, 0, 224, 0, 0, 251
+    ];
+
+    let (_, header) = parse_ipv4_header(&raw).unwrap();
+    assert!(header.verify_checksum());
+    assert!(parse_ipv4_header_strict(&raw).is_ok());
+
+    // bump the TTL without fixing the checksum
+    raw[8] = 254;
+    let (_, header) = parse_ipv4_header(&raw).unwrap();
+    assert!(!header.verify_checksum());
+    match parse_ipv4_header_strict(&raw) {
+        Err(nom::Err::Failure(e)) => assert_eq!(e.code, ErrorKind::Verify),
+        other => panic!("expected a checksum failure, got {:?}", other),
+    }
+}
+
 #[allow(dead_code)]
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct Ipv4Packet {
     pub header: Ipv4Header,
     pub data: Vec<u8>,
 }
 
+// takes the payload following `header`, as much as `total_length` says:
+// anything past it (e.g. link layer padding, or the next packet) isn't
+// part of the packet and is left for the caller
+fn parse_ipv4_payload<'a>(input: &'a [u8], rest: &'a [u8], header: Ipv4Header)
+    -> IResult<&'a [u8], Ipv4PacketRef<'a>> {
+    let header_len = input.len() - rest.len();
+    let payload_len = match (header.total_length as usize).checked_sub(header_len) {
+        Some(payload_len) => payload_len,
+        None => return Err(nom::Err::Failure(Error::new(input, ErrorKind::LengthValue))),
+    };
+    if rest.len() < payload_len {
+        return Err(nom::Err::Failure(Error::new(input, ErrorKind::Eof)));
+    }
+
+    let (data, rest) = rest.split_at(payload_len);
+    Ok((rest, Ipv4PacketRef { header, data }))
+}
+
+pub fn parse_ipv4_packet_ref(input: &[u8]) -> IResult<&[u8], Ipv4PacketRef<'_>> {
+    let (rest, header) = parse_ipv4_header(input)?;
+    parse_ipv4_payload(input, rest, header)
+}
+
 pub fn parse_ipv4_packet(input: &[u8]) -> IResult<&[u8], Ipv4Packet>
 {
-    let (rest, header) = parse_ipv4_header(input)?;
-    let packet = Ipv4Packet {
-        header,
-        data: Vec::from(rest),
-    };
+    let (rest, packet) = parse_ipv4_packet_ref(input)?;
+    Ok((rest, packet.to_owned()))
+}
+
+impl Deserialize for Ipv4Packet {
+    fn deserialize(input: &[u8]) -> IResult<&[u8], Self> {
+        parse_ipv4_packet(input)
+    }
+}
+
+// Same as `parse_ipv4_packet`, but rejects headers with a bad checksum
+pub fn parse_ipv4_packet_strict(input: &[u8]) -> IResult<&[u8], Ipv4Packet>
+{
+    let (rest, packet) = parse_ipv4_packet_ref_strict(input)?;
+    Ok((rest, packet.to_owned()))
+}
+
+pub fn parse_ipv4_packet_ref_strict(input: &[u8]) -> IResult<&[u8], Ipv4PacketRef<'_>> {
+    let (rest, header) = parse_ipv4_header_strict(input)?;
+    parse_ipv4_payload(input, rest, header)
+}
 
-    Ok((&[], packet))
+// Parses every packet in `input`, for devices that deliver several
+// datagrams in one read. A packet that fails to parse is skipped using
+// its `total_length` when that looks sane, otherwise there's no telling
+// where the next one starts and the rest of the buffer is dropped. So is
+// anything that isn't IPv4, whose length would be somewhere else.
+pub fn parse_all_ipv4(mut input: &[u8]) -> Vec<Ipv4Packet> {
+    let mut packets = Vec::new();
+    while !input.is_empty() {
+        if input[0] >> 4 != 4 {
+            warn!("Last {} bytes of the buffer aren't IPv4, discarding", input.len());
+            break;
+        }
+        match parse_ipv4_packet(input) {
+            Ok((rest, packet)) => {
+                packets.push(packet);
+                input = rest;
+            },
+            Err(e) => {
+                let total_length = match input.get(2..4) {
+                    Some(bytes) => u16::from_be_bytes([bytes[0], bytes[1]]) as usize,
+                    None => 0,
+                };
+                if total_length < IPV4_MIN_HEADER_LEN || total_length > input.len() {
+                    warn!("Last {} bytes of the buffer don't parse ({}), discarding", input.len(), IcmpTuntapError::from(e));
+                    break;
+                }
+                warn!("Packet of {total_length} bytes doesn't parse ({}), skipping", IcmpTuntapError::from(e));
+                input = &input[total_length..];
+            },
+        }
+    }
+    packets
+}
+
+// Borrowing counterpart of `Ipv4Packet`: the payload points into the
+// parsed buffer, so parsing doesn't copy it around
+#[derive(Debug)]
+pub struct Ipv4PacketRef<'a> {
+    pub header: Ipv4Header,
+    pub data: &'a [u8],
+}
+
+#[allow(dead_code)]
+impl<'a> Ipv4PacketRef<'a> {
+    // parses a packet, verifying its header checksum
+    pub fn parse(input: &'a [u8]) -> Result<Ipv4PacketRef<'a>, IcmpTuntapError> {
+        let (_, packet) = parse_ipv4_packet_ref_strict(input)?;
+        Ok(packet)
+    }
+
+    // same as `parse`, leaving the header checksum to the caller
+    pub fn parse_unchecked(input: &'a [u8]) -> Result<Ipv4PacketRef<'a>, IcmpTuntapError> {
+        let (_, packet) = parse_ipv4_packet_ref(input)?;
+        Ok(packet)
+    }
+
+    pub fn to_owned(&self) -> Ipv4Packet {
+        Ipv4Packet {
+            header: self.header.clone(),
+            data: self.data.to_vec(),
+        }
+    }
+}
+
+impl<'a> From<&'a Ipv4Packet> for Ipv4PacketRef<'a> {
+    fn from(packet: &'a Ipv4Packet) -> Self {
+        Ipv4PacketRef {
+            header: packet.header.clone(),
+            data: &packet.data,
+        }
+    }
+}
+
+impl Serialize for Ipv4PacketRef<'_> {
+    fn serialize_into(&self, s: &mut Vec<u8>) {
+        self.header.serialize_into(s);
+        s.extend(self.data);
+    }
+
+    fn serialized_len(&self) -> usize {
+        self.header.serialized_len() + self.data.len()
+    }
+
+    fn serialize_to<W: io::Write>(&self, w: &mut W) -> io::Result<()> {
+        self.header.serialize_to(w)?;
+        w.write_all(self.data)
+    }
 }
 
 #[allow(dead_code)]
 impl Ipv4Packet {
+    // parses a packet, verifying its header checksum
+    pub fn parse(input: &[u8]) -> Result<Ipv4Packet, IcmpTuntapError> {
+        let (_, packet) = parse_ipv4_packet_strict(input)?;
+        Ok(packet)
+    }
+
+    // like `parse`, also returning whatever follows the packet in `input`
+    pub fn parse_with_rest(input: &[u8]) -> Result<(Ipv4Packet, &[u8]), IcmpTuntapError> {
+        let (rest, packet) = parse_ipv4_packet_strict(input)?;
+        Ok((packet, rest))
+    }
+
     pub fn update_checksum(&mut self) {
-        self.header.checksum = 0;
-        let raw_data: Vec<u8> = self.header.serialize().to_vec();
-        self.header.checksum = checksum_16(&raw_data);
+        self.header.update_checksum();
+    }
+
+    pub fn checksum_is_valid(&self) -> bool {
+        // the checksum lives at bytes 10..12 of the header, zero it
+        // on a copy instead of mutating ourselves
+        let mut raw_data = self.header.serialize();
+        raw_data[10] = 0;
+        raw_data[11] = 0;
+        checksum_16(&raw_data) == self.header.checksum
     }
 }
 
+#[test]
+fn test_ipv4_packet_checksum_is_valid() {
+    use crate::util::PING_REQUEST;
+
+    let bytes = PING_REQUEST;
+
+    let (_, mut packet) = parse_ipv4_packet(&bytes).unwrap();
+    assert!(packet.checksum_is_valid());
+
+    packet.header.checksum ^= 0x0100;
+    assert!(!packet.checksum_is_valid());
+}
+
 #[test]
 fn test_ipv4_packet_checksum() {
+    use crate::util::PING_REQUEST;
+
     // random ICMP packet from a linux ping
-    let bytes = [
-        69, 0, 0, 84, 65, 118, 64, 0, 64, 1, 229, 50, 10, 0, 0, 0, 10, 0, 0, 1, 8, 0, 91, 182, 0, 9, 0, 2, 16, 36, 158, 100, 0, 0, 0, 0, 46, 227, 0, 0, 0, 0, 0, 0, 16, 17, 18, 19, 20, 21, 22, 23, 24, 25, 26, 27, 28, 29, 30, 31, 32, 33, 34, 35, 36, 37, 38, 39, 40, 41, 42, 43, 44, 45, 46, 47, 48, 49, 50, 51, 52, 53, 54, 55
-    ];
+    let bytes = PING_REQUEST;
 
     let (_, mut packet) = parse_ipv4_packet(&bytes).unwrap();
     eprintln!("{:?}", packet);
@@ -266,21 +1348,379 @@ fn test_ipv4_packet_checksum() {
     assert_eq!(checksum, packet.header.checksum);
 }
 
+// Hands out the identification of the datagrams we send one after the
+// other, wrapping around, which keeps captures easy to follow. Starting
+// from a random value keeps a restart from reusing recent ones.
+#[derive(Debug, Clone)]
+pub struct IdentificationSequence(u16);
+
+impl IdentificationSequence {
+    pub fn new(start: u16) -> IdentificationSequence {
+        IdentificationSequence(start)
+    }
+
+    pub fn next_id(&mut self) -> u16 {
+        let id = self.0;
+        self.0 = self.0.wrapping_add(1);
+        id
+    }
+}
+
+#[test]
+fn test_identification_sequence() {
+    let mut ids = IdentificationSequence::new(u16::MAX - 1);
+    assert_eq!(ids.next_id(), u16::MAX - 1);
+    assert_eq!(ids.next_id(), u16::MAX);
+    assert_eq!(ids.next_id(), 0);
+}
+
+// Builds outgoing packets, filling in the fields derived from the others
+// (total length, header length, checksum). Defaults to an ICMP packet
+// with a TTL of 64 and identification 0.
+#[allow(dead_code)]
+#[derive(Debug, Clone)]
+pub struct Ipv4PacketBuilder {
+    source: Ipv4Address,
+    destination: Ipv4Address,
+    protocol: Ipv4HeaderProtocol,
+    ttl: u8,
+    identification: u16,
+    payload: Vec<u8>,
+}
+
+impl Default for Ipv4PacketBuilder {
+    fn default() -> Self {
+        Ipv4PacketBuilder {
+            source: Ipv4Address(0),
+            destination: Ipv4Address(0),
+            protocol: Ipv4HeaderProtocol::Icmp,
+            ttl: 64,
+            identification: 0,
+            payload: Vec::new(),
+        }
+    }
+}
+
+#[allow(dead_code)]
+impl Ipv4PacketBuilder {
+    pub fn new() -> Ipv4PacketBuilder {
+        Ipv4PacketBuilder::default()
+    }
+
+    pub fn source(mut self, source: Ipv4Address) -> Ipv4PacketBuilder {
+        self.source = source;
+        self
+    }
+
+    pub fn destination(mut self, destination: Ipv4Address) -> Ipv4PacketBuilder {
+        self.destination = destination;
+        self
+    }
+
+    pub fn protocol(mut self, protocol: Ipv4HeaderProtocol) -> Ipv4PacketBuilder {
+        self.protocol = protocol;
+        self
+    }
+
+    pub fn ttl(mut self, ttl: u8) -> Ipv4PacketBuilder {
+        self.ttl = ttl;
+        self
+    }
+
+    pub fn identification(mut self, identification: u16) -> Ipv4PacketBuilder {
+        self.identification = identification;
+        self
+    }
+
+    pub fn payload(mut self, payload: Vec<u8>) -> Ipv4PacketBuilder {
+        self.payload = payload;
+        self
+    }
+
+    pub fn build(self) -> Ipv4Packet {
+        let mut header = Ipv4Header::new(self.source, self.destination, self.protocol, self.payload.len());
+        header.identification = self.identification;
+        header.ttl = self.ttl;
+        header.update_checksum();
+        Ipv4Packet { header, data: self.payload }
+    }
+}
+
+#[test]
+fn test_ipv4_header_new() {
+    let source = Ipv4Address::from([10, 0, 0, 1]);
+    let destination = Ipv4Address::from([10, 0, 0, 0]);
+    let mut header = Ipv4Header::new(source, destination, Ipv4HeaderProtocol::Icmp, 64);
+    assert_eq!(header.prelude.version, 4);
+    assert_eq!(header.prelude.header_length, 5);
+    assert_eq!(header.total_length, 84);
+    assert_eq!(header.ttl, 64);
+    assert_eq!(header.checksum, 0);
+    assert!(!header.verify_checksum());
+
+    header.update_checksum();
+    assert!(header.verify_checksum());
+    let bytes = header.serialize();
+    let (rest, parsed) = parse_ipv4_header_strict(&bytes).unwrap();
+    assert!(rest.is_empty());
+    assert_eq!(parsed, header);
+}
+
+#[test]
+fn test_ipv4_packet_builder() {
+    let payload = vec![0, 0, 99, 182, 0, 9, 0, 2];
+
+    let mut manual = Ipv4Packet {
+        header: Ipv4Header {
+            prelude: Ipv4HeaderPrelude {
+                version:4,
+                header_length: 5,
+                dscp: 0,
+                ecn: 0,
+            },
+            total_length: 20 + payload.len() as u16,
+            identification: 0,
+            frag_info: Ipv4HeaderFragmentationInfo { flags: 0, offset: 0 },
+            ttl: 255,
+            protocol: Ipv4HeaderProtocol::Icmp,
+            checksum: 0,
+            source: Ipv4Address(0x0a000001),
+            destination: Ipv4Address(0x0a000000),
+            options: Vec::new(),
+        },
+        data: payload.clone(),
+    };
+    manual.update_checksum();
+
+    let built = Ipv4PacketBuilder::new()
+        .source(Ipv4Address(0x0a000001))
+        .destination(Ipv4Address(0x0a000000))
+        .protocol(Ipv4HeaderProtocol::Icmp)
+        .ttl(255)
+        .payload(payload)
+        .build();
+
+    assert_eq!(manual.header.serialize(), built.header.serialize());
+    assert_eq!(manual.serialize(), built.serialize());
+    assert!(built.checksum_is_valid());
+
+    let defaults = Ipv4PacketBuilder::new().build();
+    assert_eq!(defaults.header.ttl, 64);
+    assert_eq!(defaults.header.identification, 0);
+    assert_eq!(defaults.header.total_length, 20);
+}
+
 impl Serialize for Ipv4Packet {
-    fn serialize(&self) -> Vec<u8> {
-        let mut s = Vec::new();
-        s.extend(&self.header.serialize());
+    fn serialize_into(&self, s: &mut Vec<u8>) {
+        self.header.serialize_into(s);
         s.extend(&self.data);
-        s
+    }
+
+    fn serialized_len(&self) -> usize {
+        self.header.serialized_len() + self.data.len()
+    }
+
+    fn serialize_to<W: io::Write>(&self, w: &mut W) -> io::Result<()> {
+        self.header.serialize_to(w)?;
+        w.write_all(&self.data)
     }
 }
 
 #[test]
 fn test_ipv4_packet_serialization() {
+    use crate::util::count_allocations;
+
     let bytes = [
         69, 0, 0, 84, 117, 212, 64, 0, 64, 1, 176, 212, 10, 0, 0, 0, 10, 0, 0, 1, 8, 0, 251, 37, 0, 14, 0, 3, 175, 92, 158, 100, 0, 0, 0, 0, 231, 52, 9, 0, 0, 0, 0, 0, 16, 17, 18, 19, 20, 21, 22, 23, 24, 25, 26, 27, 28, 29, 30, 31, 32, 33, 34, 35, 36, 37, 38, 39, 40, 41, 42, 43, 44, 45, 46, 47, 48, 49, 50, 51, 52, 53, 54, 55
     ];
 
     let (_, packet) = parse_ipv4_packet(&bytes).unwrap();
     assert_eq!(&bytes, packet.serialize().as_slice());
+
+    // serialize_into appends, leaving whatever was in the buffer alone
+    let mut buf = vec![0xaa, 0xbb];
+    packet.serialize_into(&mut buf);
+    assert_eq!(&buf[..2], &[0xaa, 0xbb]);
+    assert_eq!(&buf[2..], &bytes);
+
+    let mut buf = vec![0xaa, 0xbb];
+    Ipv4PacketRef::from(&packet).serialize_into(&mut buf);
+    assert_eq!(&buf[2..], &bytes);
+
+    // a buffer with room for the packet is reused without allocating,
+    // whether or not the header carries options
+    let mut with_options = packet.clone();
+    with_options.header.options.push(Ipv4Option::RecordRoute { pointer: 4, addrs: vec![Ipv4Address(0); 2] });
+    with_options.update_checksum();
+    let mut buf = Vec::with_capacity(with_options.serialized_len());
+    for packet in [&packet, &with_options] {
+        buf.clear();
+        let ((), allocations) = count_allocations(|| packet.header.serialize_into(&mut buf));
+        assert_eq!(allocations, 0);
+        buf.clear();
+        let ((), allocations) = count_allocations(|| packet.serialize_into(&mut buf));
+        assert_eq!(allocations, 0);
+        assert_eq!(buf, packet.serialize());
+    }
+}
+
+#[test]
+fn test_update_header_length() {
+    let mut packet = Ipv4PacketBuilder::new().payload(vec![0; 8]).build();
+    // a 3 byte option, attached without touching the IHL
+    packet.header.options.push(Ipv4Option::Unknown { option_type: 0x99, data: vec![1] });
+    packet.update_checksum();
+
+    assert_eq!(packet.header.prelude.header_length, 6);
+    assert_eq!(packet.header.total_length, 32);
+    let header = packet.header.serialize();
+    assert_eq!(header.len(), 24);
+    assert_eq!(&header[20..], &[0x99, 3, 1, 0]);
+
+    let (_, parsed) = parse_ipv4_packet_strict(&packet.serialize()).unwrap();
+    assert_eq!(parsed.header, packet.header);
+
+    // already consistent headers are left alone
+    let mut header = packet.header.clone();
+    header.update_header_length();
+    assert_eq!(header, packet.header);
+}
+
+#[test]
+fn test_ipv4_packet_parse_errors() {
+    use crate::util::PING_REQUEST;
+
+    let bytes = PING_REQUEST;
+    assert!(Ipv4Packet::parse(&bytes).is_ok());
+
+    assert_eq!(Ipv4Packet::parse(&bytes[..12]).unwrap_err(), IcmpTuntapError::TooShort);
+
+    let mut corrupted = bytes;
+    corrupted[8] = 1;
+    assert_eq!(Ipv4Packet::parse(&corrupted).unwrap_err(), IcmpTuntapError::BadChecksum);
+
+    let mut ipv6 = bytes;
+    ipv6[0] = 0x65;
+    assert_eq!(Ipv4Packet::parse(&ipv6).unwrap_err(), IcmpTuntapError::UnsupportedVersion);
+}
+
+#[test]
+fn test_ipv4_packet_total_length() {
+    use crate::util::PING_REQUEST;
+
+    let bytes = PING_REQUEST;
+
+    // trailing padding isn't part of the payload
+    let mut padded = bytes.to_vec();
+    padded.extend([0u8; 6]);
+    let (rest, packet) = parse_ipv4_packet(&padded).unwrap();
+    assert_eq!(packet.data.len(), 64);
+    assert_eq!(packet.serialize(), bytes);
+    assert_eq!(rest, [0u8; 6]);
+
+    // a buffer shorter than total_length is an error
+    match parse_ipv4_packet(&bytes[..80]) {
+        Err(nom::Err::Failure(e)) => assert_eq!(e.code, ErrorKind::Eof),
+        other => panic!("expected a truncation error, got {:?}", other),
+    }
+    assert_eq!(Ipv4Packet::parse(&bytes[..80]).unwrap_err(), IcmpTuntapError::TooShort);
+}
+
+#[test]
+fn test_ipv4_packet_parse_rest() {
+    let first = Ipv4PacketBuilder::new().identification(1).payload(vec![1; 8]).build().serialize();
+    let second = Ipv4PacketBuilder::new().identification(2).payload(vec![2; 16]).build().serialize();
+    let mut buf = first.clone();
+    buf.extend(&second);
+
+    let (rest, packet) = parse_ipv4_packet(&buf).unwrap();
+    assert_eq!(packet.header.identification, 1);
+    assert_eq!(rest, second);
+    let (rest, packet) = parse_ipv4_packet(rest).unwrap();
+    assert_eq!(packet.header.identification, 2);
+    assert_eq!(packet.data, [2; 16]);
+    assert!(rest.is_empty());
+
+    let (packet, rest) = Ipv4Packet::parse_with_rest(&buf).unwrap();
+    assert_eq!(packet.serialize(), first);
+    assert_eq!(rest, second);
+}
+
+#[test]
+fn test_parse_all_ipv4() {
+    use crate::util::PING_REQUEST;
+
+    let request = PING_REQUEST;
+    let mut second = request;
+    second[5] = 0x77;
+
+    let mut buf = request.to_vec();
+    buf.extend(second);
+    let packets = parse_all_ipv4(&buf);
+    assert_eq!(packets.len(), 2);
+    assert_eq!(packets[0].serialize(), request);
+    assert_eq!(packets[1].serialize(), second);
+
+    // one that doesn't parse is skipped over
+    let mut corrupted = request;
+    corrupted[0] = 0x44;
+    let mut buf = request.to_vec();
+    buf.extend(corrupted);
+    buf.extend(second);
+    let packets = parse_all_ipv4(&buf);
+    assert_eq!(packets.len(), 2);
+    assert_eq!(packets[1].header.identification, 0x4177);
+
+    // IPv6 has its length elsewhere, nothing after it can be found
+    let mut buf = request.to_vec();
+    buf.extend(crate::icmpv6::ECHO_REQUEST);
+    buf.extend(second);
+    assert_eq!(parse_all_ipv4(&buf).len(), 1);
+
+    // a truncated one at the end is dropped
+    let mut buf = request.to_vec();
+    buf.extend(&second[..30]);
+    assert_eq!(parse_all_ipv4(&buf).len(), 1);
+    assert!(parse_all_ipv4(&[]).is_empty());
+}
+
+#[test]
+fn test_ipv4_packet_serialize_to() {
+    use crate::icmp::IcmpPacket;
+
+    let icmp_packet = IcmpPacket::echo_request(3, 4, &[0xab; 48]);
+    let packet = Ipv4PacketBuilder::new().payload(icmp_packet.serialize()).build();
+
+    let mut written = Vec::new();
+    packet.serialize_to(&mut written).unwrap();
+    assert_eq!(written, packet.serialize());
+    Ipv4PacketRef::from(&packet).serialize_to(&mut written).unwrap();
+    assert_eq!(written[packet.serialized_len()..], packet.serialize());
+
+    let mut written = Vec::new();
+    icmp_packet.serialize_to(&mut written).unwrap();
+    assert_eq!(written, icmp_packet.serialize());
+
+    // the header types go through the default implementation
+    let mut written = Vec::new();
+    packet.header.serialize_to(&mut written).unwrap();
+    assert_eq!(written, packet.header.serialize());
+}
+
+#[test]
+fn test_ipv4_packet_ref() {
+    use crate::util::{count_allocations, PING_REQUEST};
+
+    let bytes = PING_REQUEST;
+
+    let (packet, allocations) = count_allocations(|| Ipv4PacketRef::parse(&bytes).unwrap());
+    assert_eq!(allocations, 0);
+    assert_eq!(packet.data, &bytes[20..]);
+
+    let (owned, allocations) = count_allocations(|| Ipv4Packet::parse(&bytes).unwrap());
+    assert!(allocations > 0);
+
+    assert_eq!(packet.serialize(), owned.serialize());
+    assert_eq!(packet.to_owned().serialize(), bytes);
+    assert_eq!(Ipv4PacketRef::from(&owned).serialize(), bytes);
 }
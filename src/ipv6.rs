@@ -0,0 +1,198 @@
+use std::fmt;
+use std::net::Ipv6Addr;
+
+use nom::error::{Error, ErrorKind};
+use nom::{bytes, number, IResult};
+
+use crate::consts::IPV6_HEADER_LEN;
+use crate::error::IcmpTuntapError;
+use crate::util::{Deserialize, Serialize};
+
+// RFC 8200, just the fixed header: we only answer ICMPv6 echo requests,
+// packets with extension headers in front of the ICMPv6 message aren't
+// looked into
+
+// https://www.iana.org/assignments/protocol-numbers/protocol-numbers.xhtml
+pub const NEXT_HEADER_ICMPV6: u8 = 58;
+
+#[derive(Eq, PartialEq, Clone, Copy, Hash)]
+pub struct Ipv6Address(pub u128);
+
+#[allow(dead_code)]
+impl Ipv6Address {
+    // ff00::/8
+    pub fn is_multicast(&self) -> bool {
+        self.0 >> 120 == 0xff
+    }
+}
+
+// the usual text form with the longest run of zeroes compressed (RFC
+// 5952), which std already knows how to write
+impl fmt::Display for Ipv6Address {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", Ipv6Addr::from(self.0))
+    }
+}
+
+impl fmt::Debug for Ipv6Address {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", Ipv6Addr::from(self.0))
+    }
+}
+
+impl From<Ipv6Addr> for Ipv6Address {
+    fn from(addr: Ipv6Addr) -> Self {
+        Ipv6Address(u128::from(addr))
+    }
+}
+
+impl From<[u8; 16]> for Ipv6Address {
+    fn from(octets: [u8; 16]) -> Self {
+        Ipv6Address(u128::from_be_bytes(octets))
+    }
+}
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Ipv6Header {
+    pub version: u8,
+    pub traffic_class: u8,
+    // 20 bits
+    pub flow_label: u32,
+    // what follows the header, extension headers included
+    pub payload_length: u16,
+    pub next_header: u8,
+    pub hop_limit: u8,
+    pub source: Ipv6Address,
+    pub destination: Ipv6Address,
+}
+
+impl Serialize for Ipv6Header {
+    fn serialize_into(&self, s: &mut Vec<u8>) {
+        let first_word = (self.version as u32) << 28
+            | (self.traffic_class as u32) << 20
+            | self.flow_label & 0xfffff;
+        s.extend(first_word.to_be_bytes());
+        s.extend(self.payload_length.to_be_bytes());
+        s.push(self.next_header);
+        s.push(self.hop_limit);
+        s.extend(self.source.0.to_be_bytes());
+        s.extend(self.destination.0.to_be_bytes());
+    }
+
+    fn serialized_len(&self) -> usize {
+        IPV6_HEADER_LEN
+    }
+}
+
+// fails with `ErrorKind::Tag` for anything but IPv6, like
+// `parse_ipv4_header` does for anything but IPv4
+pub fn parse_ipv6_header(input: &[u8]) -> IResult<&[u8], Ipv6Header> {
+    let (rest, first_word) = number::complete::be_u32(input)?;
+    let version = (first_word >> 28) as u8;
+    if version != 6 {
+        return Err(nom::Err::Failure(Error::new(input, ErrorKind::Tag)));
+    }
+    let (rest, payload_length) = number::complete::be_u16(rest)?;
+    let (rest, next_header) = number::complete::be_u8(rest)?;
+    let (rest, hop_limit) = number::complete::be_u8(rest)?;
+    let (rest, source) = number::complete::be_u128(rest)?;
+    let (rest, destination) = number::complete::be_u128(rest)?;
+
+    Ok((rest, Ipv6Header {
+        version,
+        traffic_class: (first_word >> 20) as u8,
+        flow_label: first_word & 0xfffff,
+        payload_length,
+        next_header,
+        hop_limit,
+        source: Ipv6Address(source),
+        destination: Ipv6Address(destination),
+    }))
+}
+
+impl Deserialize for Ipv6Header {
+    fn deserialize(input: &[u8]) -> IResult<&[u8], Self> {
+        parse_ipv6_header(input)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Ipv6Packet {
+    pub header: Ipv6Header,
+    pub data: Vec<u8>,
+}
+
+impl Serialize for Ipv6Packet {
+    fn serialize_into(&self, s: &mut Vec<u8>) {
+        self.header.serialize_into(s);
+        s.extend(&self.data);
+    }
+
+    fn serialized_len(&self) -> usize {
+        self.header.serialized_len() + self.data.len()
+    }
+}
+
+// takes as much payload as `payload_length` says, anything past it is
+// left for the caller
+pub fn parse_ipv6_packet(input: &[u8]) -> IResult<&[u8], Ipv6Packet> {
+    let (rest, header) = parse_ipv6_header(input)?;
+    let (rest, data) = bytes::complete::take(header.payload_length as usize)(rest)?;
+    Ok((rest, Ipv6Packet { header, data: data.to_vec() }))
+}
+
+impl Deserialize for Ipv6Packet {
+    fn deserialize(input: &[u8]) -> IResult<&[u8], Self> {
+        parse_ipv6_packet(input)
+    }
+}
+
+impl Ipv6Packet {
+    // there's no header checksum in IPv6, the upper layers cover the
+    // addresses with their pseudo-header instead
+    pub fn parse(input: &[u8]) -> Result<Ipv6Packet, IcmpTuntapError> {
+        let (_, packet) = parse_ipv6_packet(input)?;
+        Ok(packet)
+    }
+}
+
+#[test]
+fn test_ipv6_packet() {
+    use crate::util::roundtrip;
+
+    // `ping -6 -c 1 fd42::1` from fd42::2, captured on a TUN device
+    let bytes = [
+        0x60, 0x03, 0xf3, 0x33,     // Version, traffic class, flow label
+        0, 24,                      // Payload length
+        58,                         // Next header (ICMPv6)
+        64,                         // Hop limit
+        0xfd, 0x42, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 2,
+        0xfd, 0x42, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1,
+        128, 0, 0xd6, 0x7c, 0x7b, 0x2d, 0, 1,
+        0xfd, 0x80, 0xd2, 0x6a, 0, 0, 0, 0, 0x5f, 0x8d, 0x04, 0, 0, 0, 0, 0,
+    ];
+
+    let packet: Ipv6Packet = roundtrip(&bytes);
+    assert_eq!(packet.header.version, 6);
+    assert_eq!(packet.header.traffic_class, 0x00);
+    assert_eq!(packet.header.flow_label, 0x3f333);
+    assert_eq!(packet.header.next_header, NEXT_HEADER_ICMPV6);
+    assert_eq!(packet.header.hop_limit, 64);
+    assert_eq!(packet.header.source.to_string(), "fd42::2");
+    assert_eq!(packet.header.destination, Ipv6Address::from("fd42::1".parse::<Ipv6Addr>().unwrap()));
+    assert_eq!(packet.data, bytes[40..]);
+
+    // padding after the payload isn't part of the packet
+    let mut padded = bytes.to_vec();
+    padded.extend([0; 4]);
+    let (rest, _) = parse_ipv6_packet(&padded).unwrap();
+    assert_eq!(rest, [0; 4]);
+
+    assert_eq!(Ipv6Packet::parse(&bytes[..60]).unwrap_err(), IcmpTuntapError::TooShort);
+    let mut ipv4 = bytes;
+    ipv4[0] = 0x45;
+    assert_eq!(Ipv6Packet::parse(&ipv4).unwrap_err(), IcmpTuntapError::UnsupportedVersion);
+
+    assert!(Ipv6Address::from("ff02::1".parse::<Ipv6Addr>().unwrap()).is_multicast());
+    assert!(!packet.header.destination.is_multicast());
+}
@@ -1,94 +1,464 @@
+use std::borrow::Cow;
+use std::fs::File;
+use std::io::{self, BufWriter};
+use std::os::unix::io::AsRawFd;
+use std::process;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use log::{debug, error, info, trace, warn};
 use tun_tap::{Iface, Mode};
 
-use crate::ipv4::{Ipv4HeaderProtocol, Ipv4HeaderPrelude, Ipv4Header, Ipv4Packet, Ipv4HeaderFragmentationInfo};
-use crate::icmp::{parse_icmp_packet, IcmpType, IcmpPacket, IcmpHeader};
-use crate::util::Serialize;
+use crate::consts::{ETHERNET_HEADER_LEN, ETHERTYPE_IPV4, IPV4_MIN_HEADER_LEN, TUN_PREFIX_LEN};
+use crate::eth::MacAddress;
+use crate::fragment::FragmentReassembler;
+use crate::impair::{ImpairConfig, Rng};
+use crate::icmp::EchoIdRewriter;
+use crate::packet_io::PacketIo;
+use crate::ipv4::{parse_all_ipv4, parse_ipv4_packet, IdentificationSequence, Ipv4Address, Ipv4Packet};
+use crate::pcap::{PcapWriter, LINKTYPE_ETHERNET, LINKTYPE_RAW};
+use crate::ratelimit::TokenBucket;
+use crate::responder::{exceeds_mtu, tun_frame, Responder, ResponderConfig, TrustedSources, DEFAULT_MTU};
+use crate::util::{hexdump, Serialize};
 
+mod args;
+mod consts;
+mod error;
 mod util;
 mod ipv4;
+mod eth;
+mod arp;
 mod icmp;
+mod ipv6;
+mod icmpv6;
+mod tunnel;
+mod dump;
+mod fragment;
+mod packet_io;
+mod impair;
+mod ratelimit;
+mod responder;
+mod stats;
+mod shutdown;
+mod pcap;
+mod replay;
+mod ping;
+mod netdev;
+#[cfg(test)]
+mod captures;
+
+// room for the Ethernet header too, in TAP mode
+const fn recv_buffer_len(mtu: usize) -> usize {
+    mtu + TUN_PREFIX_LEN + ETHERNET_HEADER_LEN
+}
+// the MAC address we answer as in TAP mode, a locally administered one
+const TAP_MAC: MacAddress = MacAddress([0x02, 0x00, 0x00, 0x00, 0x00, 0x01]);
+// same as Linux's ipfrag_high_thresh and ipfrag_time defaults
+const REASSEMBLY_BYTE_BUDGET: usize = 4 * 1024 * 1024;
+const REASSEMBLY_TIMEOUT: Duration = Duration::from_secs(30);
+const STATS_INTERVAL: Duration = Duration::from_secs(10);
+// how long to wait for a packet before checking for a shutdown request
+const POLL_TIMEOUT: Duration = Duration::from_millis(500);
+// like ping(8): one request per second, each waiting at most that long
+const PING_INTERVAL: Duration = Duration::from_secs(1);
+
+type Capture = PcapWriter<BufWriter<File>>;
 
 fn main() {
-    let iface = Iface::new("tun0", Mode::Tun).expect("unable to create TUN/TAP device");
-    loop {
-        let mut buf = [0u8; 128];
-        let read = iface.recv(&mut buf).unwrap();
-        eprintln!("read {read} bytes");
-        // eprintln!("raw: {:?}", &buf[0..read]);
+    // everything up to info is shown unless RUST_LOG says otherwise
+    env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("info")).init();
+
+    let args = match args::parse_args(std::env::args().skip(1)) {
+        Ok(args) => args,
+        Err(e) => {
+            eprintln!("{e}");
+            eprintln!("{}", args::USAGE);
+            process::exit(2);
+        },
+    };
 
-        // the TUN frames are as follows:
-        // Flags: 2 bytes (usually 0x0000)
-        // Protocol (layer 3): 2 bytes (0x0800 for IPv4)
-        // Payload
-        let protocol = &buf[2..4];
-        let data = &buf[4..read];
-        eprintln!("protocol: {:?}", protocol);
+    // what follows the TUN prefix, which never makes it to the capture
+    let linktype = match args.mode {
+        Mode::Tun => LINKTYPE_RAW,
+        Mode::Tap => LINKTYPE_ETHERNET,
+    };
+    let mut capture = match &args.pcap {
+        Some(path) => match File::create(path).and_then(|file| PcapWriter::with_linktype(BufWriter::new(file), linktype)) {
+            Ok(capture) => Some(capture),
+            Err(e) => {
+                error!("unable to create capture file {path}: {e}");
+                process::exit(1);
+            },
+        },
+        None => None,
+    };
+
+    // every checksum gets verified, except for the subnets given with --trust
+    let mut trusted = TrustedSources::new();
+    for subnet in args.trust {
+        trusted.add(subnet);
+    }
+    let mut responder = Responder::new(
+        trusted,
+        args.allow,
+        FragmentReassembler::new(REASSEMBLY_BYTE_BUDGET, REASSEMBLY_TIMEOUT));
+    responder.json = args.json;
+    responder.config = ResponderConfig {
+        reply_ttl: args.reply_ttl,
+        source_override: args.reply_source,
+        broadcast_echo: args.broadcast_echo,
+        subnet: args.subnet,
+        local_address: args.addr,
+        forward: args.forward,
+        reply_delay: Duration::from_millis(args.delay_ms),
+        reply_jitter: args.jitter.map(|(min, max)| (Duration::from_millis(min), Duration::from_millis(max))),
+        no_checksum: args.no_checksum,
+        echo_payload: args.echo_payload,
+        mtu_probe: args.mtu_probe.map(|threshold| (threshold, args.advertise_mtu.unwrap_or(threshold))),
+        echo_id_rewriter: (!args.rewrite_id.is_empty()).then(|| {
+            let mut rewriter = EchoIdRewriter::new();
+            for (from, to) in &args.rewrite_id {
+                rewriter.insert(*from, *to);
+            }
+            rewriter
+        }),
+    };
+    if args.no_checksum {
+        warn!("--no-checksum: replies are sent with zeroed IPv4 and ICMP checksums, they're invalid unless something fills them in");
+    }
+    responder.rate_limit = args.rate.map(TokenBucket::new);
+    responder.impair = ImpairConfig { loss: args.loss, corrupt: args.corrupt };
+    // a different pattern on every run
+    responder.rng = Rng::new(SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_nanos() as u64);
+    responder.identification = IdentificationSequence::new(responder.rng.next_u64() as u16);
+    responder.fragment = !args.no_fragment;
+    if args.mode == Mode::Tap {
+        responder.tap_mac = Some(TAP_MAC);
+    }
 
-        if protocol != [0x08, 0x00] {
-            eprintln!("Not an IPv4 packet, discarding");
-            continue;
+    if let Some(path) = &args.replay {
+        if let Err(e) = replay(path, &mut responder, &mut capture, args.count) {
+            error!("unable to replay {path}: {e}");
+            process::exit(1);
         }
+        info!("replay done, stats: {}", responder.stats_summary());
+        return;
+    }
+
+    let mut iface = match Iface::new(&args.iface, args.mode) {
+        Ok(iface) => iface,
+        Err(e) => {
+            error!("unable to create TUN/TAP device {}: {}", args.iface, e);
+            if e.kind() == io::ErrorKind::PermissionDenied {
+                error!("creating the device needs CAP_NET_ADMIN, see run.sh or run_sudo.sh");
+            }
+            process::exit(1);
+        },
+    };
+    if let Err(e) = shutdown::install_handlers() {
+        error!("unable to install signal handlers: {e}");
+        process::exit(1);
+    }
+
+    if let Some(destination) = args.ping {
+        ping(&iface, &mut capture, &mut responder.identification, args.source, destination);
+        drop(iface);
+        return;
+    }
+
+    // sizes the receive buffer and when replies get fragmented
+    responder.mtu = match netdev::get_interface_mtu(iface.name()) {
+        Ok(mtu) => mtu as usize,
+        Err(e) => {
+            warn!("Unable to read the MTU of {} ({e}), assuming {DEFAULT_MTU}", iface.name());
+            DEFAULT_MTU
+        },
+    };
+    info!("listening on {} (MTU {})", iface.name(), responder.mtu);
+    serve(&mut iface, &mut responder, &mut capture, args.count, args.verbose);
 
-        let (_, ip_packet) = ipv4::parse_ipv4_packet(data).unwrap();
-        eprintln!("header: {:?}", ip_packet.header);
+    info!("shutting down, stats: {}", responder.stats_summary());
+    // closes the device, which removes it unless it was made persistent
+    drop(iface);
+}
 
-        if ip_packet.header.protocol != Ipv4HeaderProtocol::Icmp {
-            eprintln!("Not an ICMP packet; discarding");
+// answers whatever comes in until a shutdown is requested, the device
+// goes away or `count` frames were read (0 for no limit)
+fn serve<I: PacketIo>(io: &mut I, responder: &mut Responder, capture: &mut Option<Capture>, count: u64, verbose: bool) {
+    let mut remaining = count;
+    let mut last_summary = Instant::now();
+    let mut buf = vec![0u8; recv_buffer_len(responder.mtu)];
+    // reused for every reply instead of allocating one per packet
+    let mut reply = Vec::with_capacity(buf.len());
+    while !shutdown::requested() {
+        if last_summary.elapsed() >= STATS_INTERVAL {
+            info!("stats: {}", responder.stats_summary());
+            last_summary = Instant::now();
         }
 
-        eprintln!("ICMP packet; trying to parse...");
-        let (_, icmp_packet) = match parse_icmp_packet(&ip_packet.data) {
-            Ok((offset, packet)) => (offset, packet),
-            Err(_) => { eprintln!("Failed to parse packet"); continue; },
+        match io.wait_readable(POLL_TIMEOUT) {
+            Ok(true) => {},
+            Ok(false) => continue,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => panic!("polling the device failed: {e}"),
+        }
+
+        let read = match io.recv(&mut buf) {
+            Ok(0) => { info!("the device was closed"); break; },
+            Ok(read) => read,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => panic!("reading from the device failed: {e}"),
         };
+        debug!("read {read} bytes");
+        if read == buf.len() {
+            warn!("Filled the whole receive buffer, the packet may have been truncated");
+        }
+        trace!("raw:\n{}", hexdump(&buf[0..read]));
+        if read > TUN_PREFIX_LEN {
+            write_capture(capture, &buf[TUN_PREFIX_LEN..read]);
+        }
+        if verbose {
+            print_tree(&buf[..read], responder.link_header_len());
+        }
+
+        for frame in split_frames(&buf[..read], responder) {
+            reply.clear();
+            if responder.handle_packet_into(&frame, &mut reply) && responder.impair_reply(&mut reply) {
+                if verbose {
+                    print_tree(&reply, responder.link_header_len());
+                }
+                // blocks the whole loop, which is fine for playing a slow host
+                let delay = responder.reply_delay();
+                if !delay.is_zero() {
+                    std::thread::sleep(delay);
+                }
+                send_reply(io, capture, responder, &reply);
+            }
+        }
+
+        // every frame read counts, answered or not
+        if count != 0 {
+            remaining -= 1;
+            if remaining == 0 {
+                info!("handled {count} frames, stopping");
+                break;
+            }
+        }
+    }
+}
 
-        eprintln!("{:?}", icmp_packet);
+// prints the IPv4 packet in `frame` as a tree, anything else (ARP,
+// IPv6, short reads) is left out
+fn print_tree(frame: &[u8], link_header_len: usize) {
+    if let Some(Ok((_, packet))) = frame.get(link_header_len..).map(parse_ipv4_packet) {
+        print!("{}", dump::format_tree(&packet));
+    }
+}
 
-        if icmp_packet.header.icmp_type == IcmpType::EchoRequest {
+// runs the frames of a replay file through the responder, printing the
+// replies to stdout instead of sending them anywhere
+fn replay(path: &str, responder: &mut Responder, capture: &mut Option<Capture>, count: u64) -> io::Result<()> {
+    let mut frames = replay::read_frames(File::open(path)?)?;
+    if count != 0 {
+        frames.truncate(count.try_into().unwrap_or(usize::MAX));
+    }
+    info!("replaying {} frames from {path}", frames.len());
 
-            let mut icmp_reply = IcmpPacket {
-                header: IcmpHeader {
-                    checksum: 0,
-                    icmp_type: IcmpType::EchoReply,
-                    code: 0,
-                    data: None,
-                },
-                data: icmp_packet.data,
-            };
-            icmp_reply.update_checksum();
-            let icmp_reply_bytes = icmp_reply.serialize();
-            eprintln!("ICMP reply: {:?}", icmp_reply);
-
-            // TODO: this is the perfect use case for the builder pattern...
-            //       doing it manually is very ugly
-            let mut ip_packet_reply = Ipv4Packet {
-                header: Ipv4Header {
-                    prelude: Ipv4HeaderPrelude {
-                        version:4,
-                        header_length: 5,
-                        dscp: 0,
-                        ecn: 0,
-                    },
-                    total_length: 20 + icmp_reply_bytes.len() as u16,
-                    identification: 0,
-                    frag_info: Ipv4HeaderFragmentationInfo { flags: 0, offset: 0 },
-                    ttl: 255,
-                    protocol: Ipv4HeaderProtocol::Icmp,
-                    checksum: 0,
-                    source: ip_packet.header.destination,
-                    destination: ip_packet.header.source,
-                    options: Vec::new(),
-                },
-                data: icmp_reply_bytes,
+    for frame in frames {
+        if frame.len() > TUN_PREFIX_LEN {
+            write_capture(capture, &frame[TUN_PREFIX_LEN..]);
+        }
+        let reply = match responder.handle_packet(&frame) {
+            Some(reply) => reply,
+            None => continue,
+        };
+        write_capture(capture, &reply[TUN_PREFIX_LEN..]);
+        // our own replies always parse, unless they're ARP (in TAP mode)
+        // or IPv6
+        print_tree(&reply, responder.link_header_len());
+    }
+
+    Ok(())
+}
+
+// sends an echo request every PING_INTERVAL until interrupted, printing
+// the round trip time of each reply
+fn ping(iface: &Iface, capture: &mut Option<Capture>, ids: &mut IdentificationSequence, source: Ipv4Address, destination: Ipv4Address) {
+    // ping(8) uses the pid too, it keeps concurrent instances apart
+    let id = process::id() as u16;
+    // echo replies are small, whatever the MTU
+    let mut buf = [0u8; recv_buffer_len(DEFAULT_MTU)];
+    let (mut sent, mut received) = (0u32, 0u32);
+    let mut routes = ping::RouteCache::new(source);
+    routes.pinging(destination);
+    info!("PING {destination} from {source}: {} data bytes", ping::PING_PAYLOAD_LEN);
+
+    let mut seq: u16 = 1;
+    while !shutdown::requested() {
+        let request = ping::build_echo_request(source, destination, id, seq, ids.next_id()).serialize();
+        if let Some(writer) = capture {
+            if let Err(e) = writer.write_packet(&request) {
+                warn!("unable to write to the capture file: {e}");
+            }
+        }
+        if let Some(gateway) = routes.gateway(destination) {
+            debug!("echo request {seq} to {destination} should go through {gateway}");
+        }
+        let sent_at = Instant::now();
+        // fails until the device is brought up
+        if let Err(e) = iface.send(&tun_frame(&request)) {
+            warn!("unable to send echo request {seq}: {e}");
+        }
+        sent += 1;
+
+        // wait for our reply, skipping anything else that comes in
+        let mut answered = false;
+        while !answered && !shutdown::requested() {
+            let remaining = PING_INTERVAL.saturating_sub(sent_at.elapsed());
+            if remaining.is_zero() {
+                break;
+            }
+            match shutdown::wait_readable(iface.as_raw_fd(), remaining) {
+                Ok(true) => {},
+                Ok(false) => break,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => panic!("polling {} failed: {e}", iface.name()),
+            }
+            let read = match iface.recv(&mut buf) {
+                Ok(read) => read,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => panic!("reading from {} failed: {e}", iface.name()),
             };
-            ip_packet_reply.update_checksum();
+            if read <= TUN_PREFIX_LEN {
+                continue;
+            }
+            write_capture(capture, &buf[TUN_PREFIX_LEN..read]);
+            if let Ok(packet) = Ipv4Packet::parse(&buf[TUN_PREFIX_LEN..read]) {
+                if let Some((redirected, gateway)) = routes.learn(&packet) {
+                    info!("{} redirected us to {gateway} for {redirected}", packet.header.source);
+                }
+                if ping::is_echo_reply_to(&packet, destination, id, seq) {
+                    answered = true;
+                    received += 1;
+                    let rtt = sent_at.elapsed();
+                    println!("{} bytes from {destination}: icmp_seq={seq} ttl={} time={:.3} ms",
+                        packet.data.len(), packet.header.ttl, rtt.as_secs_f64() * 1000.0);
+                }
+            }
+        }
+        if !answered && !shutdown::requested() {
+            println!("no reply from {destination}: icmp_seq={seq}");
+        }
+
+        std::thread::sleep(PING_INTERVAL.saturating_sub(sent_at.elapsed()));
+        seq = seq.wrapping_add(1);
+    }
 
-            // Insert the TUN "header" at the beginning (flags+protocol)
-            let mut reply = vec![0x00, 0x00, 0x08, 0x00];
-            reply.extend(ip_packet_reply.serialize());
+    println!("{sent} packets transmitted, {received} received");
+}
 
-            eprintln!("Sending echo reply: {:?}, {:?}", ip_packet_reply, icmp_reply);
-            iface.send(&reply).unwrap();
+// Some devices deliver several IPv4 datagrams in one read: each one is
+// answered as if it came in a frame of its own. Anything else, a single
+// datagram included, is handled as read.
+fn split_frames<'a>(frame: &'a [u8], responder: &Responder) -> Vec<Cow<'a, [u8]>> {
+    if responder.tap_mac.is_some() || frame.get(2..4) != Some(&ETHERTYPE_IPV4.to_be_bytes()) {
+        return vec![Cow::Borrowed(frame)];
+    }
+    let datagrams = &frame[TUN_PREFIX_LEN..];
+    let first_len = match datagrams.get(2..4) {
+        Some(bytes) => u16::from_be_bytes([bytes[0], bytes[1]]) as usize,
+        None => return vec![Cow::Borrowed(frame)],
+    };
+    if first_len < IPV4_MIN_HEADER_LEN || first_len >= datagrams.len() {
+        return vec![Cow::Borrowed(frame)];
+    }
+    debug!("{} bytes past the first datagram of {first_len}, splitting", datagrams.len() - first_len);
+    parse_all_ipv4(datagrams).iter().map(|packet| Cow::Owned(tun_frame(&packet.serialize()))).collect()
+}
+
+// replies bigger than the MTU (e.g. to a reassembled request) are sent
+// as several fragments, unless fragmenting them was turned off
+fn send_reply<I: PacketIo>(io: &mut I, capture: &mut Option<Capture>, responder: &Responder, frame: &[u8]) {
+    let header_len = responder.link_header_len();
+    if !exceeds_mtu(frame, header_len, responder.mtu) {
+        write_capture(capture, &frame[TUN_PREFIX_LEN..]);
+        io.send(frame).unwrap();
+        return;
+    }
+
+    if !responder.fragment {
+        warn!("Reply of {} bytes exceeds the MTU of {}, dropping", frame.len() - header_len, responder.mtu);
+        return;
+    }
+
+    // ARP replies are tiny, anything this big is IPv4 or IPv6, and we
+    // only fragment the former; its checksum is 0 with --no-checksum
+    let packet = match parse_ipv4_packet(&frame[header_len..]) {
+        Ok((_, packet)) => packet,
+        Err(_) => {
+            warn!("Reply of {} bytes exceeds the MTU of {} and isn't IPv4, dropping", frame.len() - header_len, responder.mtu);
+            return;
+        },
+    };
+    for mut fragment in packet.fragment(responder.mtu) {
+        if responder.config.no_checksum {
+            fragment.header.checksum = 0;
+        }
+        // same link headers as the whole reply
+        let mut fragment_frame = frame[..header_len].to_vec();
+        fragment.serialize_into(&mut fragment_frame);
+        write_capture(capture, &fragment_frame[TUN_PREFIX_LEN..]);
+        io.send(&fragment_frame).unwrap();
+    }
+}
+
+// a broken capture file shouldn't stop us from answering
+fn write_capture(capture: &mut Option<Capture>, packet: &[u8]) {
+    if let Some(writer) = capture {
+        if let Err(e) = writer.write_packet(packet) {
+            warn!("unable to write to the capture file: {e}");
         }
     }
 }
+
+#[test]
+fn test_serve() {
+    use crate::packet_io::MockIo;
+    use crate::util::PING_REQUEST;
+
+    // a `ping 10.0.0.1` request, as read from the TUN device
+    let request = tun_frame(&PING_REQUEST);
+    let new_responder = || Responder::new(
+        TrustedSources::new(),
+        Vec::new(),
+        FragmentReassembler::new(REASSEMBLY_BYTE_BUDGET, REASSEMBLY_TIMEOUT));
+    // replies differ in their identification
+    let mut reference = new_responder();
+    let expected = [reference.handle_packet(&request).unwrap(), reference.handle_packet(&request).unwrap()];
+
+    // garbage in between gets dropped without stopping anything
+    let mut io = MockIo::new([request.clone(), vec![0, 0, 8], request.clone()]);
+    let mut responder = new_responder();
+    serve(&mut io, &mut responder, &mut None, 0, false);
+    assert_eq!(io.sent, expected);
+    assert_eq!(responder.stats.echo_requests, 2);
+    assert_eq!(responder.stats.parse_failures, 1);
+
+    // two datagrams in one read get a reply each, in frames of their own
+    let mut both = request.clone();
+    both.extend(&request[TUN_PREFIX_LEN..]);
+    let mut io = MockIo::new([both]);
+    serve(&mut io, &mut new_responder(), &mut None, 0, false);
+    assert_eq!(io.sent, expected);
+
+    // --count 1 leaves the rest unread
+    let mut io = MockIo::new([request.clone(), request]);
+    serve(&mut io, &mut new_responder(), &mut None, 1, false);
+    assert_eq!(io.sent, expected[..1]);
+    assert_eq!(io.incoming.len(), 1);
+
+    // frames that don't get answered count too
+    let mut io = MockIo::new([vec![0, 0, 8], vec![0, 0, 8], vec![0, 0, 8]]);
+    serve(&mut io, &mut new_responder(), &mut None, 2, false);
+    assert!(io.sent.is_empty());
+    assert_eq!(io.incoming.len(), 1);
+}
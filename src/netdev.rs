@@ -0,0 +1,42 @@
+use std::io;
+use std::net::UdpSocket;
+use std::os::unix::io::AsRawFd;
+
+// Asks the kernel for the MTU of a network interface (e.g. our TUN/TAP
+// device) with the SIOCGIFMTU ioctl. Any socket will do for that, it
+// doesn't have to be bound to the interface.
+pub fn get_interface_mtu(name: &str) -> io::Result<u16> {
+    // SAFETY: ifreq is plain old data, all zeroes is a valid value
+    let mut request: libc::ifreq = unsafe { std::mem::zeroed() };
+    // the name needs a NUL terminator
+    if name.is_empty() || name.len() >= request.ifr_name.len() || name.contains('\0') {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("invalid interface name '{name}'")));
+    }
+    for (dst, src) in request.ifr_name.iter_mut().zip(name.bytes()) {
+        *dst = src as libc::c_char;
+    }
+
+    let socket = UdpSocket::bind("0.0.0.0:0")?;
+    // SAFETY: the socket stays open for the call and `request` is a
+    // valid ifreq the kernel fills in
+    let ret = unsafe { libc::ioctl(socket.as_raw_fd(), libc::SIOCGIFMTU, &mut request) };
+    if ret != 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    // SAFETY: SIOCGIFMTU sets the mtu member of the union
+    let mtu = unsafe { request.ifr_ifru.ifru_mtu };
+    // loopback has 65536, no IPv4 datagram is that big anyway
+    Ok(mtu.clamp(0, u16::MAX as libc::c_int) as u16)
+}
+
+#[test]
+fn test_get_interface_mtu() {
+    // loopback exists in every network namespace
+    let mtu = get_interface_mtu("lo").unwrap();
+    assert!(mtu >= 68, "implausible MTU {mtu} for lo");
+
+    assert_eq!(get_interface_mtu("").unwrap_err().kind(), io::ErrorKind::InvalidInput);
+    assert_eq!(get_interface_mtu("an-interface-name-too-long").unwrap_err().kind(), io::ErrorKind::InvalidInput);
+    assert!(get_interface_mtu("nonexistent0").is_err());
+}
@@ -0,0 +1,88 @@
+#[cfg(test)]
+use std::collections::VecDeque;
+use std::io;
+use std::os::unix::io::AsRawFd;
+use std::time::Duration;
+
+use tun_tap::Iface;
+
+use crate::shutdown;
+
+// Where the frames come from and replies go to: the TUN/TAP device, or
+// an in-memory queue in tests, which don't get to create devices
+pub trait PacketIo {
+    // reads one frame; 0 means there will be no more of them
+    fn recv(&mut self, buf: &mut [u8]) -> io::Result<usize>;
+    fn send(&mut self, data: &[u8]) -> io::Result<usize>;
+
+    // whether a frame can be read without blocking, waiting at most
+    // `timeout` for one
+    fn wait_readable(&mut self, _timeout: Duration) -> io::Result<bool> {
+        Ok(true)
+    }
+}
+
+impl PacketIo for Iface {
+    fn recv(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        Iface::recv(self, buf)
+    }
+
+    fn send(&mut self, data: &[u8]) -> io::Result<usize> {
+        Iface::send(self, data)
+    }
+
+    fn wait_readable(&mut self, timeout: Duration) -> io::Result<bool> {
+        shutdown::wait_readable(self.as_raw_fd(), timeout)
+    }
+}
+
+// hands out the `incoming` frames one by one, then behaves like a
+// closed device; whatever is sent ends up in `sent`
+#[cfg(test)]
+#[derive(Debug, Default)]
+pub struct MockIo {
+    pub incoming: VecDeque<Vec<u8>>,
+    pub sent: Vec<Vec<u8>>,
+}
+
+#[cfg(test)]
+impl MockIo {
+    pub fn new<I: IntoIterator<Item = Vec<u8>>>(incoming: I) -> MockIo {
+        MockIo {
+            incoming: incoming.into_iter().collect(),
+            sent: Vec::new(),
+        }
+    }
+}
+
+#[cfg(test)]
+impl PacketIo for MockIo {
+    fn recv(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let frame = match self.incoming.pop_front() {
+            Some(frame) => frame,
+            None => return Ok(0),
+        };
+        // a device truncates frames that don't fit too
+        let len = frame.len().min(buf.len());
+        buf[..len].copy_from_slice(&frame[..len]);
+        Ok(len)
+    }
+
+    fn send(&mut self, data: &[u8]) -> io::Result<usize> {
+        self.sent.push(data.to_vec());
+        Ok(data.len())
+    }
+}
+
+#[test]
+fn test_mock_io() {
+    let mut mock = MockIo::new([vec![1, 2, 3], vec![4; 8]]);
+    let mut buf = [0u8; 4];
+    assert_eq!(mock.recv(&mut buf).unwrap(), 3);
+    assert_eq!(buf[..3], [1, 2, 3]);
+    assert_eq!(mock.recv(&mut buf).unwrap(), 4);
+    assert_eq!(mock.recv(&mut buf).unwrap(), 0);
+
+    assert_eq!(mock.send(&[5, 6]).unwrap(), 2);
+    assert_eq!(mock.sent, [vec![5, 6]]);
+}
@@ -0,0 +1,134 @@
+use std::io::{self, Write};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::util::Serialize;
+
+// https://wiki.wireshark.org/Development/LibpcapFileFormat
+
+const MAGIC: u32 = 0xa1b2c3d4;
+const VERSION_MAJOR: u16 = 2;
+const VERSION_MINOR: u16 = 4;
+const SNAPLEN: u32 = 65535;
+// the packets start with the IP header
+pub const LINKTYPE_RAW: u32 = 101;
+// the packets start with an Ethernet header, as in TAP mode
+pub const LINKTYPE_ETHERNET: u32 = 1;
+
+// Writes packets in the classic pcap format so they can be opened with
+// Wireshark or tcpdump. Everything is little endian, the magic number
+// tells the readers so.
+#[derive(Debug)]
+pub struct PcapWriter<W: Write> {
+    writer: W,
+}
+
+#[allow(dead_code)]
+impl<W: Write> PcapWriter<W> {
+    pub fn new(writer: W) -> io::Result<PcapWriter<W>> {
+        PcapWriter::with_linktype(writer, LINKTYPE_RAW)
+    }
+
+    pub fn with_linktype(mut writer: W, linktype: u32) -> io::Result<PcapWriter<W>> {
+        let mut header = Vec::with_capacity(24);
+        header.extend(MAGIC.to_le_bytes());
+        header.extend(VERSION_MAJOR.to_le_bytes());
+        header.extend(VERSION_MINOR.to_le_bytes());
+        // GMT offset and timestamp accuracy, always 0 in practice
+        header.extend(0i32.to_le_bytes());
+        header.extend(0u32.to_le_bytes());
+        header.extend(SNAPLEN.to_le_bytes());
+        header.extend(linktype.to_le_bytes());
+        writer.write_all(&header)?;
+        Ok(PcapWriter { writer })
+    }
+
+    pub fn write_packet(&mut self, packet: &[u8]) -> io::Result<()> {
+        self.write_packet_at(packet, SystemTime::now())
+    }
+
+    pub fn write_packet_at(&mut self, packet: &[u8], timestamp: SystemTime) -> io::Result<()> {
+        let captured = &packet[..packet.len().min(SNAPLEN as usize)];
+        let mut record = Vec::with_capacity(16 + captured.len());
+        record_header(&mut record, captured.len(), packet.len(), timestamp);
+        record.extend(captured);
+        self.writer.write_all(&record)?;
+        // keep the file readable while we're still running
+        self.writer.flush()
+    }
+
+    pub fn write_serialized<S: Serialize>(&mut self, packet: &S) -> io::Result<()> {
+        self.write_serialized_at(packet, SystemTime::now())
+    }
+
+    // same as `write_packet_at`, streaming `packet` into the file instead
+    // of serializing it to a buffer first
+    pub fn write_serialized_at<S: Serialize>(&mut self, packet: &S, timestamp: SystemTime) -> io::Result<()> {
+        let len = packet.serialized_len();
+        if len > SNAPLEN as usize {
+            return self.write_packet_at(&packet.serialize(), timestamp);
+        }
+        let mut header = Vec::with_capacity(16);
+        record_header(&mut header, len, len, timestamp);
+        self.writer.write_all(&header)?;
+        packet.serialize_to(&mut self.writer)?;
+        self.writer.flush()
+    }
+
+    pub fn into_inner(self) -> W {
+        self.writer
+    }
+}
+
+// timestamp, then how much of the packet is in the file and how long it
+// really was
+fn record_header(record: &mut Vec<u8>, captured_len: usize, len: usize, timestamp: SystemTime) {
+    let since_epoch = timestamp.duration_since(UNIX_EPOCH).unwrap_or_default();
+    record.extend((since_epoch.as_secs() as u32).to_le_bytes());
+    record.extend(since_epoch.subsec_micros().to_le_bytes());
+    record.extend((captured_len as u32).to_le_bytes());
+    record.extend((len as u32).to_le_bytes());
+}
+
+#[test]
+fn test_pcap_writer() {
+    use std::time::Duration;
+
+    let mut writer = PcapWriter::new(Vec::new()).unwrap();
+    let timestamp = UNIX_EPOCH + Duration::from_micros(1_700_000_000_123_456);
+    writer.write_packet_at(&[0x45; 84], timestamp).unwrap();
+    writer.write_packet_at(&[0x45; 20], timestamp).unwrap();
+    let bytes = writer.into_inner();
+
+    let u32_at = |offset: usize| u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap());
+    assert_eq!(u32_at(0), MAGIC);
+    assert_eq!(bytes[4..8], [2, 0, 4, 0]);
+    assert_eq!(u32_at(16), SNAPLEN);
+    assert_eq!(u32_at(20), LINKTYPE_RAW);
+
+    // first record
+    assert_eq!(u32_at(24), 1_700_000_000);
+    assert_eq!(u32_at(28), 123_456);
+    assert_eq!(u32_at(32), 84);
+    assert_eq!(u32_at(36), 84);
+    // second record, right after the first packet's data
+    let second = 24 + 16 + 84;
+    assert_eq!(u32_at(second + 8), 20);
+    assert_eq!(u32_at(second + 12), 20);
+    assert_eq!(bytes.len(), second + 16 + 20);
+
+    let bytes = PcapWriter::with_linktype(Vec::new(), LINKTYPE_ETHERNET).unwrap().into_inner();
+    assert_eq!(bytes[20..24], LINKTYPE_ETHERNET.to_le_bytes());
+}
+
+#[test]
+fn test_pcap_writer_serialized() {
+    use crate::ipv4::Ipv4PacketBuilder;
+
+    let timestamp = SystemTime::now();
+    let packet = Ipv4PacketBuilder::new().payload(vec![7; 64]).build();
+    let mut written = PcapWriter::new(Vec::new()).unwrap();
+    written.write_packet_at(&packet.serialize(), timestamp).unwrap();
+    let mut streamed = PcapWriter::new(Vec::new()).unwrap();
+    streamed.write_serialized_at(&packet, timestamp).unwrap();
+    assert_eq!(streamed.into_inner(), written.into_inner());
+}
@@ -0,0 +1,161 @@
+use std::collections::{HashMap, HashSet};
+
+#[cfg(test)]
+use crate::consts::{ICMP_HEADER_LEN, IPV4_MIN_HEADER_LEN};
+use crate::icmp::{parse_icmp_packet, IcmpHeaderData, IcmpPacket, IcmpType};
+use crate::ipv4::{Ipv4Address, Ipv4HeaderProtocol, Ipv4Packet, Ipv4PacketBuilder};
+use crate::util::Serialize;
+
+// same payload size as ping(8), 84 bytes on the wire with the headers
+pub const PING_PAYLOAD_LEN: usize = 56;
+// our end of the TUN subnet set up by run.sh (the kernel has 10.0.0.0)
+pub const DEFAULT_PING_SOURCE: [u8; 4] = [10, 0, 0, 1];
+
+pub fn build_echo_request(source: Ipv4Address, destination: Ipv4Address, id: u16, seq: u16, identification: u16) -> Ipv4Packet {
+    let payload: Vec<u8> = (0..PING_PAYLOAD_LEN).map(|i| i as u8).collect();
+    Ipv4PacketBuilder::new()
+        .source(source)
+        .destination(destination)
+        .protocol(Ipv4HeaderProtocol::Icmp)
+        .identification(identification)
+        .payload(IcmpPacket::echo_request(id, seq, &payload).serialize())
+        .build()
+}
+
+// whether `packet` answers the request we sent to `destination`; the
+// id tells our requests apart from other pings, the seq which one it is
+pub fn is_echo_reply_to(packet: &Ipv4Packet, destination: Ipv4Address, id: u16, seq: u16) -> bool {
+    if packet.header.protocol != Ipv4HeaderProtocol::Icmp || packet.header.source != destination {
+        return false;
+    }
+
+    match parse_icmp_packet(&packet.data) {
+        Ok((_, icmp_packet)) => {
+            icmp_packet.header.icmp_type == IcmpType::EchoReply
+                && icmp_packet.echo_id() == Some(id)
+                && icmp_packet.echo_seq() == Some(seq)
+        },
+        Err(_) => false,
+    }
+}
+
+// The gateways ICMP redirects told us to use, by destination, like the
+// route cache of a real host. We have no routing table to change, so
+// it's only there to tell where our requests should be going.
+#[derive(Debug)]
+pub struct RouteCache {
+    // the address we send from
+    source: Ipv4Address,
+    // where we sent requests to, nobody gets to reroute anything else
+    pinged: HashSet<Ipv4Address>,
+    routes: HashMap<Ipv4Address, Ipv4Address>,
+}
+
+impl RouteCache {
+    pub fn new(source: Ipv4Address) -> RouteCache {
+        RouteCache { source, pinged: HashSet::new(), routes: HashMap::new() }
+    }
+
+    pub fn pinging(&mut self, destination: Ipv4Address) {
+        self.pinged.insert(destination);
+    }
+
+    // records the gateway suggested by `packet` for the destination of
+    // the datagram it quotes, returning both; None if it isn't a redirect
+    // about one of our own requests
+    pub fn learn(&mut self, packet: &Ipv4Packet) -> Option<(Ipv4Address, Ipv4Address)> {
+        if packet.header.protocol != Ipv4HeaderProtocol::Icmp {
+            return None;
+        }
+        let (_, icmp_packet) = parse_icmp_packet(&packet.data).ok()?;
+        match icmp_packet.header.data {
+            Some(IcmpHeaderData::Redirect { gateway, ip_header, .. })
+                if ip_header.source == self.source && self.pinged.contains(&ip_header.destination) => {
+                self.routes.insert(ip_header.destination, gateway);
+                Some((ip_header.destination, gateway))
+            },
+            _ => None,
+        }
+    }
+
+    pub fn gateway(&self, destination: Ipv4Address) -> Option<Ipv4Address> {
+        self.routes.get(&destination).copied()
+    }
+}
+
+#[test]
+fn test_build_echo_request() {
+    let source = Ipv4Address::from(DEFAULT_PING_SOURCE);
+    let destination = Ipv4Address::from([10, 0, 0, 0]);
+    let request = build_echo_request(source, destination, 0x1234, 7, 100);
+
+    let ip_packet = Ipv4Packet::parse(&request.serialize()).unwrap();
+    assert_eq!(ip_packet.header.source, source);
+    assert_eq!(ip_packet.header.identification, 100);
+    assert_eq!(ip_packet.header.destination, destination);
+    assert_eq!(ip_packet.header.total_length as usize, IPV4_MIN_HEADER_LEN + ICMP_HEADER_LEN + PING_PAYLOAD_LEN);
+
+    let (_, icmp_packet) = parse_icmp_packet(&ip_packet.data).unwrap();
+    assert_eq!(icmp_packet.header.icmp_type, IcmpType::EchoRequest);
+    assert_eq!(icmp_packet.echo_id(), Some(0x1234));
+    assert_eq!(icmp_packet.echo_seq(), Some(7));
+    assert_eq!(icmp_packet.data.len(), PING_PAYLOAD_LEN);
+}
+
+#[test]
+fn test_is_echo_reply_to() {
+    use crate::responder::ipv4_reply;
+
+    let source = Ipv4Address::from(DEFAULT_PING_SOURCE);
+    let destination = Ipv4Address::from([10, 0, 0, 0]);
+    let request = build_echo_request(source, destination, 0x1234, 7, 100);
+    let (_, icmp_request) = parse_icmp_packet(&request.data).unwrap();
+    let reply = ipv4_reply(&request.header, &icmp_request.echo_reply());
+
+    assert!(is_echo_reply_to(&reply, destination, 0x1234, 7));
+    assert!(!is_echo_reply_to(&reply, destination, 0x1234, 8));
+    assert!(!is_echo_reply_to(&reply, destination, 0x4321, 7));
+    assert!(!is_echo_reply_to(&reply, source, 0x1234, 7));
+    // our own request doesn't count
+    assert!(!is_echo_reply_to(&request, source, 0x1234, 7));
+}
+
+#[test]
+fn test_route_cache() {
+    use crate::responder::{build_redirect, ipv4_reply};
+
+    let source = Ipv4Address::from(DEFAULT_PING_SOURCE);
+    let destination = Ipv4Address::from([192, 168, 1, 7]);
+    let gateway = Ipv4Address::from([10, 0, 0, 254]);
+    let request = build_echo_request(source, destination, 0x1234, 1, 100);
+    // a redirect for the host, as a router would send it
+    let redirect = build_redirect(&request, gateway, 1);
+    let redirect = Ipv4Packet::parse(&redirect.serialize()).unwrap();
+
+    let mut routes = RouteCache::new(source);
+    // only once we're pinging it
+    assert_eq!(routes.learn(&redirect), None);
+    routes.pinging(destination);
+    assert_eq!(routes.gateway(destination), None);
+    assert_eq!(routes.learn(&redirect), Some((destination, gateway)));
+    assert_eq!(routes.gateway(destination), Some(gateway));
+    assert_eq!(routes.gateway(source), None);
+
+    // a later redirect replaces the gateway
+    let other_gateway = Ipv4Address::from([10, 0, 0, 253]);
+    routes.learn(&build_redirect(&request, other_gateway, 1));
+    assert_eq!(routes.gateway(destination), Some(other_gateway));
+
+    // nor do redirects about other hosts' datagrams, or other destinations
+    let other_source = build_echo_request(Ipv4Address::from([10, 0, 0, 9]), destination, 0x1234, 1, 100);
+    assert_eq!(routes.learn(&build_redirect(&other_source, gateway, 1)), None);
+    let other_destination = build_echo_request(source, Ipv4Address::from([192, 168, 1, 8]), 0x1234, 1, 100);
+    assert_eq!(routes.learn(&build_redirect(&other_destination, gateway, 1)), None);
+    assert_eq!(routes.gateway(Ipv4Address::from([192, 168, 1, 8])), None);
+    assert_eq!(routes.gateway(destination), Some(other_gateway));
+
+    // echo replies don't touch the cache
+    let (_, icmp_request) = parse_icmp_packet(&request.data).unwrap();
+    assert_eq!(routes.learn(&ipv4_reply(&request.header, &icmp_request.echo_reply())), None);
+    assert_eq!(routes.gateway(destination), Some(other_gateway));
+}
@@ -0,0 +1,91 @@
+use std::time::{Duration, Instant};
+
+// Token bucket allowing `rate` replies per second on average, in bursts
+// of up to a second's worth. Tokens are kept as the time they took to
+// accumulate, which keeps the arithmetic exact. The clock is passed in
+// so that tests don't depend on timing.
+#[derive(Debug, Clone)]
+pub struct TokenBucket {
+    // the time it takes to earn one token
+    cost: Duration,
+    capacity: Duration,
+    budget: Duration,
+    last_refill: Option<Instant>,
+}
+
+impl TokenBucket {
+    // starts out full, the first burst isn't held back
+    pub fn new(rate: u32) -> TokenBucket {
+        assert!(rate > 0, "a rate of 0 would never allow anything");
+        let capacity = Duration::from_secs(1);
+        TokenBucket {
+            cost: capacity / rate,
+            capacity,
+            budget: capacity,
+            last_refill: None,
+        }
+    }
+
+    // takes a token if there's one left; `now` shouldn't go backwards,
+    // when it does no tokens are earned
+    pub fn allow(&mut self, now: Instant) -> bool {
+        if let Some(last_refill) = self.last_refill {
+            let earned = now.saturating_duration_since(last_refill);
+            self.budget = (self.budget + earned).min(self.capacity);
+        }
+        self.last_refill = Some(now);
+
+        if self.budget < self.cost {
+            return false;
+        }
+        self.budget -= self.cost;
+        true
+    }
+}
+
+#[test]
+fn test_token_bucket() {
+    let start = Instant::now();
+    let at = |millis: u64| start + Duration::from_millis(millis);
+    let mut bucket = TokenBucket::new(10);
+
+    // a full second's worth of burst, then nothing
+    for _ in 0..10 {
+        assert!(bucket.allow(at(0)));
+    }
+    assert!(!bucket.allow(at(0)));
+
+    // a token every 100 ms
+    assert!(!bucket.allow(at(99)));
+    assert!(bucket.allow(at(100)));
+    assert!(!bucket.allow(at(100)));
+    assert!(!bucket.allow(at(150)));
+    assert!(bucket.allow(at(200)));
+
+    // 350 ms earn 3 tokens and half of the next one
+    assert!(bucket.allow(at(550)));
+    assert!(bucket.allow(at(550)));
+    assert!(bucket.allow(at(550)));
+    assert!(!bucket.allow(at(550)));
+    assert!(bucket.allow(at(600)));
+
+    // idling refills the bucket, but not past its capacity
+    let allowed = (0..20).filter(|_| bucket.allow(at(10_000))).count();
+    assert_eq!(allowed, 10);
+
+    // a clock going backwards earns nothing
+    assert!(!bucket.allow(at(5_000)));
+}
+
+#[test]
+fn test_token_bucket_uneven_rate() {
+    let start = Instant::now();
+    let mut bucket = TokenBucket::new(3);
+    for _ in 0..3 {
+        assert!(bucket.allow(start));
+    }
+    assert!(!bucket.allow(start));
+    // 333.33.. ms per token
+    assert!(!bucket.allow(start + Duration::from_millis(333)));
+    assert!(bucket.allow(start + Duration::from_millis(334)));
+}
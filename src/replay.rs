@@ -0,0 +1,39 @@
+use std::io::{self, Read};
+
+// Replay files hold TUN frames (prefix included) back to back, each
+// preceded by its length as a big endian u32:
+//
+// | length (4) | frame (length bytes) | length (4) | frame | ...
+pub fn read_frames<R: Read>(mut reader: R) -> io::Result<Vec<Vec<u8>>> {
+    let mut contents = Vec::new();
+    reader.read_to_end(&mut contents)?;
+
+    let mut frames = Vec::new();
+    let mut rest = contents.as_slice();
+    while !rest.is_empty() {
+        if rest.len() < 4 {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "truncated frame length"));
+        }
+        let (length, after) = rest.split_at(4);
+        let length = u32::from_be_bytes(length.try_into().unwrap()) as usize;
+        if after.len() < length {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof,
+                format!("frame of {length} bytes but only {} left", after.len())));
+        }
+        let (frame, after) = after.split_at(length);
+        frames.push(frame.to_vec());
+        rest = after;
+    }
+
+    Ok(frames)
+}
+
+#[test]
+fn test_read_frames() {
+    let bytes = [0, 0, 0, 2, 1, 2, 0, 0, 0, 0, 0, 0, 0, 3, 3, 4, 5];
+    assert_eq!(read_frames(&bytes[..]).unwrap(), vec![vec![1, 2], vec![], vec![3, 4, 5]]);
+    assert!(read_frames(&[][..]).unwrap().is_empty());
+
+    assert_eq!(read_frames(&bytes[..16]).unwrap_err().kind(), io::ErrorKind::UnexpectedEof);
+    assert_eq!(read_frames(&bytes[..2]).unwrap_err().kind(), io::ErrorKind::UnexpectedEof);
+}
@@ -0,0 +1,1965 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use log::{debug, trace, warn};
+use nom::IResult;
+
+use crate::arp::{arp_reply, parse_arp_packet, ArpPacket};
+use crate::consts::{ETHERNET_HEADER_LEN, ETHERTYPE_ARP, ETHERTYPE_IPV4, ETHERTYPE_IPV6, ICMP_HEADER_LEN, IPV4_MIN_HEADER_LEN, IPV6_HEADER_LEN};
+use crate::consts::{TUN_FLAGS, TUN_PREFIX_IPV4, TUN_PREFIX_IPV6, TUN_PREFIX_LEN};
+use crate::eth::{parse_ethernet_header, EthernetHeader, MacAddress};
+use crate::icmp::{parse_icmp_packet, parse_icmp_packet_ref, parse_icmp_packet_ref_unchecked, parse_icmp_packet_unchecked};
+use crate::icmp::{EchoIdRewriter, EchoPayload, IcmpHeader, IcmpHeaderData, IcmpPacket, IcmpPacketRef, IcmpType, DEST_UNREACHABLE_FRAGMENTATION_NEEDED};
+use crate::icmpv6::{self, Icmpv6Packet, Icmpv6Type};
+use crate::ipv4::{parse_ipv4_packet, IdentificationSequence, Ipv4Address, Ipv4Cidr, Ipv4Header, Ipv4HeaderProtocol, Ipv4Packet, Ipv4PacketBuilder, Ipv4PacketRef};
+use crate::ipv6::{Ipv6Packet, NEXT_HEADER_ICMPV6};
+#[cfg(test)]
+use crate::ipv6::Ipv6Address;
+use crate::error::IcmpTuntapError;
+use crate::fragment::FragmentReassembler;
+use crate::impair::{self, ImpairConfig, Impairment, Rng};
+use crate::ratelimit::TokenBucket;
+use crate::stats::Stats;
+use crate::util::{checksum_valid, Serialize};
+
+// same as Linux's net.ipv4.ip_default_ttl
+pub const DEFAULT_REPLY_TTL: u8 = 64;
+
+// How we answer, and how the IPv4 header of our replies is filled in
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct ResponderConfig {
+    pub reply_ttl: u8,
+    // reply from this address instead of the one the request was sent
+    // to, e.g. when the device has several addresses assigned
+    pub source_override: Option<Ipv4Address>,
+    // answer echo and timestamp requests sent to a broadcast or multicast
+    // address, which turns us into an amplifier for smurf attacks. The
+    // replies come from `local_address` (or `source_override`), without
+    // either they're dropped anyway.
+    pub broadcast_echo: bool,
+    // the subnet of the device, to know its broadcast address
+    pub subnet: Option<Ipv4Cidr>,
+    // our address: ARP requests are answered for it in TAP mode and
+    // packets to other addresses aren't answered. Any address is ours
+    // when not set.
+    pub local_address: Option<Ipv4Address>,
+    // route packets that aren't for us back out (decrementing their TTL)
+    // instead of dropping them
+    pub forward: bool,
+    // how long to hold each echo reply back, to play a slow host
+    pub reply_delay: Duration,
+    // a random extra delay between these two, different for every reply
+    pub reply_jitter: Option<(Duration, Duration)>,
+    // leave the IPv4 and ICMP checksums of our replies at 0, for setups
+    // where the (virtual) NIC is expected to fill them in
+    pub no_checksum: bool,
+    // what to echo back in the payload of echo replies
+    pub echo_payload: EchoPayload,
+    // (threshold, advertised MTU): play a router with a small MTU and
+    // answer every Don't Fragment datagram above the threshold with
+    // "fragmentation needed", to test path MTU discovery
+    pub mtu_probe: Option<(u16, u16)>,
+    // maps the identifier of echo requests to the one of our replies,
+    // like a NAT translating ICMP queries would
+    pub echo_id_rewriter: Option<EchoIdRewriter>,
+}
+
+impl Default for ResponderConfig {
+    fn default() -> Self {
+        ResponderConfig {
+            reply_ttl: DEFAULT_REPLY_TTL,
+            source_override: None,
+            broadcast_echo: false,
+            subnet: None,
+            local_address: None,
+            forward: false,
+            reply_delay: Duration::ZERO,
+            reply_jitter: None,
+            no_checksum: false,
+            echo_payload: EchoPayload::Mirror,
+            mtu_probe: None,
+            echo_id_rewriter: None,
+        }
+    }
+}
+
+impl ResponderConfig {
+    fn apply(&self, reply: &mut Ipv4Packet) {
+        reply.header.ttl = self.reply_ttl;
+        if let Some(source) = self.source_override {
+            reply.header.source = source;
+        }
+        if !self.no_checksum {
+            reply.update_checksum();
+            return;
+        }
+        reply.header.checksum = 0;
+        // the ICMP checksum was computed along with the message
+        if reply.header.protocol == Ipv4HeaderProtocol::Icmp && reply.data.len() >= ICMP_HEADER_LEN {
+            reply.data[2..4].fill(0);
+        }
+    }
+}
+
+// wraps an ICMP reply in an IPv4 packet going back to whoever sent `request`
+pub fn ipv4_reply(request: &Ipv4Header, icmp_reply: &IcmpPacket) -> Ipv4Packet {
+    Ipv4PacketBuilder::new()
+        .source(request.destination)
+        .destination(request.source)
+        .protocol(Ipv4HeaderProtocol::Icmp)
+        .ttl(DEFAULT_REPLY_TTL)
+        .payload(icmp_reply.serialize())
+        .build()
+}
+
+// The ICMP error quotes the offending datagram's header plus the first
+// 8 bytes of its payload
+fn quoted_data(original: &Ipv4Packet) -> Vec<u8> {
+    let len = original.data.len().min(8);
+    original.data[..len].to_vec()
+}
+
+// Builds the ICMP error messages about `original`, quoting it and
+// computing the checksum. Only the error types carrying a quoted
+// datagram are supported, `build` returns None for the others.
+#[derive(Debug, Clone)]
+pub struct IcmpErrorBuilder<'a> {
+    original: &'a Ipv4Packet,
+    icmp_type: IcmpType,
+    code: u8,
+    next_hop_mtu: u16,
+    gateway: Ipv4Address,
+}
+
+impl<'a> IcmpErrorBuilder<'a> {
+    pub fn new(original: &'a Ipv4Packet, icmp_type: IcmpType, code: u8) -> IcmpErrorBuilder<'a> {
+        IcmpErrorBuilder {
+            original,
+            icmp_type,
+            code,
+            next_hop_mtu: 0,
+            gateway: Ipv4Address(0),
+        }
+    }
+
+    // only used by Destination Unreachable
+    pub fn next_hop_mtu(mut self, next_hop_mtu: u16) -> IcmpErrorBuilder<'a> {
+        self.next_hop_mtu = next_hop_mtu;
+        self
+    }
+
+    // only used by Redirect
+    pub fn gateway(mut self, gateway: Ipv4Address) -> IcmpErrorBuilder<'a> {
+        self.gateway = gateway;
+        self
+    }
+
+    pub fn build(self) -> Option<IcmpPacket> {
+        let ip_header = self.original.header.clone();
+        let data = quoted_data(self.original);
+        let header_data = match self.icmp_type {
+            IcmpType::DestinationUnreachable => IcmpHeaderData::DestinationUnreachable {
+                next_hop_mtu: self.next_hop_mtu,
+                ip_header,
+                data,
+                extension: None,
+            },
+            IcmpType::TimeExceeded => IcmpHeaderData::TimeExceeded { ip_header, data, extension: None },
+            IcmpType::RedirectMessage => IcmpHeaderData::Redirect { gateway: self.gateway, ip_header, data },
+            IcmpType::SourceQuench => IcmpHeaderData::SourceQuench { ip_header, data },
+            _ => return None,
+        };
+
+        let mut packet = IcmpPacket {
+            header: IcmpHeader {
+                icmp_type: self.icmp_type,
+                code: self.code,
+                checksum: 0,
+                data: Some(header_data),
+            },
+            data: Vec::new(),
+        };
+        packet.update_checksum();
+        Some(packet)
+    }
+}
+
+// Time Exceeded (TTL expired in transit) for a datagram whose TTL ran out
+pub fn build_time_exceeded(original: &Ipv4Packet) -> IcmpPacket {
+    IcmpErrorBuilder::new(original, IcmpType::TimeExceeded, 0)
+        .build()
+        .expect("Time Exceeded is an error type")
+}
+
+pub const DEST_UNREACHABLE_PROTOCOL: u8 = 2;
+
+// Destination Unreachable sent back to the source of `original`
+pub fn build_dest_unreachable(original: &Ipv4Packet, code: u8) -> Ipv4Packet {
+    build_dest_unreachable_with_mtu(original, code, 0)
+}
+
+fn build_dest_unreachable_with_mtu(original: &Ipv4Packet, code: u8, next_hop_mtu: u16) -> Ipv4Packet {
+    let icmp_packet = IcmpErrorBuilder::new(original, IcmpType::DestinationUnreachable, code)
+        .next_hop_mtu(next_hop_mtu)
+        .build()
+        .expect("Destination Unreachable is an error type");
+    ipv4_reply(&original.header, &icmp_packet)
+}
+
+// RFC 1191 path MTU discovery: a datagram with Don't Fragment set that
+// doesn't fit in `mtu` is answered with "fragmentation needed" and the
+// MTU it has to fit in. None when the datagram can go through.
+pub fn build_fragmentation_needed(original: &Ipv4Packet, mtu: u16) -> Option<Ipv4Packet> {
+    if !original.header.frag_info.dont_fragment() || original.header.total_length <= mtu {
+        return None;
+    }
+    Some(build_dest_unreachable_with_mtu(original, DEST_UNREACHABLE_FRAGMENTATION_NEEDED, mtu))
+}
+
+// Redirect telling the source of `original` to use `better_gateway`
+// for its destination from now on; `code` says whether that applies to
+// the network, the host, or either one for this ToS only
+#[allow(dead_code)]
+pub fn build_redirect(original: &Ipv4Packet, better_gateway: Ipv4Address, code: u8) -> Ipv4Packet {
+    let icmp_packet = IcmpErrorBuilder::new(original, IcmpType::RedirectMessage, code)
+        .gateway(better_gateway)
+        .build()
+        .expect("Redirect is an error type");
+    ipv4_reply(&original.header, &icmp_packet)
+}
+
+// RFC 792 timestamps are milliseconds since midnight UT
+fn milliseconds_since_midnight() -> u32 {
+    let since_epoch = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+    (since_epoch.as_millis() % (24 * 60 * 60 * 1000)) as u32
+}
+
+pub fn build_timestamp_reply(req: &IcmpPacket) -> Option<IcmpPacket> {
+    build_timestamp_reply_at(req, milliseconds_since_midnight())
+}
+
+// we handle the request in a single pass, so the receive and transmit
+// timestamps are one and the same
+fn build_timestamp_reply_at(req: &IcmpPacket, now: u32) -> Option<IcmpPacket> {
+    let (id, seq, originate) = match req.header.data {
+        Some(IcmpHeaderData::Timestamp { id, seq, originate, .. }) => (id, seq, originate),
+        _ => return None,
+    };
+
+    let mut reply = IcmpPacket {
+        header: IcmpHeader {
+            icmp_type: IcmpType::TimestampReply,
+            code: 0,
+            checksum: 0,
+            data: Some(IcmpHeaderData::TimestampReply {
+                id,
+                seq,
+                originate,
+                receive: now,
+                transmit: now,
+            }),
+        },
+        data: Vec::new(),
+    };
+    reply.update_checksum();
+    Some(reply)
+}
+
+// Source subnets whose checksums we don't verify, e.g. a link where the
+// hypervisor already validated (offloaded) them. This is faster, but
+// corrupted packets from these sources get through, so it's opt-in.
+#[derive(Debug, Default)]
+pub struct TrustedSources {
+    subnets: Vec<Ipv4Cidr>,
+}
+
+impl TrustedSources {
+    pub fn new() -> TrustedSources {
+        TrustedSources::default()
+    }
+
+    pub fn add(&mut self, subnet: Ipv4Cidr) {
+        self.subnets.push(subnet);
+    }
+
+    pub fn contains(&self, addr: Ipv4Address) -> bool {
+        self.subnets.iter().any(|subnet| subnet.contains(addr))
+    }
+}
+
+// whether a packet sent to `destination` goes to more than one host: the
+// limited broadcast address, the broadcast address of `subnet` (/31 and
+// /32 don't have one) or a multicast group
+pub fn is_broadcast_or_multicast(destination: Ipv4Address, subnet: Option<Ipv4Cidr>) -> bool {
+    destination.is_broadcast()
+        || subnet.is_some_and(|subnet| subnet.prefix_len < 31 && subnet.broadcast() == destination)
+        || destination.is_multicast()
+}
+
+// RFC 1122 3.2.2: no ICMP error is sent about an ICMP error, a datagram
+// to (or from) a broadcast or multicast address or a fragment other than
+// the first, so that errors can't multiply
+pub fn may_send_error(header: &Ipv4Header, data: &[u8], subnet: Option<Ipv4Cidr>) -> bool {
+    if is_broadcast_or_multicast(header.destination, subnet) || is_broadcast_or_multicast(header.source, subnet) {
+        return false;
+    }
+    if header.frag_info.offset != 0 {
+        return false;
+    }
+    !(header.protocol == Ipv4HeaderProtocol::Icmp && data.first().is_some_and(|&icmp_type| IcmpType::from(icmp_type).is_error()))
+}
+
+// whether we should answer `source` at all, given the subnets passed
+// with --allow; no subnets means everyone is allowed
+pub fn source_allowed(allow: &[Ipv4Cidr], source: Ipv4Address) -> bool {
+    allow.is_empty() || allow.iter().any(|subnet| subnet.contains(source))
+}
+
+// parses the ICMP payload of a packet coming from `source`, only
+// verifying the checksum when the source isn't trusted
+pub fn parse_icmp_packet_from<'a>(source: Ipv4Address, input: &'a [u8], trusted: &TrustedSources)
+    -> IResult<&'a [u8], IcmpPacketRef<'a>> {
+    if trusted.contains(source) {
+        parse_icmp_packet_ref_unchecked(input)
+    } else {
+        parse_icmp_packet_ref(input)
+    }
+}
+
+pub fn packet_json(header: &Ipv4Header, icmp_packet: &IcmpPacket) -> String {
+    format!("{{\"ip\":{},\"icmp\":{}}}", header.to_json(), icmp_packet.to_json())
+}
+
+// the prefix plus an IPv4 header without options, anything shorter is a
+// runt we can't do anything with
+const MIN_FRAME_LEN: usize = TUN_PREFIX_LEN + IPV4_MIN_HEADER_LEN;
+pub const DEFAULT_MTU: usize = 1500;
+
+// whether the packet in a frame is too big to be sent as is, given the
+// length of the headers in front of it
+pub fn exceeds_mtu(frame: &[u8], link_header_len: usize, mtu: usize) -> bool {
+    frame.len() > link_header_len + mtu
+}
+
+// the TUN prefix and Ethernet header of a frame sent in TAP mode
+fn tap_frame_header(frame: &mut Vec<u8>, header: &EthernetHeader) {
+    frame.extend(TUN_FLAGS);
+    frame.extend(header.ethertype.to_be_bytes());
+    header.serialize_into(frame);
+}
+
+// prepends the TUN prefix to a serialized IPv4 packet
+pub fn tun_frame(packet: &[u8]) -> Vec<u8> {
+    let mut frame = TUN_PREFIX_IPV4.to_vec();
+    frame.extend(packet);
+    frame
+}
+
+// Answers the packets of a protocol the responder doesn't handle itself,
+// e.g. a UDP echo; see `Responder::register`
+pub type ProtocolHandler = Box<dyn Fn(&Ipv4Packet) -> Option<Ipv4Packet>>;
+
+enum Handler {
+    // our own ICMP handling, which needs the state of the responder
+    Icmp,
+    Registered(ProtocolHandler),
+}
+
+impl fmt::Debug for Handler {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Handler::Icmp => write!(f, "Icmp"),
+            Handler::Registered(_) => write!(f, "Registered"),
+        }
+    }
+}
+
+// Everything needed to answer the packets read from the device, minus
+// the device itself
+#[derive(Debug)]
+pub struct Responder {
+    pub stats: Stats,
+    // print every ICMP packet we get to stdout, one JSON object per line
+    pub json: bool,
+    // applied to the replies we build, not to those of registered handlers
+    pub config: ResponderConfig,
+    // replies bigger than this are fragmented, or dropped if `fragment`
+    // isn't set
+    pub mtu: usize,
+    pub fragment: bool,
+    // set in TAP mode, where an Ethernet header follows the prefix: the
+    // MAC address we answer as
+    pub tap_mac: Option<MacAddress>,
+    // echo requests over the limit are dropped instead of answered
+    pub rate_limit: Option<TokenBucket>,
+    // echo replies to drop or corrupt on purpose, picked with `rng`
+    pub impair: ImpairConfig,
+    pub rng: Rng,
+    // the identification of our replies, shared with --ping
+    pub identification: IdentificationSequence,
+    trusted: TrustedSources,
+    allow: Vec<Ipv4Cidr>,
+    reassembler: FragmentReassembler,
+    // packets of any other protocol get a Protocol Unreachable
+    handlers: HashMap<Ipv4HeaderProtocol, Handler>,
+    // where the ICMP(v6) message starts in the last reply frame, if it
+    // holds an echo reply: only those get delayed and impaired
+    echo_reply_offset: Option<usize>,
+}
+
+impl Responder {
+    pub fn new(trusted: TrustedSources, allow: Vec<Ipv4Cidr>, reassembler: FragmentReassembler) -> Responder {
+        Responder {
+            stats: Stats::default(),
+            json: false,
+            config: ResponderConfig::default(),
+            mtu: DEFAULT_MTU,
+            fragment: true,
+            tap_mac: None,
+            rate_limit: None,
+            impair: ImpairConfig::default(),
+            rng: Rng::new(0),
+            identification: IdentificationSequence::new(0),
+            trusted,
+            allow,
+            reassembler,
+            handlers: HashMap::from([(Ipv4HeaderProtocol::Icmp, Handler::Icmp)]),
+            echo_reply_offset: None,
+        }
+    }
+
+    // hands the packets of `protocol` to `handler`, replacing whatever
+    // handled them before (our own ICMP handling included)
+    #[allow(dead_code)]
+    pub fn register<F>(&mut self, protocol: Ipv4HeaderProtocol, handler: F)
+    where
+        F: Fn(&Ipv4Packet) -> Option<Ipv4Packet> + 'static,
+    {
+        self.handlers.insert(protocol, Handler::Registered(Box::new(handler)));
+    }
+
+    // takes a raw TUN frame and returns the reply frame to send, if any
+    pub fn handle_packet(&mut self, frame: &[u8]) -> Option<Vec<u8>> {
+        let mut reply = Vec::new();
+        self.handle_packet_into(frame, &mut reply).then_some(reply)
+    }
+
+    // like `handle_packet`, but appends the reply frame to `reply` so the
+    // caller can reuse one buffer; returns whether there is a reply
+    pub fn handle_packet_into(&mut self, frame: &[u8], reply: &mut Vec<u8>) -> bool {
+        // the handlers set it relative to the IP packet
+        self.echo_reply_offset = None;
+        let replied = self.handle_frame(frame, reply);
+        self.echo_reply_offset = match self.echo_reply_offset {
+            Some(offset) if replied => Some(self.link_header_len() + offset),
+            _ => None,
+        };
+        replied
+    }
+
+    fn handle_frame(&mut self, frame: &[u8], reply: &mut Vec<u8>) -> bool {
+        if frame.len() < MIN_FRAME_LEN {
+            warn!("Short read of {} bytes, discarding", frame.len());
+            self.stats.parse_failures += 1;
+            return false;
+        }
+
+        // the TUN frames are as follows:
+        // Flags: 2 bytes (usually 0x0000)
+        // Protocol (layer 3): 2 bytes (0x0800 for IPv4)
+        // Payload
+        // In TAP mode the protocol is the ethertype of the Ethernet frame
+        // making up the payload.
+        let protocol = &frame[2..4];
+        trace!("protocol: {:?}", protocol);
+        if let Some(mac) = self.tap_mac {
+            return self.handle_ethernet_frame(&frame[TUN_PREFIX_LEN..], mac, reply);
+        }
+        match u16::from_be_bytes([protocol[0], protocol[1]]) {
+            ETHERTYPE_IPV4 => {
+                let packet = match self.handle_ipv4_packet(&frame[TUN_PREFIX_LEN..]) {
+                    Some(packet) => packet,
+                    None => return false,
+                };
+                debug!("Sending reply: {:?}", packet);
+                reply.extend(TUN_PREFIX_IPV4);
+                packet.serialize_into(reply);
+            },
+            ETHERTYPE_IPV6 => {
+                let packet = match self.handle_ipv6_packet(&frame[TUN_PREFIX_LEN..]) {
+                    Some(packet) => packet,
+                    None => return false,
+                };
+                debug!("Sending reply: {:?}", packet);
+                reply.extend(TUN_PREFIX_IPV6);
+                packet.serialize_into(reply);
+            },
+            _ => {
+                warn!("Not an IP packet, discarding");
+                self.stats.non_icmp_dropped += 1;
+                return false;
+            },
+        }
+        true
+    }
+
+    // the counters as logged, followed by the configured --delay-ms and
+    // --jitter so that slow replies in the logs aren't mistaken for a
+    // slow host
+    pub fn stats_summary(&self) -> String {
+        let delay = self.config.reply_delay;
+        match self.config.reply_jitter {
+            Some((min, max)) => format!("{}, echo replies delayed by {}-{} ms",
+                self.stats, (delay + min).as_millis(), (delay + max).as_millis()),
+            None if !delay.is_zero() => format!("{}, echo replies delayed by {} ms", self.stats, delay.as_millis()),
+            None => self.stats.to_string(),
+        }
+    }
+
+    // what comes before the IPv4 packets in our frames
+    pub fn link_header_len(&self) -> usize {
+        match self.tap_mac {
+            Some(_) => TUN_PREFIX_LEN + ETHERNET_HEADER_LEN,
+            None => TUN_PREFIX_LEN,
+        }
+    }
+
+    // TAP mode: besides IPv4 we have to answer ARP, or the kernel never
+    // finds out where to send its packets
+    fn handle_ethernet_frame(&mut self, frame: &[u8], mac: MacAddress, reply: &mut Vec<u8>) -> bool {
+        let (payload, ethernet) = match parse_ethernet_header(frame) {
+            Ok(parsed) => parsed,
+            Err(_) => {
+                warn!("Ethernet frame too short, discarding");
+                self.stats.parse_failures += 1;
+                return false;
+            },
+        };
+        trace!("ethernet: {:?}", ethernet);
+        if ethernet.destination != mac && !ethernet.destination.is_broadcast() {
+            debug!("Frame for {}, discarding", ethernet.destination);
+            return false;
+        }
+
+        let response = EthernetHeader { destination: ethernet.source, source: mac, ethertype: ethernet.ethertype };
+        match ethernet.ethertype {
+            ETHERTYPE_ARP => {
+                let arp = match self.handle_arp_packet(payload, mac) {
+                    Some(arp) => arp,
+                    None => return false,
+                };
+                debug!("Sending ARP reply: {:?}", arp);
+                tap_frame_header(reply, &response);
+                arp.serialize_into(reply);
+            },
+            ETHERTYPE_IPV4 => {
+                let packet = match self.handle_ipv4_packet(payload) {
+                    Some(packet) => packet,
+                    None => return false,
+                };
+                debug!("Sending reply: {:?}", packet);
+                tap_frame_header(reply, &response);
+                packet.serialize_into(reply);
+            },
+            // only reachable by hosts that already know our MAC, we don't
+            // do neighbor discovery
+            ETHERTYPE_IPV6 => {
+                let packet = match self.handle_ipv6_packet(payload) {
+                    Some(packet) => packet,
+                    None => return false,
+                };
+                debug!("Sending reply: {:?}", packet);
+                tap_frame_header(reply, &response);
+                packet.serialize_into(reply);
+            },
+            other => {
+                warn!("Unsupported ethertype {other:#06x}, discarding");
+                self.stats.non_icmp_dropped += 1;
+                return false;
+            },
+        }
+        true
+    }
+
+    fn handle_arp_packet(&mut self, data: &[u8], mac: MacAddress) -> Option<ArpPacket> {
+        let (_, request) = match parse_arp_packet(data) {
+            Ok(parsed) => parsed,
+            Err(_) => {
+                warn!("Failed to parse ARP packet, discarding");
+                self.stats.parse_failures += 1;
+                return None;
+            },
+        };
+        debug!("ARP: who has {}? tell {}", request.target_ip, request.sender_ip);
+
+        let reply = arp_reply(&request, mac, self.config.local_address?)?;
+        self.stats.replies_sent += 1;
+        Some(reply)
+    }
+
+    fn handle_ipv4_packet(&mut self, data: &[u8]) -> Option<Ipv4Packet> {
+        self.stats.bytes_in += data.len() as u64;
+
+        // borrow the payload, most packets are answered without copying it
+        let ip_packet = match Ipv4PacketRef::parse_unchecked(data) {
+            Ok(packet) => packet,
+            Err(IcmpTuntapError::UnsupportedVersion) => {
+                warn!("Not IPv4, discarding");
+                self.stats.non_icmp_dropped += 1;
+                return None;
+            },
+            Err(e) => {
+                warn!("Failed to parse IPv4 packet ({e}), discarding");
+                self.stats.parse_failures += 1;
+                return None;
+            },
+        };
+        trace!("header: {:?}", ip_packet.header);
+
+        // trusted sources skip the header checksum as well as the ICMP one
+        let header_len = ip_packet.header.prelude.header_length as usize * 4;
+        if !self.trusted.contains(ip_packet.header.source) && !checksum_valid(&data[..header_len]) {
+            warn!("Failed to parse IPv4 packet ({}), discarding", IcmpTuntapError::BadChecksum);
+            self.stats.parse_failures += 1;
+            return None;
+        }
+
+        if !source_allowed(&self.allow, ip_packet.header.source) {
+            warn!("Source {} is not allowed, discarding", ip_packet.header.source);
+            self.stats.not_allowed += 1;
+            return None;
+        }
+
+        // the probe stands in for a router on the way, so it applies to
+        // packets for anyone
+        if let Some((threshold, mtu)) = self.config.mtu_probe {
+            if ip_packet.header.total_length > threshold && may_send_error(&ip_packet.header, ip_packet.data, self.config.subnet) {
+                if let Some(mut reply) = build_fragmentation_needed(&ip_packet.to_owned(), mtu) {
+                    debug!("Datagram of {} bytes from {} over the probe threshold; replying with fragmentation needed",
+                        ip_packet.header.total_length, ip_packet.header.source);
+                    self.finish_reply(&mut reply);
+                    self.stats.replies_sent += 1;
+                    self.stats.bytes_out += reply.header.total_length as u64;
+                    return Some(reply);
+                }
+            }
+        }
+
+        // routers don't reassemble what they forward
+        if !self.is_local(&ip_packet.header) {
+            return self.forward_packet(&ip_packet);
+        }
+
+        let reassembled;
+        let ip_packet = if ip_packet.header.frag_info.is_fragment() {
+            match self.reassembler.push(ip_packet.to_owned()) {
+                Some(packet) => {
+                    reassembled = packet;
+                    Ipv4PacketRef::from(&reassembled)
+                },
+                None => { debug!("Buffered IPv4 fragment"); return None; },
+            }
+        } else {
+            ip_packet
+        };
+
+        let reply = match self.handlers.get(&ip_packet.header.protocol) {
+            Some(Handler::Icmp) => {
+                let mut reply = self.handle_icmp_packet(&ip_packet)?;
+                self.finish_reply(&mut reply);
+                reply
+            },
+            Some(Handler::Registered(handler)) => handler(&ip_packet.to_owned())?,
+            None => {
+                if !may_send_error(&ip_packet.header, ip_packet.data, self.config.subnet) {
+                    debug!("No handler for {:?}, discarding", ip_packet.header.protocol);
+                    self.stats.non_icmp_dropped += 1;
+                    return None;
+                }
+                debug!("No handler for {:?}; replying with protocol unreachable", ip_packet.header.protocol);
+                let mut reply = build_dest_unreachable(&ip_packet.to_owned(), DEST_UNREACHABLE_PROTOCOL);
+                self.finish_reply(&mut reply);
+                self.stats.protocol_unreachable_sent += 1;
+                reply
+            },
+        };
+
+        self.stats.replies_sent += 1;
+        self.stats.bytes_out += reply.header.total_length as u64;
+        Some(reply)
+    }
+
+    // Answers ICMPv6 echo requests; anything else over IPv6 is dropped.
+    // Forwarding, fragments and the registered handlers are IPv4 only.
+    fn handle_ipv6_packet(&mut self, data: &[u8]) -> Option<Ipv6Packet> {
+        self.stats.bytes_in += data.len() as u64;
+
+        let ip_packet = match Ipv6Packet::parse(data) {
+            Ok(packet) => packet,
+            Err(e) => {
+                warn!("Failed to parse IPv6 packet ({e}), discarding");
+                self.stats.parse_failures += 1;
+                return None;
+            },
+        };
+        trace!("header: {:?}", ip_packet.header);
+
+        // the subnets of --allow are IPv4 ones, no IPv6 source is in them
+        if !self.allow.is_empty() {
+            warn!("IPv6 packet from {} while --allow only covers IPv4, discarding", ip_packet.header.source);
+            self.stats.not_allowed += 1;
+            return None;
+        }
+        if ip_packet.header.next_header != NEXT_HEADER_ICMPV6 {
+            debug!("Next header {} isn't ICMPv6, discarding", ip_packet.header.next_header);
+            self.stats.non_icmp_dropped += 1;
+            return None;
+        }
+        let icmp_packet = match Icmpv6Packet::from_ipv6(&ip_packet) {
+            Ok(packet) => packet,
+            Err(e) => {
+                warn!("Failed to parse ICMPv6 packet ({e}), discarding");
+                self.stats.parse_failures += 1;
+                return None;
+            },
+        };
+        debug!("{}", icmp_packet);
+        if icmp_packet.icmp_type != Icmpv6Type::EchoRequest {
+            return None;
+        }
+
+        self.stats.echo_requests += 1;
+        // we have no unicast IPv6 address to answer from (RFC 4443 2.2)
+        let destination = ip_packet.header.destination;
+        if destination.is_multicast() {
+            warn!("Echo request to multicast address {destination}, discarding");
+            return None;
+        }
+        if self.over_rate_limit() {
+            debug!("Echo request from {} over the rate limit, discarding", ip_packet.header.source);
+            return None;
+        }
+
+        let echo_payload = self.config.echo_payload;
+        let mut reply = icmpv6::build_echo_reply(&ip_packet.header, &icmp_packet, self.config.reply_ttl,
+            |payload| echo_payload.apply(payload))?;
+        if self.config.no_checksum {
+            reply.data[2..4].fill(0);
+        }
+        self.echo_reply_offset = Some(IPV6_HEADER_LEN);
+        self.stats.replies_sent += 1;
+        self.stats.bytes_out += reply.serialized_len() as u64;
+        Some(reply)
+    }
+
+    // takes a token from the --rate bucket, if there's one
+    fn over_rate_limit(&mut self) -> bool {
+        let over = match &mut self.rate_limit {
+            Some(bucket) => !bucket.allow(Instant::now()),
+            None => false,
+        };
+        if over {
+            self.stats.rate_limited += 1;
+        }
+        over
+    }
+
+    // how long to wait before sending the last reply: only echo replies
+    // get delayed, errors and forwarded packets go out right away. The
+    // jitter is drawn from the same `rng` as the impairments.
+    pub fn reply_delay(&mut self) -> Duration {
+        if (self.config.reply_delay.is_zero() && self.config.reply_jitter.is_none()) || self.echo_reply_offset.is_none() {
+            return Duration::ZERO;
+        }
+        match self.config.reply_jitter {
+            Some((min, max)) => self.config.reply_delay + impair::jitter(&mut self.rng, min, max),
+            None => self.config.reply_delay,
+        }
+    }
+
+    // Drops or corrupts echo replies as configured with `impair`, leaving
+    // everything else alone; `frame` is the last reply `handle_packet_into`
+    // produced. Returns whether it should still be sent. The corrupted bit
+    // is always in the ICMP message, a broken IP header would just look
+    // like loss.
+    pub fn impair_reply(&mut self, frame: &mut [u8]) -> bool {
+        if self.impair.is_off() {
+            return true;
+        }
+        let offset = match self.echo_reply_offset {
+            Some(offset) if offset <= frame.len() => offset,
+            _ => return true,
+        };
+        match impair::decide(&mut self.rng, &self.impair, frame.len() - offset) {
+            Impairment::Deliver => true,
+            Impairment::Drop => {
+                debug!("Dropping echo reply on purpose");
+                self.stats.replies_lost += 1;
+                false
+            },
+            Impairment::Corrupt(bit) => {
+                debug!("Corrupting bit {bit} of an echo reply on purpose");
+                impair::flip_bit(&mut frame[offset..], bit);
+                self.stats.replies_corrupted += 1;
+                true
+            },
+        }
+    }
+
+    // fills in what the replies we build get on top of what the request
+    // says, before they're checksummed
+    fn finish_reply(&mut self, reply: &mut Ipv4Packet) {
+        reply.header.identification = self.identification.next_id();
+        self.config.apply(reply);
+    }
+
+    // broadcasts and multicasts are for us too, whether they get answered
+    // is up to the handlers
+    fn is_local(&self, header: &Ipv4Header) -> bool {
+        match self.config.local_address {
+            Some(local) => header.is_for_us(local) || is_broadcast_or_multicast(header.destination, self.config.subnet),
+            None => true,
+        }
+    }
+
+    fn forward_packet(&mut self, ip_packet: &Ipv4PacketRef) -> Option<Ipv4Packet> {
+        let destination = ip_packet.header.destination;
+        if !self.config.forward {
+            warn!("Packet for {destination} is not for us, discarding");
+            return None;
+        }
+
+        let original = ip_packet.to_owned();
+        let mut forwarded = original.clone();
+        if !forwarded.header.decrement_ttl() {
+            if !may_send_error(&original.header, &original.data, self.config.subnet) {
+                debug!("TTL expired on the way to {destination}, discarding");
+                return None;
+            }
+            debug!("TTL expired on the way to {destination}; replying with time exceeded");
+            let mut reply = ipv4_reply(&original.header, &build_time_exceeded(&original));
+            self.finish_reply(&mut reply);
+            self.stats.replies_sent += 1;
+            self.stats.bytes_out += reply.header.total_length as u64;
+            return Some(reply);
+        }
+
+        debug!("Forwarding packet to {destination}");
+        self.stats.bytes_out += forwarded.header.total_length as u64;
+        Some(forwarded)
+    }
+
+    fn handle_icmp_packet(&mut self, ip_packet: &Ipv4PacketRef) -> Option<Ipv4Packet> {
+        trace!("ICMP packet; trying to parse...");
+        if self.json {
+            // report packets with a bad checksum too, they're dropped below
+            if let Ok((_, icmp_packet)) = parse_icmp_packet_unchecked(ip_packet.data) {
+                println!("{}", packet_json(&ip_packet.header, &icmp_packet));
+            }
+        }
+        let (_, icmp_packet) = match parse_icmp_packet_from(ip_packet.header.source, ip_packet.data, &self.trusted) {
+            Ok(parsed) => parsed,
+            Err(_) => {
+                warn!("Failed to parse packet");
+                self.stats.parse_failures += 1;
+                return None;
+            },
+        };
+
+        debug!("{}", icmp_packet.summary());
+        trace!("{:?}", icmp_packet);
+
+        if !matches!(icmp_packet.header.icmp_type, IcmpType::EchoRequest | IcmpType::Timestamp) {
+            return None;
+        }
+        if icmp_packet.header.icmp_type == IcmpType::EchoRequest {
+            self.stats.echo_requests += 1;
+        }
+
+        // replies come from a unicast address (RFC 1122 3.2.2.6), which
+        // for a broadcast or multicast request has to be one we're given
+        let destination = ip_packet.header.destination;
+        let mut source = destination;
+        if is_broadcast_or_multicast(destination, self.config.subnet) {
+            source = match (self.config.broadcast_echo, self.config.local_address.or(self.config.source_override)) {
+                (true, Some(local)) => local,
+                _ => {
+                    warn!("{:?} to broadcast/multicast address {destination}, discarding", icmp_packet.header.icmp_type);
+                    return None;
+                },
+            };
+        }
+
+        let icmp_reply = match icmp_packet.header.icmp_type {
+            IcmpType::EchoRequest => {
+                if self.over_rate_limit() {
+                    debug!("Echo request from {} over the rate limit, discarding", ip_packet.header.source);
+                    return None;
+                }
+                let echo_payload = self.config.echo_payload;
+                let mut reply = icmp_packet.echo_reply_with(|payload| echo_payload.apply(payload));
+                if let Some(rewriter) = &self.config.echo_id_rewriter {
+                    rewriter.rewrite(&mut reply);
+                }
+                reply
+            },
+            _ => match build_timestamp_reply(&icmp_packet.to_owned()) {
+                Some(reply) => reply,
+                None => { warn!("Malformed timestamp request, discarding"); return None; },
+            },
+        };
+
+        trace!("ICMP reply: {:?}", icmp_reply);
+        let mut reply = ipv4_reply(&ip_packet.header, &icmp_reply);
+        reply.header.source = source;
+        if icmp_reply.header.icmp_type == IcmpType::EchoReply {
+            self.echo_reply_offset = Some(reply.header.prelude.header_length as usize * 4);
+        }
+        Some(reply)
+    }
+}
+
+// How the Linux kernel answers an echo request, as far as the IPv4
+// header goes:
+// - TTL comes from net.ipv4.ip_default_ttl (64 by default)
+// - the identification is picked by the kernel, so it can't be predicted
+// - the ToS byte (DSCP and ECN) is copied from the request
+// - Don't Fragment is never set
+const KERNEL_DEFAULT_TTL: u8 = 64;
+
+// Builds our reply to a raw (IPv4) echo request and lists the header
+// fields where it is known to differ from the reply Linux would send.
+// The identification always does, Linux picks it at random.
+#[allow(dead_code)]
+pub fn echo_reply_with_kernel_differences(request: &[u8]) -> Option<(Vec<u8>, Vec<&'static str>)> {
+    let (_, ip_packet) = parse_ipv4_packet(request).ok()?;
+    let (_, icmp_packet) = parse_icmp_packet(&ip_packet.data).ok()?;
+    if icmp_packet.header.icmp_type != IcmpType::EchoRequest {
+        return None;
+    }
+
+    let reply = ipv4_reply(&ip_packet.header, &icmp_packet.echo_reply());
+
+    let mut differences = vec!["identification"];
+    if reply.header.ttl != KERNEL_DEFAULT_TTL {
+        differences.push("ttl");
+    }
+    if reply.header.prelude.dscp != ip_packet.header.prelude.dscp {
+        differences.push("dscp");
+    }
+    if reply.header.prelude.ecn != ip_packet.header.prelude.ecn {
+        differences.push("ecn");
+    }
+    if reply.header.frag_info.dont_fragment() {
+        differences.push("flags");
+    }
+
+    Some((reply.serialize(), differences))
+}
+
+#[test]
+fn test_echo_reply_matches_kernel_except_documented_fields() {
+    // captured on a TUN device: an echo request sent by Linux 6.18 from
+    // a raw socket (like ping(8) does) and the reply Linux sent back
+    let request = [
+        69, 0, 0, 84, 135, 250, 64, 0, 64, 1, 158, 172, 10, 0, 0, 2, 10, 0, 0, 1, 8, 0, 242, 170, 123, 45, 0, 1, 240, 128, 210, 106, 0, 0, 0, 0, 255, 103, 9, 0, 0, 0, 0, 0, 16, 17, 18, 19, 20, 21, 22, 23, 24, 25, 26, 27, 28, 29, 30, 31, 32, 33, 34, 35, 36, 37, 38, 39, 40, 41, 42, 43, 44, 45, 46, 47, 48, 49, 50, 51, 52, 53, 54, 55
+    ];
+    let kernel_reply = [
+        69, 0, 0, 84, 158, 96, 0, 0, 64, 1, 200, 70, 10, 0, 0, 1, 10, 0, 0, 2, 0, 0, 250, 170, 123, 45, 0, 1, 240, 128, 210, 106, 0, 0, 0, 0, 255, 103, 9, 0, 0, 0, 0, 0, 16, 17, 18, 19, 20, 21, 22, 23, 24, 25, 26, 27, 28, 29, 30, 31, 32, 33, 34, 35, 36, 37, 38, 39, 40, 41, 42, 43, 44, 45, 46, 47, 48, 49, 50, 51, 52, 53, 54, 55
+    ];
+
+    let (reply, differences) = echo_reply_with_kernel_differences(&request).unwrap();
+    let (_, ours) = parse_ipv4_packet(&reply).unwrap();
+    let (_, kernel) = parse_ipv4_packet(&kernel_reply).unwrap();
+    assert!(ours.checksum_is_valid());
+    assert!(kernel.checksum_is_valid());
+
+    assert!(differences.contains(&"identification"));
+    for field in ours.header.diff(&kernel.header) {
+        assert!(differences.contains(&field), "undocumented difference in {}", field);
+    }
+    assert_eq!(ours.data, kernel.data);
+}
+
+#[test]
+fn test_handle_packet_trusted_sources() {
+    use crate::util::PING_REQUEST;
+
+    // a `ping 10.0.0.1` request from 10.0.0.0, as read from the TUN device
+    let request = tun_frame(&PING_REQUEST);
+    let trusting = |subnet: &str| {
+        let mut trusted = TrustedSources::new();
+        trusted.add(subnet.parse().unwrap());
+        Responder::new(trusted, Vec::new(), FragmentReassembler::new(64 * 1024, Duration::from_secs(30)))
+    };
+
+    // a bad IPv4 header checksum, then a bad ICMP one on top of it
+    let mut bad_header = request;
+    bad_header[TUN_PREFIX_LEN + 10] ^= 0xff;
+    let mut bad_both = bad_header.clone();
+    bad_both[TUN_PREFIX_LEN + IPV4_MIN_HEADER_LEN + 2] ^= 0xff;
+
+    for frame in [bad_header, bad_both] {
+        let mut responder = trusting("10.0.0.0/24");
+        let reply = responder.handle_packet(&frame).unwrap();
+        let ip_packet = Ipv4Packet::parse(&reply[TUN_PREFIX_LEN..]).unwrap();
+        let (_, icmp_packet) = parse_icmp_packet(&ip_packet.data).unwrap();
+        assert_eq!(icmp_packet.header.icmp_type, IcmpType::EchoReply);
+
+        let mut responder = trusting("192.168.0.0/16");
+        assert!(responder.handle_packet(&frame).is_none());
+        assert_eq!(responder.stats.parse_failures, 1);
+        assert_eq!(responder.stats.replies_sent, 0);
+    }
+}
+
+#[test]
+fn test_source_allowed() {
+    let source: Ipv4Address = "10.0.0.5".parse().unwrap();
+    assert!(source_allowed(&[], source));
+
+    let lab: Ipv4Cidr = "10.0.0.0/24".parse().unwrap();
+    let other: Ipv4Cidr = "192.168.0.0/16".parse().unwrap();
+    assert!(source_allowed(&[lab], source));
+    assert!(!source_allowed(&[other], source));
+    assert!(source_allowed(&[other, lab], source));
+    assert!(!source_allowed(&[lab, other], "10.0.1.5".parse().unwrap()));
+}
+
+#[test]
+fn test_build_timestamp_reply() {
+    let mut request = IcmpPacket {
+        header: IcmpHeader {
+            icmp_type: IcmpType::Timestamp,
+            code: 0,
+            checksum: 0,
+            data: Some(IcmpHeaderData::Timestamp {
+                id: 7,
+                seq: 1,
+                originate: 1234,
+                receive: 0,
+                transmit: 0,
+            }),
+        },
+        data: Vec::new(),
+    };
+    request.update_checksum();
+
+    let reply = build_timestamp_reply_at(&request, 5678).unwrap();
+    assert_eq!(reply.header.data, Some(IcmpHeaderData::TimestampReply {
+        id: 7,
+        seq: 1,
+        originate: 1234,
+        receive: 5678,
+        transmit: 5678,
+    }));
+    assert!(parse_icmp_packet(&reply.serialize()).is_ok());
+
+    // anything else isn't a timestamp request
+    assert!(build_timestamp_reply(&IcmpPacket::echo_request(1, 1, &[])).is_none());
+}
+
+#[test]
+fn test_large_echo_request_round_trip() {
+    let payload: Vec<u8> = (0..1400).map(|i| i as u8).collect();
+    let request = Ipv4PacketBuilder::new()
+        .source(Ipv4Address(0x0a000000))
+        .destination(Ipv4Address(0x0a000001))
+        .payload(IcmpPacket::echo_request(1, 1, &payload).serialize())
+        .build();
+    let bytes = request.serialize();
+    assert_eq!(bytes.len(), 20 + 8 + 1400);
+
+    let (_, ip_packet) = parse_ipv4_packet(&bytes).unwrap();
+    let (_, icmp_packet) = parse_icmp_packet(&ip_packet.data).unwrap();
+    assert_eq!(icmp_packet.data, payload);
+
+    let reply = ipv4_reply(&ip_packet.header, &icmp_packet.echo_reply());
+    assert_eq!(reply.serialize().len(), bytes.len());
+    let (_, icmp_reply) = parse_icmp_packet(&reply.data).unwrap();
+    assert_eq!(icmp_reply.data, payload);
+}
+
+#[test]
+fn test_time_exceeded_on_ttl_expiry() {
+    use crate::util::PING_REQUEST;
+
+    let bytes = PING_REQUEST;
+    let (_, mut packet) = parse_ipv4_packet(&bytes).unwrap();
+
+    assert!(packet.header.decrement_ttl());
+    assert_eq!(packet.header.ttl, 63);
+    assert!(packet.header.verify_checksum());
+
+    packet.header.ttl = 1;
+    packet.update_checksum();
+    assert!(!packet.header.decrement_ttl());
+    assert_eq!(packet.header.ttl, 0);
+
+    let error = build_time_exceeded(&packet);
+    let (_, parsed) = parse_icmp_packet(&error.serialize()).unwrap();
+    assert_eq!(parsed.header.icmp_type, IcmpType::TimeExceeded);
+    assert_eq!(parsed.header.code, 0);
+    match parsed.header.data {
+        Some(IcmpHeaderData::TimeExceeded { ip_header, data, .. }) => {
+            assert_eq!(ip_header, packet.header);
+            assert_eq!(data, [8, 0, 91, 182, 0, 9, 0, 2]);
+        },
+        other => panic!("expected Time Exceeded data, got {:?}", other),
+    }
+}
+
+#[test]
+fn test_build_dest_unreachable() {
+    // a UDP datagram (mDNS) we don't handle
+    let original = Ipv4PacketBuilder::new()
+        .source(Ipv4Address::from([10, 0, 0, 0]))
+        .destination(Ipv4Address::from([10, 0, 0, 1]))
+        .protocol(Ipv4HeaderProtocol::Udp)
+        .payload(vec![20, 233, 20, 233, 0, 82, 0, 0, 1, 2, 3, 4])
+        .build();
+
+    let reply = build_dest_unreachable(&original, DEST_UNREACHABLE_PROTOCOL);
+    assert!(reply.header.verify_checksum());
+    assert_eq!(reply.header.source, original.header.destination);
+    assert_eq!(reply.header.destination, original.header.source);
+    assert_eq!(reply.header.protocol, Ipv4HeaderProtocol::Icmp);
+
+    // type, code, checksum, unused, next-hop MTU, quoted header, 8 bytes
+    assert_eq!(reply.data.len(), 8 + 20 + 8);
+    let (_, icmp_packet) = parse_icmp_packet(&reply.data).unwrap();
+    assert_eq!(icmp_packet.header.icmp_type, IcmpType::DestinationUnreachable);
+    assert_eq!(icmp_packet.header.code, DEST_UNREACHABLE_PROTOCOL);
+    assert_eq!(icmp_packet.header.data, Some(IcmpHeaderData::DestinationUnreachable {
+        next_hop_mtu: 0,
+        ip_header: original.header.clone(),
+        data: vec![20, 233, 20, 233, 0, 82, 0, 0],
+        extension: None,
+    }));
+}
+
+#[test]
+fn test_icmp_error_builder() {
+    use crate::util::checksum_16;
+
+    let original = Ipv4PacketBuilder::new()
+        .source(Ipv4Address::from([10, 0, 0, 0]))
+        .destination(Ipv4Address::from([10, 0, 0, 1]))
+        .protocol(Ipv4HeaderProtocol::Udp)
+        .payload(vec![20, 233, 20, 233, 0, 82, 0, 0, 1, 2, 3, 4])
+        .build();
+    let quoted = vec![20, 233, 20, 233, 0, 82, 0, 0];
+
+    let checked = |packet: IcmpPacket, icmp_type, code| {
+        assert_eq!(checksum_16(&packet.serialize()), 0);
+        assert_eq!(packet.header.icmp_type, icmp_type);
+        assert_eq!(packet.header.code, code);
+        assert!(packet.data.is_empty());
+        packet.header.data.unwrap()
+    };
+
+    let unreachable = IcmpErrorBuilder::new(&original, IcmpType::DestinationUnreachable, 3)
+        .next_hop_mtu(1280)
+        .build()
+        .unwrap();
+    assert_eq!(checked(unreachable, IcmpType::DestinationUnreachable, 3), IcmpHeaderData::DestinationUnreachable {
+        next_hop_mtu: 1280,
+        ip_header: original.header.clone(),
+        data: quoted.clone(),
+        extension: None,
+    });
+
+    let time_exceeded = IcmpErrorBuilder::new(&original, IcmpType::TimeExceeded, 1).build().unwrap();
+    assert_eq!(checked(time_exceeded, IcmpType::TimeExceeded, 1), IcmpHeaderData::TimeExceeded {
+        ip_header: original.header.clone(),
+        data: quoted.clone(),
+        extension: None,
+    });
+
+    let gateway = Ipv4Address::from([10, 0, 0, 254]);
+    let redirect = IcmpErrorBuilder::new(&original, IcmpType::RedirectMessage, 1)
+        .gateway(gateway)
+        .build()
+        .unwrap();
+    assert_eq!(checked(redirect, IcmpType::RedirectMessage, 1), IcmpHeaderData::Redirect {
+        gateway,
+        ip_header: original.header.clone(),
+        data: quoted,
+    });
+
+    // short payloads are quoted whole
+    let short = Ipv4PacketBuilder::new().payload(vec![1, 2, 3]).build();
+    match IcmpErrorBuilder::new(&short, IcmpType::TimeExceeded, 0).build().unwrap().header.data {
+        Some(IcmpHeaderData::TimeExceeded { data, .. }) => assert_eq!(data, [1, 2, 3]),
+        other => panic!("expected Time Exceeded data, got {:?}", other),
+    }
+
+    assert!(IcmpErrorBuilder::new(&original, IcmpType::EchoReply, 0).build().is_none());
+}
+
+#[test]
+fn test_build_fragmentation_needed() {
+    let mut original = Ipv4PacketBuilder::new()
+        .source(Ipv4Address::from([10, 0, 0, 0]))
+        .destination(Ipv4Address::from([10, 0, 0, 1]))
+        .payload(IcmpPacket::echo_request(1, 1, &[0; 1472]).serialize())
+        .build();
+    // fits, and may be fragmented anyway
+    assert!(build_fragmentation_needed(&original, 1500).is_none());
+    assert!(build_fragmentation_needed(&original, 1400).is_none());
+
+    original.header.frag_info.set_dont_fragment(true);
+    original.update_checksum();
+    assert!(build_fragmentation_needed(&original, 1500).is_none());
+
+    let reply = build_fragmentation_needed(&original, 1400).unwrap();
+    assert!(reply.checksum_is_valid());
+    assert_eq!(reply.header.destination, original.header.source);
+    let (_, icmp_packet) = parse_icmp_packet(&reply.data).unwrap();
+    assert_eq!(icmp_packet.header.icmp_type, IcmpType::DestinationUnreachable);
+    assert_eq!(icmp_packet.header.code, DEST_UNREACHABLE_FRAGMENTATION_NEEDED);
+    match icmp_packet.header.data {
+        Some(IcmpHeaderData::DestinationUnreachable { next_hop_mtu, ip_header, .. }) => {
+            assert_eq!(next_hop_mtu, 1400);
+            assert_eq!(ip_header, original.header);
+        },
+        other => panic!("expected Destination Unreachable data, got {:?}", other),
+    }
+}
+
+#[test]
+fn test_mtu_probe() {
+    let request = |len: usize, dont_fragment: bool| {
+        let mut request = Ipv4PacketBuilder::new()
+            .source(Ipv4Address::from([10, 0, 0, 0]))
+            .destination(Ipv4Address::from([10, 0, 0, 1]))
+            .payload(IcmpPacket::echo_request(1, 1, &vec![0; len - 28]).serialize())
+            .build();
+        request.header.frag_info.set_dont_fragment(dont_fragment);
+        request.update_checksum();
+        request
+    };
+
+    let mut responder = test_responder();
+    responder.config.mtu_probe = Some((1400, 1400));
+    let original = request(1500, true);
+    let reply = responder.handle_packet(&tun_frame(&original.serialize())).unwrap();
+    let ip_packet = Ipv4Packet::parse(&reply[TUN_PREFIX_LEN..]).unwrap();
+    assert!(ip_packet.checksum_is_valid());
+    assert_eq!(ip_packet.header.destination, original.header.source);
+    let (_, icmp_packet) = parse_icmp_packet(&ip_packet.data).unwrap();
+    assert_eq!(icmp_packet.header.icmp_type, IcmpType::DestinationUnreachable);
+    assert_eq!(icmp_packet.header.code, DEST_UNREACHABLE_FRAGMENTATION_NEEDED);
+    match icmp_packet.header.data {
+        Some(IcmpHeaderData::DestinationUnreachable { next_hop_mtu, ip_header, .. }) => {
+            assert_eq!(next_hop_mtu, 1400);
+            assert_eq!(ip_header, original.header);
+        },
+        other => panic!("expected Destination Unreachable data, got {:?}", other),
+    }
+
+    // datagrams that fit or may be fragmented get through to the echo
+    let echo_reply = |responder: &mut Responder, request: &Ipv4Packet| {
+        let reply = responder.handle_packet(&tun_frame(&request.serialize())).unwrap();
+        let ip_packet = Ipv4Packet::parse(&reply[TUN_PREFIX_LEN..]).unwrap();
+        parse_icmp_packet(&ip_packet.data).unwrap().1.header.icmp_type
+    };
+    assert_eq!(echo_reply(&mut responder, &request(1400, true)), IcmpType::EchoReply);
+    assert_eq!(echo_reply(&mut responder, &request(1500, false)), IcmpType::EchoReply);
+
+    // errors and broadcasts are let through like they'd be by a router
+    let mut error = ipv4_reply(&original.header, &build_time_exceeded(&original));
+    error.data.resize(1500 - IPV4_MIN_HEADER_LEN, 0);
+    error.header.total_length = 1500;
+    error.header.frag_info.set_dont_fragment(true);
+    error.update_checksum();
+    let mut broadcast = request(1500, true);
+    broadcast.header.destination = Ipv4Address::from([255, 255, 255, 255]);
+    broadcast.update_checksum();
+    assert!(responder.handle_packet(&tun_frame(&error.serialize())).is_none());
+    assert!(responder.handle_packet(&tun_frame(&broadcast.serialize())).is_none());
+
+    // the advertised MTU can be below the threshold
+    responder.config.mtu_probe = Some((1000, 576));
+    let reply = responder.handle_packet(&tun_frame(&request(1001, true).serialize())).unwrap();
+    let ip_packet = Ipv4Packet::parse(&reply[TUN_PREFIX_LEN..]).unwrap();
+    match parse_icmp_packet(&ip_packet.data).unwrap().1.header.data {
+        Some(IcmpHeaderData::DestinationUnreachable { next_hop_mtu, .. }) => assert_eq!(next_hop_mtu, 576),
+        other => panic!("expected Destination Unreachable data, got {:?}", other),
+    }
+}
+
+#[test]
+fn test_build_redirect() {
+    let original = Ipv4PacketBuilder::new()
+        .source(Ipv4Address::from([10, 0, 0, 2]))
+        .destination(Ipv4Address::from([192, 168, 1, 1]))
+        .payload(IcmpPacket::echo_request(7, 3, &[0xab; 32]).serialize())
+        .build();
+    let gateway = Ipv4Address::from([10, 0, 0, 254]);
+
+    let reply = build_redirect(&original, gateway, 1);
+    assert!(reply.checksum_is_valid());
+    assert_eq!(reply.header.destination, original.header.source);
+    let (_, icmp_packet) = parse_icmp_packet(&reply.data).unwrap();
+    assert_eq!(icmp_packet.header.icmp_type, IcmpType::RedirectMessage);
+    assert_eq!(icmp_packet.header.code, 1);
+    match icmp_packet.header.data {
+        Some(IcmpHeaderData::Redirect { gateway: redirected_to, ip_header, data }) => {
+            assert_eq!(redirected_to, gateway);
+            assert_eq!(ip_header, original.header);
+            assert_eq!(data, original.data[..8]);
+        },
+        other => panic!("expected Redirect data, got {:?}", other),
+    }
+}
+
+#[test]
+fn test_responder_stats() {
+    let mut responder = test_responder();
+
+    let echo_request = Ipv4PacketBuilder::new()
+        .source(Ipv4Address::from([10, 0, 0, 0]))
+        .destination(Ipv4Address::from([10, 0, 0, 1]))
+        .payload(IcmpPacket::echo_request(1, 1, &[0x42; 56]).serialize())
+        .build()
+        .serialize();
+    let echo_frame = tun_frame(&echo_request);
+    let udp = Ipv4PacketBuilder::new()
+        .protocol(Ipv4HeaderProtocol::Udp)
+        .payload(vec![0; 8])
+        .build()
+        .serialize();
+    let mut corrupted = echo_frame.clone();
+    corrupted[34] ^= 0xff;
+    let mut not_ip = echo_frame.clone();
+    not_ip[2..4].copy_from_slice(&[0x88, 0xb5]);
+
+    let reply = responder.handle_packet(&echo_frame).unwrap();
+    assert!(responder.handle_packet(&echo_frame).is_some());
+    assert!(responder.handle_packet(&tun_frame(&udp)).is_some());
+    assert!(responder.handle_packet(&corrupted).is_none());
+    assert!(responder.handle_packet(&echo_frame[..14]).is_none());
+    assert!(responder.handle_packet(&not_ip).is_none());
+
+    let stats = &responder.stats;
+    assert_eq!(stats.echo_requests, 2);
+    assert_eq!(stats.replies_sent, 3);
+    assert_eq!(stats.protocol_unreachable_sent, 1);
+    assert_eq!(stats.non_icmp_dropped, 1);
+    assert_eq!(stats.parse_failures, 2);
+    // the short read is dropped before its bytes are counted
+    assert_eq!(stats.bytes_in, (3 * echo_request.len() + udp.len()) as u64);
+    assert_eq!(stats.bytes_out, (2 * (reply.len() - TUN_PREFIX_LEN) + 56) as u64);
+}
+
+#[cfg(test)]
+fn test_responder() -> Responder {
+    Responder::new(
+        TrustedSources::new(),
+        Vec::new(),
+        FragmentReassembler::new(64 * 1024, Duration::from_secs(30)))
+}
+
+#[test]
+fn test_handle_packet_echo_request() {
+    use crate::util::PING_REQUEST;
+
+    // a `ping 10.0.0.1` request, as read from the TUN device
+    let request = tun_frame(&PING_REQUEST);
+
+    let reply = test_responder().handle_packet(&request).unwrap();
+    assert_eq!(reply[..TUN_PREFIX_LEN], request[..TUN_PREFIX_LEN]);
+
+    let ip_packet = Ipv4Packet::parse(&reply[TUN_PREFIX_LEN..]).unwrap();
+    assert!(ip_packet.checksum_is_valid());
+    assert_eq!(ip_packet.header.source, Ipv4Address::from([10, 0, 0, 1]));
+    assert_eq!(ip_packet.header.destination, Ipv4Address::from([10, 0, 0, 0]));
+
+    let (_, icmp_packet) = parse_icmp_packet(&ip_packet.data).unwrap();
+    assert_eq!(icmp_packet.header.icmp_type, IcmpType::EchoReply);
+    assert_eq!(icmp_packet.echo_id(), Some(9));
+    assert_eq!(icmp_packet.echo_seq(), Some(2));
+    assert_eq!(icmp_packet.data, request[32..]);
+
+    // a reused buffer ends up holding the same frame once cleared
+    let mut responder = test_responder();
+    let mut buf = vec![0xff; 8];
+    buf.clear();
+    assert!(responder.handle_packet_into(&request, &mut buf));
+    assert_eq!(buf, reply);
+    assert!(!responder.handle_packet_into(&request[..14], &mut buf));
+    assert_eq!(buf, reply);
+}
+
+#[test]
+fn test_handle_packet_echo_id_rewriter() {
+    use crate::util::PING_REQUEST;
+
+    // a `ping 10.0.0.1` request with identifier 9, as read from the TUN device
+    let request = tun_frame(&PING_REQUEST);
+    let echo_reply = |responder: &mut Responder| {
+        let reply = responder.handle_packet(&request).unwrap();
+        let ip_packet = Ipv4Packet::parse(&reply[TUN_PREFIX_LEN..]).unwrap();
+        // fails on a stale checksum
+        let (_, icmp_packet) = parse_icmp_packet(&ip_packet.data).unwrap();
+        icmp_packet
+    };
+
+    let mut rewriter = EchoIdRewriter::new();
+    rewriter.insert(9, 0xbeef);
+    let mut responder = test_responder();
+    responder.config.echo_id_rewriter = Some(rewriter);
+    let icmp_packet = echo_reply(&mut responder);
+    assert_eq!(icmp_packet.echo_id(), Some(0xbeef));
+    assert_eq!(icmp_packet.echo_seq(), Some(2));
+    assert_eq!(icmp_packet.data, request[32..]);
+
+    // identifiers without a mapping are echoed as is
+    let mut rewriter = EchoIdRewriter::new();
+    rewriter.insert(10, 0xbeef);
+    responder.config.echo_id_rewriter = Some(rewriter);
+    assert_eq!(echo_reply(&mut responder).echo_id(), Some(9));
+}
+
+#[test]
+fn test_handle_packet_tap() {
+    // an `arping 10.0.0.1` request, as read from the TAP device
+    let request = [
+        0, 0, 8, 6,                             // TUN prefix
+        0xff, 0xff, 0xff, 0xff, 0xff, 0xff,     // Destination MAC
+        0x5a, 0x3c, 0x11, 0x02, 0x9e, 0x41,     // Source MAC
+        8, 6,                                   // Ethertype (ARP)
+        0, 1, 8, 0, 6, 4, 0, 1,
+        0x5a, 0x3c, 0x11, 0x02, 0x9e, 0x41, 10, 0, 0, 0,
+        0, 0, 0, 0, 0, 0, 10, 0, 0, 1,
+    ];
+    let ours = MacAddress([0x02, 0, 0, 0, 0, 0x01]);
+
+    let mut responder = test_responder();
+    responder.tap_mac = Some(ours);
+    assert_eq!(responder.link_header_len(), TUN_PREFIX_LEN + ETHERNET_HEADER_LEN);
+    // we only answer for our own address
+    assert!(responder.handle_packet(&request).is_none());
+    responder.config.local_address = Some(Ipv4Address::from([10, 0, 0, 1]));
+    let reply = responder.handle_packet(&request).unwrap();
+    assert_eq!(reply, [
+        0, 0, 8, 6,
+        0x5a, 0x3c, 0x11, 0x02, 0x9e, 0x41,
+        0x02, 0, 0, 0, 0, 0x01,
+        8, 6,
+        0, 1, 8, 0, 6, 4, 0, 2,
+        0x02, 0, 0, 0, 0, 0x01, 10, 0, 0, 1,
+        0x5a, 0x3c, 0x11, 0x02, 0x9e, 0x41, 10, 0, 0, 0,
+    ]);
+
+    // echo requests get their Ethernet header swapped around
+    let echo = Ipv4PacketBuilder::new()
+        .source(Ipv4Address::from([10, 0, 0, 0]))
+        .destination(Ipv4Address::from([10, 0, 0, 1]))
+        .payload(IcmpPacket::echo_request(1, 1, &[]).serialize())
+        .build();
+    let mut frame = request[..ETHERNET_HEADER_LEN + TUN_PREFIX_LEN].to_vec();
+    frame[2..4].copy_from_slice(&[8, 0]);
+    frame[4..10].copy_from_slice(&ours.0);
+    frame[16..18].copy_from_slice(&[8, 0]);
+    frame.extend(echo.serialize());
+    let reply = responder.handle_packet(&frame).unwrap();
+    assert_eq!(reply[4..10], request[10..16]);
+    assert_eq!(reply[10..16], ours.0);
+    let ip_packet = Ipv4Packet::parse(&reply[responder.link_header_len()..]).unwrap();
+    assert_eq!(ip_packet.header.destination, Ipv4Address::from([10, 0, 0, 0]));
+
+    // frames for someone else are ignored
+    frame[4..10].copy_from_slice(&[0x02, 0, 0, 0, 0, 0x02]);
+    assert!(responder.handle_packet(&frame).is_none());
+}
+
+#[test]
+fn test_exceeds_mtu() {
+    let fits = tun_frame(&[0; DEFAULT_MTU]);
+    assert!(!exceeds_mtu(&fits, TUN_PREFIX_LEN, DEFAULT_MTU));
+    // the TUN prefix doesn't count towards the MTU
+    assert!(!exceeds_mtu(&fits[..TUN_PREFIX_LEN], TUN_PREFIX_LEN, 0));
+
+    let oversize = tun_frame(&[0; DEFAULT_MTU + 1]);
+    assert!(exceeds_mtu(&oversize, TUN_PREFIX_LEN, DEFAULT_MTU));
+    assert!(!exceeds_mtu(&oversize, TUN_PREFIX_LEN, 9000));
+    assert!(exceeds_mtu(&fits, TUN_PREFIX_LEN, 576));
+    // nor does the Ethernet header in TAP mode
+    assert!(!exceeds_mtu(&oversize, TUN_PREFIX_LEN + 14, DEFAULT_MTU));
+}
+
+#[test]
+fn test_register_protocol_handler() {
+    use std::cell::Cell;
+    use std::rc::Rc;
+
+    let udp = Ipv4PacketBuilder::new()
+        .source(Ipv4Address::from([10, 0, 0, 0]))
+        .destination(Ipv4Address::from([10, 0, 0, 1]))
+        .protocol(Ipv4HeaderProtocol::Udp)
+        .payload(vec![0, 7, 0, 7, 0, 12, 0, 0, 1, 2, 3, 4])
+        .build();
+
+    // UDP echo: sends the datagram back with the addresses swapped
+    let calls = Rc::new(Cell::new(0));
+    let mut responder = test_responder();
+    let counter = Rc::clone(&calls);
+    responder.register(Ipv4HeaderProtocol::Udp, move |packet| {
+        counter.set(counter.get() + 1);
+        Some(Ipv4PacketBuilder::new()
+            .source(packet.header.destination)
+            .destination(packet.header.source)
+            .protocol(Ipv4HeaderProtocol::Udp)
+            .payload(packet.data.clone())
+            .build())
+    });
+
+    let reply = responder.handle_packet(&tun_frame(&udp.serialize())).unwrap();
+    assert_eq!(calls.get(), 1);
+    let reply = Ipv4Packet::parse(&reply[TUN_PREFIX_LEN..]).unwrap();
+    assert_eq!(reply.header.protocol, Ipv4HeaderProtocol::Udp);
+    assert_eq!(reply.header.destination, udp.header.source);
+    assert_eq!(reply.data, udp.data);
+    assert_eq!(responder.stats.non_icmp_dropped, 0);
+
+    // ICMP still goes through the default handler
+    let echo = ipv4_reply(&reply.header, &IcmpPacket::echo_request(1, 1, &[0; 8]));
+    let reply = responder.handle_packet(&tun_frame(&echo.serialize())).unwrap();
+    assert_eq!(calls.get(), 1);
+    assert_eq!(responder.stats.echo_requests, 1);
+    let reply = Ipv4Packet::parse(&reply[TUN_PREFIX_LEN..]).unwrap();
+    assert_eq!(reply.header.protocol, Ipv4HeaderProtocol::Icmp);
+}
+
+#[test]
+fn test_responder_config() {
+    let request = Ipv4PacketBuilder::new()
+        .source(Ipv4Address::from([10, 0, 0, 0]))
+        .destination(Ipv4Address::from([10, 0, 0, 1]))
+        .payload(IcmpPacket::echo_request(1, 1, &[0; 8]).serialize())
+        .build();
+    let frame = tun_frame(&request.serialize());
+
+    let mut responder = test_responder();
+    let reply = responder.handle_packet(&frame).unwrap();
+    let reply = Ipv4Packet::parse(&reply[TUN_PREFIX_LEN..]).unwrap();
+    assert_eq!(reply.header.ttl, DEFAULT_REPLY_TTL);
+    assert_eq!(reply.header.source, Ipv4Address::from([10, 0, 0, 1]));
+
+    let source = Ipv4Address::from([10, 0, 0, 42]);
+    responder.config = ResponderConfig { reply_ttl: 1, source_override: Some(source), ..Default::default() };
+    let reply = responder.handle_packet(&frame).unwrap();
+    let reply = Ipv4Packet::parse(&reply[TUN_PREFIX_LEN..]).unwrap();
+    assert_eq!(reply.header.ttl, 1);
+    assert_eq!(reply.header.source, source);
+    assert_eq!(reply.header.destination, request.header.source);
+}
+
+#[test]
+fn test_broadcast_echo() {
+    let subnet: Ipv4Cidr = "10.0.0.0/24".parse().unwrap();
+    assert!(is_broadcast_or_multicast(Ipv4Address::from([255, 255, 255, 255]), None));
+    assert!(is_broadcast_or_multicast(Ipv4Address::from([224, 0, 0, 251]), None));
+    assert!(is_broadcast_or_multicast(Ipv4Address::from([239, 255, 255, 250]), None));
+    assert!(is_broadcast_or_multicast(Ipv4Address::from([10, 0, 0, 255]), Some(subnet)));
+    assert!(!is_broadcast_or_multicast(Ipv4Address::from([10, 0, 0, 255]), None));
+    assert!(!is_broadcast_or_multicast(Ipv4Address::from([10, 0, 0, 1]), Some(subnet)));
+    assert!(!is_broadcast_or_multicast(Ipv4Address::from([240, 0, 0, 1]), None));
+    assert!(!is_broadcast_or_multicast(Ipv4Address::from([10, 0, 0, 1]), Some("10.0.0.0/31".parse().unwrap())));
+
+    let echo_to = |destination: [u8; 4]| {
+        let request = Ipv4PacketBuilder::new()
+            .source(Ipv4Address::from([10, 0, 0, 2]))
+            .destination(Ipv4Address::from(destination))
+            .payload(IcmpPacket::echo_request(1, 1, &[0; 8]).serialize())
+            .build();
+        tun_frame(&request.serialize())
+    };
+
+    let mut responder = test_responder();
+    responder.config.subnet = Some(subnet);
+    assert!(responder.handle_packet(&echo_to([10, 0, 0, 1])).is_some());
+    assert!(responder.handle_packet(&echo_to([224, 0, 0, 1])).is_none());
+    assert!(responder.handle_packet(&echo_to([10, 0, 0, 255])).is_none());
+    assert_eq!(responder.stats.replies_sent, 1);
+
+    // and an address of ours to answer from
+    responder.config.broadcast_echo = true;
+    assert!(responder.handle_packet(&echo_to([224, 0, 0, 1])).is_none());
+    responder.config.local_address = Some(Ipv4Address::from([10, 0, 0, 1]));
+    for destination in [[224, 0, 0, 1], [10, 0, 0, 255], [255, 255, 255, 255]] {
+        let reply = responder.handle_packet(&echo_to(destination)).unwrap();
+        let reply = Ipv4Packet::parse(&reply[TUN_PREFIX_LEN..]).unwrap();
+        assert_eq!(reply.header.source, Ipv4Address::from([10, 0, 0, 1]));
+    }
+    responder.config.source_override = Some(Ipv4Address::from([10, 0, 0, 5]));
+    let reply = responder.handle_packet(&echo_to([224, 0, 0, 1])).unwrap();
+    assert_eq!(Ipv4Packet::parse(&reply[TUN_PREFIX_LEN..]).unwrap().header.source, Ipv4Address::from([10, 0, 0, 5]));
+
+    // timestamp requests too
+    let mut timestamp = IcmpPacket {
+        header: IcmpHeader {
+            icmp_type: IcmpType::Timestamp,
+            code: 0,
+            checksum: 0,
+            data: Some(IcmpHeaderData::Timestamp { id: 7, seq: 1, originate: 1234, receive: 0, transmit: 0 }),
+        },
+        data: Vec::new(),
+    };
+    timestamp.update_checksum();
+    let timestamp_to = |destination: [u8; 4]| {
+        let request = Ipv4PacketBuilder::new()
+            .source(Ipv4Address::from([10, 0, 0, 2]))
+            .destination(Ipv4Address::from(destination))
+            .payload(timestamp.serialize())
+            .build();
+        tun_frame(&request.serialize())
+    };
+    responder.config.broadcast_echo = false;
+    assert!(responder.handle_packet(&timestamp_to([10, 0, 0, 1])).is_some());
+    assert!(responder.handle_packet(&timestamp_to([10, 0, 0, 255])).is_none());
+}
+
+#[test]
+fn test_packets_not_for_us() {
+    let echo_to = |destination: [u8; 4], ttl: u8| {
+        let request = Ipv4PacketBuilder::new()
+            .source(Ipv4Address::from([10, 0, 0, 2]))
+            .destination(Ipv4Address::from(destination))
+            .ttl(ttl)
+            .payload(IcmpPacket::echo_request(1, 1, &[0; 8]).serialize())
+            .build();
+        tun_frame(&request.serialize())
+    };
+
+    let mut responder = test_responder();
+    responder.config.local_address = Some(Ipv4Address::from([10, 0, 0, 1]));
+    assert!(responder.handle_packet(&echo_to([10, 0, 0, 1], 64)).is_some());
+    assert!(responder.handle_packet(&echo_to([10, 0, 0, 3], 64)).is_none());
+    assert_eq!(responder.stats.replies_sent, 1);
+
+    // forwarded as is, but for the TTL
+    responder.config.forward = true;
+    let request = echo_to([10, 0, 0, 3], 64);
+    let forwarded = responder.handle_packet(&request).unwrap();
+    let ip_packet = Ipv4Packet::parse(&forwarded[TUN_PREFIX_LEN..]).unwrap();
+    assert!(ip_packet.checksum_is_valid());
+    assert_eq!(ip_packet.header.ttl, 63);
+    assert_eq!(ip_packet.header.destination, Ipv4Address::from([10, 0, 0, 3]));
+    assert_eq!(forwarded[TUN_PREFIX_LEN + 20..], request[TUN_PREFIX_LEN + 20..]);
+
+    // or not at all if this was the last hop
+    let reply = responder.handle_packet(&echo_to([10, 0, 0, 3], 1)).unwrap();
+    let ip_packet = Ipv4Packet::parse(&reply[TUN_PREFIX_LEN..]).unwrap();
+    assert_eq!(ip_packet.header.destination, Ipv4Address::from([10, 0, 0, 2]));
+    let (_, icmp_packet) = parse_icmp_packet(&ip_packet.data).unwrap();
+    assert_eq!(icmp_packet.header.icmp_type, IcmpType::TimeExceeded);
+    assert_eq!(responder.stats.replies_sent, 2);
+}
+
+#[test]
+fn test_rate_limit() {
+    let echo_request = tun_frame(&Ipv4PacketBuilder::new()
+        .payload(IcmpPacket::echo_request(1, 1, &[0; 8]).serialize())
+        .build()
+        .serialize());
+
+    let mut responder = test_responder();
+    // a token a second, the test is over long before the next one
+    responder.rate_limit = Some(TokenBucket::new(1));
+    assert!(responder.handle_packet(&echo_request).is_some());
+    assert!(responder.handle_packet(&echo_request).is_none());
+    assert!(responder.handle_packet(&echo_request).is_none());
+    assert_eq!(responder.stats.echo_requests, 3);
+    assert_eq!(responder.stats.replies_sent, 1);
+    assert_eq!(responder.stats.rate_limited, 2);
+}
+
+#[test]
+fn test_impair_reply() {
+    let echo_request = Ipv4PacketBuilder::new()
+        .payload(IcmpPacket::echo_request(1, 1, &[0; 56]).serialize())
+        .build();
+    let udp = Ipv4PacketBuilder::new()
+        .protocol(Ipv4HeaderProtocol::Udp)
+        .payload(vec![0; 8])
+        .build();
+
+    let mut responder = test_responder();
+    let echo_reply = responder.handle_packet(&tun_frame(&echo_request.serialize())).unwrap();
+    let mut frame = echo_reply.clone();
+    assert!(responder.impair_reply(&mut frame));
+    assert_eq!(frame, echo_reply);
+
+    responder.impair = ImpairConfig { loss: 1.0, corrupt: 0.0 };
+    assert!(!responder.impair_reply(&mut frame));
+    // errors are left alone
+    let mut unreachable = responder.handle_packet(&tun_frame(&udp.serialize())).unwrap();
+    assert!(responder.impair_reply(&mut unreachable));
+
+    responder.impair = ImpairConfig { loss: 0.0, corrupt: 1.0 };
+    responder.handle_packet(&tun_frame(&echo_request.serialize())).unwrap();
+    assert!(responder.impair_reply(&mut frame));
+    let ip_packet = Ipv4Packet::parse(&frame[TUN_PREFIX_LEN..]).unwrap();
+    assert!(ip_packet.header.verify_checksum());
+    assert!(parse_icmp_packet(&ip_packet.data).is_err());
+    let flipped: u32 = frame.iter().zip(&echo_reply).map(|(a, b)| (a ^ b).count_ones()).sum();
+    assert_eq!(flipped, 1);
+
+    assert_eq!(responder.stats.replies_lost, 1);
+    assert_eq!(responder.stats.replies_corrupted, 1);
+
+    // ICMPv6 echo replies too
+    let mut request = TUN_PREFIX_IPV6.to_vec();
+    request.extend(icmpv6::ECHO_REQUEST);
+    let echo6_reply = responder.handle_packet(&request).unwrap();
+    let mut frame = echo6_reply.clone();
+    assert!(responder.impair_reply(&mut frame));
+    let ip_packet = Ipv6Packet::parse(&frame[TUN_PREFIX_LEN..]).unwrap();
+    assert!(Icmpv6Packet::from_ipv6(&ip_packet).is_err());
+    responder.impair = ImpairConfig { loss: 1.0, corrupt: 0.0 };
+    assert!(!responder.impair_reply(&mut frame));
+    assert_eq!(responder.stats.replies_lost, 2);
+    assert_eq!(responder.stats.replies_corrupted, 2);
+}
+
+#[test]
+fn test_echo_payload_config() {
+    let request = tun_frame(&Ipv4PacketBuilder::new()
+        .payload(IcmpPacket::echo_request(1, 1, &[1, 2, 3, 4]).serialize())
+        .build()
+        .serialize());
+
+    let mut responder = test_responder();
+    responder.config.echo_payload = EchoPayload::Reverse;
+    let reply = responder.handle_packet(&request).unwrap();
+    let reply = Ipv4Packet::parse(&reply[TUN_PREFIX_LEN..]).unwrap();
+    let icmp_reply = IcmpPacket::from_ipv4(&reply).unwrap();
+    assert_eq!(icmp_reply.data, [4, 3, 2, 1]);
+}
+
+#[test]
+fn test_no_checksum() {
+    let echo_request = tun_frame(&Ipv4PacketBuilder::new()
+        .payload(IcmpPacket::echo_request(1, 1, &[0; 8]).serialize())
+        .build()
+        .serialize());
+    let udp = tun_frame(&Ipv4PacketBuilder::new()
+        .protocol(Ipv4HeaderProtocol::Udp)
+        .payload(vec![0; 8])
+        .build()
+        .serialize());
+
+    let mut responder = test_responder();
+    responder.config.no_checksum = true;
+    for request in [&echo_request, &udp] {
+        let reply = responder.handle_packet(request).unwrap();
+        let (_, packet) = parse_ipv4_packet(&reply[TUN_PREFIX_LEN..]).unwrap();
+        assert_eq!(packet.header.checksum, 0);
+        let (_, icmp_packet) = parse_icmp_packet_unchecked(&packet.data).unwrap();
+        assert_eq!(icmp_packet.header.checksum, 0);
+        assert!(Ipv4Packet::parse(&reply[TUN_PREFIX_LEN..]).is_err());
+    }
+
+    // still an echo reply as far as the impairments are concerned
+    assert!(responder.handle_packet(&echo_request).is_some());
+    responder.config.reply_delay = Duration::from_millis(10);
+    assert_eq!(responder.reply_delay(), Duration::from_millis(10));
+}
+
+#[test]
+fn test_reply_identification() {
+    let echo_request = tun_frame(&Ipv4PacketBuilder::new()
+        .identification(7)
+        .payload(IcmpPacket::echo_request(1, 1, &[0; 8]).serialize())
+        .build()
+        .serialize());
+    let identification = |reply: &[u8]| Ipv4Packet::parse(&reply[TUN_PREFIX_LEN..]).unwrap().header.identification;
+
+    let mut responder = test_responder();
+    responder.identification = IdentificationSequence::new(1000);
+    let first = responder.handle_packet(&echo_request).unwrap();
+    let second = responder.handle_packet(&echo_request).unwrap();
+    assert_eq!(identification(&first), 1000);
+    assert_eq!(identification(&second), 1001);
+    assert_eq!(responder.identification.next_id(), 1002);
+}
+
+#[test]
+fn test_reply_delay() {
+    let echo_request = Ipv4PacketBuilder::new()
+        .payload(IcmpPacket::echo_request(1, 1, &[0; 8]).serialize())
+        .build();
+    let udp = Ipv4PacketBuilder::new()
+        .protocol(Ipv4HeaderProtocol::Udp)
+        .payload(vec![0; 8])
+        .build();
+
+    let echo_request = tun_frame(&echo_request.serialize());
+    let udp = tun_frame(&udp.serialize());
+    let mut echo6_request = TUN_PREFIX_IPV6.to_vec();
+    echo6_request.extend(icmpv6::ECHO_REQUEST);
+
+    let mut responder = test_responder();
+    assert!(responder.handle_packet(&echo_request).is_some());
+    assert_eq!(responder.reply_delay(), Duration::ZERO);
+
+    responder.config.reply_delay = Duration::from_millis(250);
+    assert_eq!(responder.reply_delay(), Duration::from_millis(250));
+    assert!(responder.handle_packet(&udp).is_some());
+    assert_eq!(responder.reply_delay(), Duration::ZERO);
+    assert!(responder.handle_packet(&echo6_request).is_some());
+    assert_eq!(responder.reply_delay(), Duration::from_millis(250));
+    // nor does anything linger once a frame goes unanswered
+    assert!(responder.handle_packet(&echo_request[..TUN_PREFIX_LEN]).is_none());
+    assert_eq!(responder.reply_delay(), Duration::ZERO);
+
+    assert!(responder.stats_summary().ends_with(", echo replies delayed by 250 ms"));
+
+    let jitter = (Duration::from_millis(5), Duration::from_millis(40));
+    responder.config.reply_jitter = Some(jitter);
+    assert!(responder.handle_packet(&echo_request).is_some());
+    for _ in 0..100 {
+        let delay = responder.reply_delay();
+        assert!((Duration::from_millis(255)..=Duration::from_millis(290)).contains(&delay), "{delay:?}");
+    }
+    assert!(responder.handle_packet(&udp).is_some());
+    assert_eq!(responder.reply_delay(), Duration::ZERO);
+
+    assert!(responder.stats_summary().ends_with(", echo replies delayed by 255-290 ms"));
+}
+
+#[test]
+fn test_handle_packet_short_reads() {
+    let mut responder = test_responder();
+    assert!(responder.handle_packet(&[0, 0, 8]).is_none());
+    // a prefix and a truncated IPv4 header
+    let frame = tun_frame(&Ipv4PacketBuilder::new().build().serialize());
+    assert!(responder.handle_packet(&frame[..MIN_FRAME_LEN - 1]).is_none());
+    assert_eq!(responder.stats.parse_failures, 2);
+    assert_eq!(responder.stats.bytes_in, 0);
+}
+
+#[test]
+fn test_handle_packet_drops() {
+    let mut responder = test_responder();
+    // too short for the TUN prefix
+    assert!(responder.handle_packet(&[0, 0]).is_none());
+    assert!(responder.handle_packet(&[]).is_none());
+    // an echo reply doesn't get answered
+    let echo_reply = Ipv4PacketBuilder::new()
+        .payload(IcmpPacket::echo_request(1, 1, &[]).echo_reply().serialize())
+        .build();
+    assert!(responder.handle_packet(&tun_frame(&echo_reply.serialize())).is_none());
+}
+
+#[test]
+fn test_packet_json() {
+    use crate::util::PING_REQUEST;
+
+    let request = PING_REQUEST;
+
+    let ip_packet = Ipv4Packet::parse(&request).unwrap();
+    let (_, icmp_packet) = parse_icmp_packet(&ip_packet.data).unwrap();
+    let json = packet_json(&ip_packet.header, &icmp_packet);
+    assert_eq!(json, concat!(
+        r#"{"ip":{"source":"10.0.0.0","destination":"10.0.0.1","protocol":"Icmp","ttl":64,"checksum_valid":true},"#,
+        r#""icmp":{"type":"EchoRequest","code":0,"id":9,"seq":2,"checksum_valid":true}}"#));
+    assert!(!json.contains('\\'));
+    assert!(!json.contains('\n'));
+}
+
+#[test]
+fn test_handle_packet_ipv6_echo_request() {
+    let mut request = TUN_PREFIX_IPV6.to_vec();
+    request.extend(icmpv6::ECHO_REQUEST);
+
+    let mut responder = test_responder();
+    let reply = responder.handle_packet(&request).unwrap();
+    assert_eq!(reply[..TUN_PREFIX_LEN], TUN_PREFIX_IPV6);
+
+    let ip_packet = Ipv6Packet::parse(&reply[TUN_PREFIX_LEN..]).unwrap();
+    assert_eq!(ip_packet.header.source, Ipv6Packet::parse(&icmpv6::ECHO_REQUEST).unwrap().header.destination);
+    assert_eq!(ip_packet.header.hop_limit, DEFAULT_REPLY_TTL);
+    let icmp_packet = Icmpv6Packet::from_ipv6(&ip_packet).unwrap();
+    assert_eq!(icmp_packet.icmp_type, Icmpv6Type::EchoReply);
+    assert_eq!(icmp_packet.echo_id(), Some(0x7b2d));
+    assert_eq!(icmp_packet.data, icmpv6::ECHO_REQUEST[48..]);
+    assert_eq!(responder.stats.echo_requests, 1);
+    assert_eq!(responder.stats.replies_sent, 1);
+
+    // a corrupted checksum, and a reply, get no answer
+    let mut corrupted = request.clone();
+    corrupted[TUN_PREFIX_LEN + 42] ^= 0xff;
+    assert!(responder.handle_packet(&corrupted).is_none());
+    assert_eq!(responder.stats.parse_failures, 1);
+    assert!(responder.handle_packet(&reply).is_none());
+
+    // multicast destinations need --broadcast-echo
+    let mut multicast = Ipv6Packet::parse(&icmpv6::ECHO_REQUEST).unwrap();
+    multicast.header.destination = Ipv6Address(0xff02 << 112 | 1);
+    let mut icmp_request = Icmpv6Packet::from_ipv6(&Ipv6Packet::parse(&icmpv6::ECHO_REQUEST).unwrap()).unwrap();
+    icmp_request.update_checksum(multicast.header.source, multicast.header.destination);
+    multicast.data = icmp_request.serialize();
+    let mut multicast_frame = TUN_PREFIX_IPV6.to_vec();
+    multicast.serialize_into(&mut multicast_frame);
+    assert!(responder.handle_packet(&multicast_frame).is_none());
+    responder.config.broadcast_echo = true;
+    assert!(responder.handle_packet(&multicast_frame).is_none());
+
+    // --allow only covers IPv4, with it every IPv6 packet is dropped
+    let mut responder = Responder::new(
+        TrustedSources::new(),
+        vec!["0.0.0.0/0".parse().unwrap()],
+        FragmentReassembler::new(64 * 1024, Duration::from_secs(30)));
+    assert!(responder.handle_packet(&request).is_none());
+    assert_eq!(responder.stats.not_allowed, 1);
+    assert_eq!(responder.stats.parse_failures, 0);
+}
+
+#[test]
+fn test_no_errors_about_broadcasts_or_errors() {
+    let udp_to = |destination: [u8; 4]| {
+        Ipv4PacketBuilder::new()
+            .source(Ipv4Address::from([10, 0, 0, 2]))
+            .destination(Ipv4Address::from(destination))
+            .protocol(Ipv4HeaderProtocol::Udp)
+            .payload(vec![0; 8])
+            .build()
+    };
+    let subnet = Some("10.0.0.0/24".parse().unwrap());
+    let unicast = udp_to([10, 0, 0, 1]);
+    assert!(may_send_error(&unicast.header, &unicast.data, subnet));
+    for destination in [[255, 255, 255, 255], [10, 0, 0, 255], [224, 0, 0, 251], [239, 255, 255, 250]] {
+        let packet = udp_to(destination);
+        assert!(!may_send_error(&packet.header, &packet.data, subnet), "{:?}", destination);
+    }
+    let mut later_fragment = unicast.clone();
+    later_fragment.header.frag_info.offset = 185;
+    assert!(!may_send_error(&later_fragment.header, &later_fragment.data, subnet));
+    let error = ipv4_reply(&unicast.header, &build_time_exceeded(&unicast));
+    assert!(!may_send_error(&error.header, &error.data, subnet));
+    let echo = ipv4_reply(&unicast.header, &IcmpPacket::echo_request(1, 1, &[0; 8]));
+    assert!(may_send_error(&echo.header, &echo.data, subnet));
+
+    // mDNS, SSDP and the like get no protocol unreachable
+    let mut responder = test_responder();
+    responder.config.subnet = subnet;
+    assert!(responder.handle_packet(&tun_frame(&unicast.serialize())).is_some());
+    assert!(responder.handle_packet(&tun_frame(&udp_to([224, 0, 0, 251]).serialize())).is_none());
+    assert!(responder.handle_packet(&tun_frame(&udp_to([10, 0, 0, 255]).serialize())).is_none());
+
+    // nor does an expiring ICMP error when forwarding
+    responder.config.local_address = Some(Ipv4Address::from([10, 0, 0, 1]));
+    responder.config.forward = true;
+    let mut expiring = ipv4_reply(&unicast.header, &build_time_exceeded(&unicast));
+    expiring.header.destination = Ipv4Address::from([10, 0, 0, 7]);
+    expiring.header.ttl = 1;
+    expiring.update_checksum();
+    assert!(responder.handle_packet(&tun_frame(&expiring.serialize())).is_none());
+}
@@ -0,0 +1,51 @@
+use std::io;
+use std::os::unix::io::RawFd;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+
+// Set from the signal handler, the main loop polls it between packets
+static SHUTDOWN_REQUESTED: AtomicBool = AtomicBool::new(false);
+
+extern "C" fn on_signal(_signal: libc::c_int) {
+    // only async-signal-safe things in here, a store is fine
+    SHUTDOWN_REQUESTED.store(true, Ordering::SeqCst);
+}
+
+// Handles SIGINT and SIGTERM by asking the main loop to stop. SA_RESTART
+// is left out on purpose so that a blocking read fails with EINTR
+// instead of being resumed.
+pub fn install_handlers() -> io::Result<()> {
+    for signal in [libc::SIGINT, libc::SIGTERM] {
+        // SAFETY: the handler only touches an atomic, and the sigaction
+        // struct is fully initialized before being passed in
+        let ret = unsafe {
+            let mut action: libc::sigaction = std::mem::zeroed();
+            action.sa_sigaction = on_signal as extern "C" fn(libc::c_int) as libc::sighandler_t;
+            libc::sigemptyset(&mut action.sa_mask);
+            libc::sigaction(signal, &action, std::ptr::null_mut())
+        };
+        if ret != 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
+
+pub fn requested() -> bool {
+    SHUTDOWN_REQUESTED.load(Ordering::SeqCst)
+}
+
+// Waits up to `timeout` for `fd` to become readable. A signal arriving
+// right before a blocking read would go unnoticed until the next packet,
+// so the loop polls with a timeout instead of blocking forever.
+pub fn wait_readable(fd: RawFd, timeout: Duration) -> io::Result<bool> {
+    let mut pollfd = libc::pollfd { fd, events: libc::POLLIN, revents: 0 };
+    let timeout_ms = timeout.as_millis().min(libc::c_int::MAX as u128) as libc::c_int;
+    // SAFETY: we pass a single valid pollfd
+    let ret = unsafe { libc::poll(&mut pollfd, 1, timeout_ms) };
+    match ret {
+        -1 => Err(io::Error::last_os_error()),
+        0 => Ok(false),
+        _ => Ok(true),
+    }
+}
@@ -0,0 +1,47 @@
+use std::fmt;
+
+// Counters for the main loop, printed every now and then so that a
+// `ping -f` run shows how much we actually processed
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+pub struct Stats {
+    pub echo_requests: u64,
+    pub replies_sent: u64,
+    // neither IP nor ICMP, and not answered
+    pub non_icmp_dropped: u64,
+    // other IPv4 protocols answered with a Protocol Unreachable, these
+    // count as replies sent too
+    pub protocol_unreachable_sent: u64,
+    pub parse_failures: u64,
+    // echo requests over the --rate limit
+    pub rate_limited: u64,
+    pub bytes_in: u64,
+    pub bytes_out: u64,
+    // echo replies dropped or corrupted on purpose, with --loss and
+    // --corrupt; they're counted as sent too
+    pub replies_lost: u64,
+    pub replies_corrupted: u64,
+    // packets from sources outside the --allow subnets, which IPv6 ones
+    // always are
+    pub not_allowed: u64,
+}
+
+impl fmt::Display for Stats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} echo requests, {} replies sent, {} non-ICMP dropped, {} protocol unreachable sent, {} parse failures, {} rate limited, {} bytes in, {} bytes out",
+            self.echo_requests,
+            self.replies_sent,
+            self.non_icmp_dropped,
+            self.protocol_unreachable_sent,
+            self.parse_failures,
+            self.rate_limited,
+            self.bytes_in,
+            self.bytes_out)?;
+        if self.replies_lost > 0 || self.replies_corrupted > 0 {
+            write!(f, ", {} replies lost and {} corrupted on purpose", self.replies_lost, self.replies_corrupted)?;
+        }
+        if self.not_allowed > 0 {
+            write!(f, ", {} not allowed", self.not_allowed)?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,119 @@
+use std::collections::HashMap;
+
+use crate::icmp::{IcmpPacket, IcmpType};
+
+// Tunnels arbitrary bytes (e.g. DNS queries) through ICMP echo payloads.
+//
+// Every echo carries a small frame in front of its chunk of the message:
+// message id: 2 bytes
+// chunk index: 2 bytes
+// chunk count: 2 bytes
+// chunk: up to `chunk_size` bytes
+//
+// The echo sequence number mirrors the chunk index, which makes the
+// exchange easy to follow in a capture.
+
+const FRAME_HEADER_LEN: usize = 6;
+
+#[allow(dead_code)]
+pub fn split_message(id: u16, message_id: u16, message: &[u8], chunk_size: usize) -> Vec<IcmpPacket> {
+    assert!(chunk_size > 0, "chunk size must be positive");
+
+    let chunks: Vec<&[u8]> = if message.is_empty() {
+        vec![&[]]
+    } else {
+        message.chunks(chunk_size).collect()
+    };
+    // chunk indices and the count travel as u16
+    let count = u16::try_from(chunks.len()).expect("message splits into more than 65535 chunks");
+
+    chunks.iter().enumerate().map(|(index, chunk)| {
+        let index = index as u16;
+        let mut payload = Vec::with_capacity(FRAME_HEADER_LEN + chunk.len());
+        payload.extend(message_id.to_be_bytes());
+        payload.extend(index.to_be_bytes());
+        payload.extend(count.to_be_bytes());
+        payload.extend(*chunk);
+        IcmpPacket::echo_request(id, index, &payload)
+    }).collect()
+}
+
+#[allow(dead_code)]
+#[derive(Debug, Default)]
+pub struct TunnelReceiver {
+    // message id -> chunks received so far (indexed by chunk index)
+    pending: HashMap<u16, Vec<Option<Vec<u8>>>>,
+}
+
+#[allow(dead_code)]
+impl TunnelReceiver {
+    pub fn new() -> TunnelReceiver {
+        TunnelReceiver::default()
+    }
+
+    // feeds an echo into the receiver, returning the whole message once
+    // its last missing chunk arrives
+    pub fn push(&mut self, packet: &IcmpPacket) -> Option<Vec<u8>> {
+        match packet.header.icmp_type {
+            IcmpType::EchoRequest | IcmpType::EchoReply => (),
+            _ => return None,
+        }
+
+        let payload = &packet.data;
+        if payload.len() < FRAME_HEADER_LEN {
+            return None;
+        }
+
+        let message_id = u16::from_be_bytes([payload[0], payload[1]]);
+        let index = u16::from_be_bytes([payload[2], payload[3]]) as usize;
+        let count = u16::from_be_bytes([payload[4], payload[5]]) as usize;
+        if index >= count {
+            return None;
+        }
+
+        let chunks = self.pending.entry(message_id)
+            .or_insert_with(|| vec![None; count]);
+        if chunks.len() != count {
+            // inconsistent framing, the message can't be trusted anymore
+            self.pending.remove(&message_id);
+            return None;
+        }
+        chunks[index] = Some(payload[FRAME_HEADER_LEN..].to_vec());
+
+        if chunks.iter().any(|chunk| chunk.is_none()) {
+            return None;
+        }
+
+        let chunks = self.pending.remove(&message_id)?;
+        Some(chunks.into_iter().flatten().flatten().collect())
+    }
+}
+
+#[test]
+fn test_tunnel_reassembles_split_message() {
+    use crate::icmp::parse_icmp_packet;
+    use crate::util::Serialize;
+
+    let message = b"a message that does not fit in a single echo request payload";
+    let packets = split_message(0x1234, 7, message, 16);
+    assert_eq!(packets.len(), 4);
+
+    let mut receiver = TunnelReceiver::new();
+    let mut reassembled = None;
+    // deliver them out of order, through the wire format
+    for packet in packets.iter().rev() {
+        let bytes = packet.serialize();
+        let (_, packet) = parse_icmp_packet(&bytes).unwrap();
+        assert_eq!(packet.header.icmp_type, IcmpType::EchoRequest);
+        assert!(reassembled.is_none());
+        reassembled = receiver.push(&packet);
+    }
+
+    assert_eq!(reassembled.as_deref(), Some(&message[..]));
+}
+
+#[test]
+#[should_panic(expected = "more than 65535 chunks")]
+fn test_split_message_too_many_chunks() {
+    split_message(1, 1, &[0; 65536], 1);
+}
@@ -1,37 +1,438 @@
+use std::io;
+
+use nom::IResult;
+
+use crate::ipv4::{Ipv4Address, Ipv4HeaderProtocol};
+
 // crude attempt at implementing serialization capabilities
 // since using Serde would be too much work
 pub trait Serialize {
-    fn serialize(&self) -> Vec<u8>;
+    // appends the serialized bytes to `buf`, so a buffer can be reused
+    // across packets instead of allocating a fresh one every time
+    fn serialize_into(&self, buf: &mut Vec<u8>);
+
+    // exactly how many bytes `serialize_into` appends, so the buffer can
+    // be allocated once instead of growing along the way
+    fn serialized_len(&self) -> usize;
+
+    fn serialize(&self) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(self.serialized_len());
+        self.serialize_into(&mut buf);
+        buf
+    }
+
+    // streams the bytes to a file or socket; the packets override this
+    // to write their payload straight from where it is
+    fn serialize_to<W: io::Write>(&self, w: &mut W) -> io::Result<()> {
+        w.write_all(&self.serialize())
+    }
 }
 
 impl Serialize for Vec<u8> {
-    fn serialize(&self) -> Vec<u8> {
-        self.clone()
+    fn serialize_into(&self, buf: &mut Vec<u8>) {
+        buf.extend(self);
     }
+
+    fn serialized_len(&self) -> usize {
+        self.len()
+    }
+}
+
+// counterpart to `Serialize`, backed by the nom parsers
+#[allow(dead_code)]
+pub trait Deserialize: Sized {
+    fn deserialize(input: &[u8]) -> IResult<&[u8], Self>;
+}
+
+// parses `bytes` as a `T` and checks it serializes back to the same bytes
+#[cfg(test)]
+pub fn roundtrip<T: Serialize + Deserialize>(bytes: &[u8]) -> T {
+    let (rest, value) = T::deserialize(bytes).unwrap();
+    assert!(rest.is_empty());
+    assert_eq!(bytes, value.serialize().as_slice());
+    assert_eq!(value.serialized_len(), bytes.len());
+    value
+}
+
+// Counts the heap allocations made by the current thread, so tests can
+// check that a code path doesn't allocate
+#[cfg(test)]
+mod allocations {
+    use std::alloc::{GlobalAlloc, Layout, System};
+    use std::cell::Cell;
+
+    thread_local! {
+        // const-initialized, so using it doesn't allocate in turn
+        pub static COUNT: Cell<usize> = const { Cell::new(0) };
+    }
+
+    pub struct CountingAllocator;
+
+    unsafe impl GlobalAlloc for CountingAllocator {
+        unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+            let _ = COUNT.try_with(|count| count.set(count.get() + 1));
+            System.alloc(layout)
+        }
+
+        unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+            System.dealloc(ptr, layout)
+        }
+    }
+
+    #[global_allocator]
+    static ALLOCATOR: CountingAllocator = CountingAllocator;
 }
 
+// runs `f`, returning its result and how many allocations it made
+#[cfg(test)]
+pub fn count_allocations<R, F: FnOnce() -> R>(f: F) -> (R, usize) {
+    let before = allocations::COUNT.with(|count| count.get());
+    let result = f();
+    let after = allocations::COUNT.with(|count| count.get());
+    (result, after - before)
+}
+
+// The Internet checksum (RFC 1071) of `data`, padded with a zero byte
+// when its length is odd. Implementations only differ in speed.
+pub trait Checksum {
+    fn sum(data: &[u8]) -> u16;
+}
+
+// a 16-bit word at a time, the way RFC 1071 describes it
+#[derive(Debug, Clone, Copy)]
+pub struct ScalarChecksum;
+
+impl Checksum for ScalarChecksum {
+    fn sum(data: &[u8]) -> u16 {
+        // a u64 holds the sum of 2^48 words before overflowing, so the
+        // carries can safely be folded once at the end whatever the length
+        let mut sum: u64 = 0;
+        for bytes in data.chunks(2) {
+            let high = bytes[0];
+
+            // handle misaligned buffers
+            let low: u8 = match bytes.get(1) {
+                Some(&b) => b,
+                None => 0x0,
+            };
+
+            let t = (high as u64) << 8 | low as u64;
+            sum += t;
+        }
+
+        while sum >> 16 != 0 {
+            sum = (sum & 0xFFFF) + (sum >> 16);
+        }
+
+        !(sum as u16)
+    }
+}
+
+// 8 bytes at a time. One's complement addition works the same with
+// 64-bit words as long as the carries are added back in, and the result
+// folds down to the 16-bit sum (RFC 1071, section 2).
+#[derive(Debug, Clone, Copy)]
+pub struct WideChecksum;
+
+impl Checksum for WideChecksum {
+    fn sum(data: &[u8]) -> u16 {
+        let mut sum: u64 = 0;
+        let chunks = data.chunks_exact(8);
+        // the tail starts on an even offset, so padding it with zeroes up
+        // to a whole word is the same as padding an odd length
+        let mut tail = [0u8; 8];
+        tail[..chunks.remainder().len()].copy_from_slice(chunks.remainder());
+
+        for chunk in chunks.map(|chunk| chunk.try_into().unwrap()).chain([tail]) {
+            let (added, carry) = sum.overflowing_add(u64::from_be_bytes(chunk));
+            // can't overflow again, a carry leaves `added` below u64::MAX
+            sum = added + carry as u64;
+        }
+
+        while sum >> 16 != 0 {
+            sum = (sum & 0xFFFF) + (sum >> 16);
+        }
+
+        !(sum as u16)
+    }
+}
+
+// below this the word by word loop is just as fast
+const WIDE_CHECKSUM_MIN_LEN: usize = 64;
+
 pub fn checksum_16(data: &[u8]) -> u16 {
-    let mut sum = 0;
-    for bytes in data.chunks(2) {
-        let high = bytes[0];
+    if data.len() < WIDE_CHECKSUM_MIN_LEN {
+        ScalarChecksum::sum(data)
+    } else {
+        WideChecksum::sum(data)
+    }
+}
 
-        // handle misaligned buffers
-        let low: u8 = match bytes.get(1) {
-            Some(&b) => b,
-            None => 0x0,
-        };
+// data carrying its own checksum (e.g. an IPv4 header) sums up to 0xFFFF
+// in one's complement arithmetic, i.e. its checksum comes out as zero
+pub fn checksum_valid(data: &[u8]) -> bool {
+    checksum_16(data) == 0
+}
 
-        let t = (high as u32) << 8 | low as u32;
-        sum += t;
+// TCP and UDP checksums also cover a pseudo-header made of the IPv4
+// addresses, the protocol and the transport length (RFC 768); `payload`
+// is the whole transport segment, with its checksum field zeroed to
+// compute a checksum or left as is to verify one
+#[allow(dead_code)]
+pub fn pseudo_header_checksum(
+    src: Ipv4Address,
+    dst: Ipv4Address,
+    protocol: Ipv4HeaderProtocol,
+    transport_len: u16,
+    payload: &[u8],
+) -> u16 {
+    let mut data = Vec::with_capacity(12 + payload.len());
+    data.extend(src.0.to_be_bytes());
+    data.extend(dst.0.to_be_bytes());
+    data.push(0);
+    data.push(protocol.into());
+    data.extend(transport_len.to_be_bytes());
+    data.extend(payload);
+    checksum_16(&data)
+}
+
+#[test]
+fn test_pseudo_header_checksum() {
+    // a UDP datagram the kernel sent from 10.9.0.1:5353 to 10.9.0.2:7
+    let mut udp = [
+        20, 233, 0, 7,      // Source port, destination port
+        0, 27, 80, 87,      // Length, checksum
+        104, 101, 108, 108, 111, 44, 32, 105, 99, 109, 112, 45, 116, 117, 110, 116, 97, 112, 10
+    ];
+    let src = Ipv4Address::from([10, 9, 0, 1]);
+    let dst = Ipv4Address::from([10, 9, 0, 2]);
+    let len = udp.len() as u16;
+
+    assert_eq!(pseudo_header_checksum(src, dst, Ipv4HeaderProtocol::Udp, len, &udp), 0);
+    udp[6..8].copy_from_slice(&[0, 0]);
+    assert_eq!(pseudo_header_checksum(src, dst, Ipv4HeaderProtocol::Udp, len, &udp), 0x5057);
+    // every pseudo-header field counts
+    assert_ne!(pseudo_header_checksum(dst, dst, Ipv4HeaderProtocol::Udp, len, &udp), 0x5057);
+    assert_ne!(pseudo_header_checksum(src, dst, Ipv4HeaderProtocol::Tcp, len, &udp), 0x5057);
+}
+
+#[test]
+fn test_checksum_valid() {
+    // the test_ip_header_serialization sample, an mDNS datagram
+    let mut raw = [
+        69, 0, 0, 102, 133, 153, 0, 0, 255, 17, 74, 242, 10, 0, 0, 0, 224, 0, 0, 251
+    ];
+    assert!(checksum_valid(&raw));
+
+    raw[8] ^= 0x01;
+    assert!(!checksum_valid(&raw));
+    raw[8] ^= 0x01;
+    raw[10] = 0;
+    assert!(!checksum_valid(&raw));
+}
+
+// one's complement addition, folding the carry back in
+fn ones_complement_add(a: u16, b: u16) -> u16 {
+    let sum = a as u32 + b as u32;
+    ((sum & 0xFFFF) + (sum >> 16)) as u16
+}
+
+// when a 16-bit word changes from `old_word` to `new_word`, returns the
+// value `other_word` has to be changed to so that the checksum doesn't
+// change at all (i.e. other + old - new, in one's complement arithmetic)
+#[allow(dead_code)]
+pub fn checksum_neutral_compensation(old_word: u16, new_word: u16, other_word: u16) -> u16 {
+    ones_complement_add(ones_complement_add(other_word, old_word), !new_word)
+}
+
+// incremental checksum update (RFC 1624): given the stored checksum and
+// a 16-bit word changing from `old_word` to `new_word`, returns the new
+// checksum without going over the rest of the data again
+pub fn checksum_update(old_sum: u16, old_word: u16, new_word: u16) -> u16 {
+    // HC' = ~(~HC + ~m + m')
+    !ones_complement_add(ones_complement_add(!old_sum, !old_word), new_word)
+}
+
+#[test]
+fn test_checksum_neutral_compensation() {
+    // IPv4 header with a valid checksum
+    let mut raw = [
+        69, 0, 0, 102, 133, 153, 0, 0, 255, 17, 74, 242, 10, 0, 0, 0, 224, 0, 0, 251
+    ];
+    assert_eq!(checksum_16(&raw), 0);
+
+    // drop the TTL to 64 and compensate through the identification field
+    let old_word = u16::from_be_bytes([raw[8], raw[9]]);
+    let new_word = u16::from_be_bytes([64, raw[9]]);
+    let identification = u16::from_be_bytes([raw[4], raw[5]]);
+    let compensated = checksum_neutral_compensation(old_word, new_word, identification);
+
+    raw[8..10].copy_from_slice(&new_word.to_be_bytes());
+    assert_ne!(checksum_16(&raw), 0);
+    raw[4..6].copy_from_slice(&compensated.to_be_bytes());
+    assert_eq!(checksum_16(&raw), 0);
+}
+
+// a `ping 10.0.0.1` request from 10.0.0.0, as read from the TUN device
+// once its prefix is stripped
+#[cfg(test)]
+pub const PING_REQUEST: [u8; 84] = [
+    69, 0, 0, 84, 65, 118, 64, 0, 64, 1, 229, 50, 10, 0, 0, 0, 10, 0, 0, 1, 8, 0, 91, 182, 0, 9, 0, 2, 16, 36, 158, 100, 0, 0, 0, 0, 46, 227, 0, 0, 0, 0, 0, 0, 16, 17, 18, 19, 20, 21, 22, 23, 24, 25, 26, 27, 28, 29, 30, 31, 32, 33, 34, 35, 36, 37, 38, 39, 40, 41, 42, 43, 44, 45, 46, 47, 48, 49, 50, 51, 52, 53, 54, 55
+];
+
+// Known-good samples captured from `ping` and mDNS traffic, each one
+// paired with the check to run on it; adding a sample is one more line
+#[cfg(test)]
+type Sample = (&'static str, fn(&str, &[u8]), &'static [u8]);
+
+#[cfg(test)]
+const SAMPLES: &[Sample] = &[
+    ("mdns ip header", check_ipv4_header, &[
+        69, 0, 0, 102, 133, 153, 0, 0, 255, 17, 74, 242, 10, 0, 0, 0, 224, 0, 0, 251
+    ]),
+    ("ping ip header", check_ipv4_header, &[
+        69, 0, 0, 84, 65, 118, 64, 0, 64, 1, 229, 50, 10, 0, 0, 0, 10, 0, 0, 1
+    ]),
+    ("ping icmp header", check_icmp_header, &[8, 0, 88, 204, 0, 3, 0, 4]),
+    ("ping icmp echo", check_icmp_packet, &[
+        8, 0, 80, 124, 0, 12, 0, 3, 237, 89, 158, 100, 0, 0, 0, 0, 91, 227, 1, 0, 0, 0, 0, 0, 16, 17, 18, 19, 20, 21, 22, 23, 24, 25, 26, 27, 28, 29, 30, 31, 32, 33, 34, 35, 36, 37, 38, 39, 40, 41, 42, 43, 44, 45, 46, 47, 48, 49, 50, 51, 52, 53, 54, 55
+    ]),
+    ("ping ip packet", check_ipv4_packet, &[
+        69, 0, 0, 84, 117, 212, 64, 0, 64, 1, 176, 212, 10, 0, 0, 0, 10, 0, 0, 1, 8, 0, 251, 37, 0, 14, 0, 3, 175, 92, 158, 100, 0, 0, 0, 0, 231, 52, 9, 0, 0, 0, 0, 0, 16, 17, 18, 19, 20, 21, 22, 23, 24, 25, 26, 27, 28, 29, 30, 31, 32, 33, 34, 35, 36, 37, 38, 39, 40, 41, 42, 43, 44, 45, 46, 47, 48, 49, 50, 51, 52, 53, 54, 55
+    ]),
+    ("tun ping ip packet", check_ipv4_packet, &PING_REQUEST),
+];
+
+// `roundtrip`, naming the sample that fails
+#[cfg(test)]
+fn roundtrip_sample<T: Serialize + Deserialize>(name: &str, bytes: &[u8]) -> T {
+    let (rest, value) = T::deserialize(bytes).unwrap_or_else(|e| panic!("{name}: {e:?}"));
+    assert!(rest.is_empty(), "{name}: {} bytes left over", rest.len());
+    assert_eq!(bytes, value.serialize().as_slice(), "{name}: round trip");
+    assert_eq!(value.serialized_len(), bytes.len(), "{name}: serialized_len");
+    value
+}
+
+#[cfg(test)]
+fn check_ipv4_header(name: &str, bytes: &[u8]) {
+    let header = roundtrip_sample::<crate::ipv4::Ipv4Header>(name, bytes);
+    assert!(header.verify_checksum(), "{name}: bad checksum");
+}
+
+#[cfg(test)]
+fn check_ipv4_packet(name: &str, bytes: &[u8]) {
+    let mut packet = roundtrip_sample::<crate::ipv4::Ipv4Packet>(name, bytes);
+    assert!(packet.header.verify_checksum(), "{name}: bad checksum");
+    // recomputing a valid checksum must not change a single byte
+    packet.update_checksum();
+    assert!(packet.checksum_is_valid(), "{name}: bad checksum once recomputed");
+    assert_eq!(bytes, packet.serialize().as_slice(), "{name}: changed by recomputing the checksum");
+}
+
+#[cfg(test)]
+fn check_icmp_header(name: &str, bytes: &[u8]) {
+    // the checksum covers the payload too, so there's nothing to verify
+    roundtrip_sample::<crate::icmp::IcmpHeader>(name, bytes);
+}
+
+#[cfg(test)]
+fn check_icmp_packet(name: &str, bytes: &[u8]) {
+    let mut packet = roundtrip_sample::<crate::icmp::IcmpPacket>(name, bytes);
+    packet.update_checksum();
+    assert_eq!(checksum_16(&packet.serialize()), 0, "{name}: bad checksum once recomputed");
+    assert_eq!(bytes, packet.serialize().as_slice(), "{name}: changed by recomputing the checksum");
+}
+
+#[test]
+fn test_deserialize_roundtrip() {
+    for (name, check, bytes) in SAMPLES {
+        check(name, bytes);
+    }
+}
+
+#[test]
+fn test_checksum_update_matches_recompute() {
+    let mut raw = [
+        69, 0, 0, 102, 133, 153, 0, 0, 255, 17, 74, 242, 10, 0, 0, 0, 224, 0, 0, 251
+    ];
+
+    for ttl in [254, 128, 1, 0] {
+        let old_sum = u16::from_be_bytes([raw[10], raw[11]]);
+        let old_word = u16::from_be_bytes([raw[8], raw[9]]);
+        raw[8] = ttl;
+        let new_word = u16::from_be_bytes([raw[8], raw[9]]);
+        let incremental = checksum_update(old_sum, old_word, new_word);
+
+        raw[10..12].copy_from_slice(&[0, 0]);
+        let recomputed = checksum_16(&raw);
+        assert_eq!(incremental, recomputed);
+        raw[10..12].copy_from_slice(&recomputed.to_be_bytes());
+    }
+}
+
+#[test]
+fn test_checksum_16_large_buffer() {
+    // reference implementation folding the carry after every addition
+    fn reference(data: &[u8]) -> u16 {
+        let mut sum: u16 = 0;
+        for bytes in data.chunks(2) {
+            let word = u16::from_be_bytes([bytes[0], *bytes.get(1).unwrap_or(&0)]);
+            sum = ones_complement_add(sum, word);
+        }
+        !sum
+    }
+
+    let data = vec![0xFFu8; 70 * 1024];
+    assert_eq!(checksum_16(&data), reference(&data));
+
+    let data: Vec<u8> = (0..70 * 1024 + 1).map(|i| (i * 7) as u8).collect();
+    assert_eq!(checksum_16(&data), reference(&data));
+}
+
+#[test]
+fn test_checksum_implementations_agree() {
+    use crate::impair::Rng;
+
+    for (name, _, bytes) in SAMPLES {
+        assert_eq!(WideChecksum::sum(bytes), ScalarChecksum::sum(bytes), "{name}");
+    }
+
+    let mut rng = Rng::new(5);
+    let data: Vec<u8> = (0..64 * 1024 + 13).map(|_| rng.next_u64() as u8).collect();
+    // every length of tail, odd ones included
+    for len in (0..40).chain([1499, 1500, data.len()]) {
+        assert_eq!(WideChecksum::sum(&data[..len]), ScalarChecksum::sum(&data[..len]), "{len} bytes");
     }
 
-    let carry = sum >> 16;
-    sum &= 0x0000_FFFF;
-    sum += carry;
-    let carry = sum >> 16;
-    sum &= 0x0000_FFFF;
-    sum += carry;
+    // carries out of the 64-bit words
+    let data = vec![0xFFu8; 4096 + 6];
+    assert_eq!(WideChecksum::sum(&data), ScalarChecksum::sum(&data));
+}
 
-    !(sum & 0xFFFF) as u16
+// xxd-style dump for the trace logs: offset, 16 bytes in hex grouped by
+// two, then the printable ones as ASCII
+pub fn hexdump(data: &[u8]) -> String {
+    let lines: Vec<String> = data.chunks(16).enumerate().map(|(i, line)| {
+        let hex: Vec<String> = line.chunks(2)
+            .map(|group| group.iter().map(|byte| format!("{byte:02x}")).collect())
+            .collect();
+        let ascii: String = line.iter()
+            .map(|&byte| if byte.is_ascii_graphic() || byte == b' ' { byte as char } else { '.' })
+            .collect();
+        format!("{:08x}: {:<39}  {}", i * 16, hex.join(" "), ascii)
+    }).collect();
+    lines.join("\n")
 }
 
+#[test]
+fn test_hexdump() {
+    // the IPv4 header of a `ping 10.0.0.1`
+    let header = [
+        69, 0, 0, 84, 65, 118, 64, 0, 64, 1, 229, 50, 10, 0, 0, 0, 10, 0, 0, 1
+    ];
+    assert_eq!(hexdump(&header), "\
+00000000: 4500 0054 4176 4000 4001 e532 0a00 0000  E..TAv@.@..2....
+00000010: 0a00 0001                                ....");
+    assert_eq!(hexdump(&[0x41, 0x20, 0x7f]), "00000000: 4120 7f                                  A .");
+    assert_eq!(hexdump(&[]), "");
+}
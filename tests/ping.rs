@@ -0,0 +1,61 @@
+// Runs the binary the way run.sh deploys it and checks that a real
+// `ping` gets its reply. It needs root (to configure the device) and
+// the ip(8) and ping(8) tools, so it only builds with
+// `cargo test --features integration`.
+#![cfg(feature = "integration")]
+
+use std::io::{BufRead, BufReader};
+use std::process::{Child, Command, Stdio};
+
+const IFACE: &str = "tun0";
+
+// interrupts the responder even when an assertion fails halfway
+struct Responder(Child);
+
+impl Drop for Responder {
+    fn drop(&mut self) {
+        // SAFETY: plain kill(2) on our own child
+        unsafe { libc::kill(self.0.id() as libc::pid_t, libc::SIGINT) };
+        let _ = self.0.wait();
+    }
+}
+
+fn ip(args: &[&str]) {
+    let status = Command::new("ip").args(args).status().expect("unable to run ip(8)");
+    assert!(status.success(), "ip {} failed: {status}", args.join(" "));
+}
+
+#[test]
+fn test_answers_ping() {
+    let mut child = Command::new(env!("CARGO_BIN_EXE_icmp-tuntap"))
+        .args(["--iface", IFACE])
+        .env("RUST_LOG", "info")
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+
+    // the device exists once it starts listening
+    let mut stderr = BufReader::new(child.stderr.take().unwrap());
+    let responder = Responder(child);
+    let mut line = String::new();
+    loop {
+        line.clear();
+        assert_ne!(stderr.read_line(&mut line).unwrap(), 0, "unable to create {IFACE}, not running as root?");
+        if line.contains("listening on") {
+            break;
+        }
+    }
+
+    ip(&["addr", "add", "10.0.0.0/24", "dev", IFACE]);
+    ip(&["link", "set", "up", "dev", IFACE]);
+
+    let output = Command::new("ping")
+        .args(["-c1", "-W2", "10.0.0.1"])
+        .output()
+        .expect("unable to run ping(8)");
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(output.status.success(), "{stdout}");
+    assert!(stdout.contains("1 received"), "{stdout}");
+
+    drop(responder);
+}
@@ -0,0 +1,38 @@
+use std::process::Command;
+
+// a `ping 10.0.0.1` request, as read from the TUN device
+const ECHO_REQUEST: [u8; 88] = [
+    0, 0, 8, 0, 69, 0, 0, 84, 65, 118, 64, 0, 64, 1, 229, 50, 10, 0, 0, 0, 10, 0, 0, 1, 8, 0, 91, 182, 0, 9, 0, 2, 16, 36, 158, 100, 0, 0, 0, 0, 46, 227, 0, 0, 0, 0, 0, 0, 16, 17, 18, 19, 20, 21, 22, 23, 24, 25, 26, 27, 28, 29, 30, 31, 32, 33, 34, 35, 36, 37, 38, 39, 40, 41, 42, 43, 44, 45, 46, 47, 48, 49, 50, 51, 52, 53, 54, 55
+];
+
+#[test]
+fn test_replay_echo_request() {
+    let path = std::env::temp_dir().join(format!("icmp-tuntap-replay-{}.bin", std::process::id()));
+    let mut contents = (ECHO_REQUEST.len() as u32).to_be_bytes().to_vec();
+    contents.extend(ECHO_REQUEST);
+    std::fs::write(&path, contents).unwrap();
+
+    let output = Command::new(env!("CARGO_BIN_EXE_icmp-tuntap"))
+        .arg("--replay")
+        .arg(&path)
+        .output()
+        .unwrap();
+    std::fs::remove_file(&path).unwrap();
+
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    assert!(stdout.contains("type: Echo Reply"), "{stdout}");
+    assert!(stdout.contains("source: 10.0.0.1"), "{stdout}");
+    assert!(stdout.contains("destination: 10.0.0.0"), "{stdout}");
+    assert!(stdout.contains("id: 9"), "{stdout}");
+    assert!(stdout.contains("seq: 2"), "{stdout}");
+}
+
+#[test]
+fn test_replay_missing_file() {
+    let status = Command::new(env!("CARGO_BIN_EXE_icmp-tuntap"))
+        .args(["--replay", "/nonexistent/replay.bin"])
+        .status()
+        .unwrap();
+    assert_eq!(status.code(), Some(1));
+}
@@ -0,0 +1,42 @@
+use std::io::{BufRead, BufReader};
+use std::process::{Command, Stdio};
+
+// Spawns the binary on its own TUN device, interrupts it and checks that
+// it exits cleanly. Creating the device needs CAP_NET_ADMIN, without it
+// the test has nothing to check and passes; any other reason for the
+// binary to exit early is a failure.
+#[test]
+fn test_exits_cleanly_on_sigint() {
+    let iface = format!("tunsig{}", std::process::id() % 10000);
+    let mut child = Command::new(env!("CARGO_BIN_EXE_icmp-tuntap"))
+        .args(["--iface", &iface])
+        .env("RUST_LOG", "info")
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+
+    // the handlers are installed by the time it starts listening
+    let mut stderr = BufReader::new(child.stderr.take().unwrap());
+    let mut output = String::new();
+    loop {
+        let start = output.len();
+        if stderr.read_line(&mut output).unwrap() == 0 {
+            let status = child.wait().unwrap();
+            assert!(output.contains("needs CAP_NET_ADMIN"), "exited before listening ({status}): {output}");
+            eprintln!("not allowed to create {iface}, skipping");
+            return;
+        }
+        if output[start..].contains("listening on") {
+            break;
+        }
+    }
+
+    // SAFETY: plain kill(2) on our own child
+    assert_eq!(unsafe { libc::kill(child.id() as libc::pid_t, libc::SIGINT) }, 0);
+
+    let status = child.wait().unwrap();
+    assert_eq!(status.code(), Some(0));
+
+    let rest: Vec<String> = stderr.lines().map(|line| line.unwrap()).collect();
+    assert!(rest.iter().any(|line| line.contains("shutting down, stats:")), "{:?}", rest);
+}